@@ -1,4 +1,5 @@
-use bmp::bmp::Bmp;
+use binary::bytes::{ByteWriter, ToBytes};
+use bmp::bmp::{Bmp, EncodeOptions};
 use riff::riff::Chunk;
 use std::fs::File;
 use std::io::{Error, Write};
@@ -38,27 +39,16 @@ fn main() -> Result<(), Error> {
         frame_bottom: 426,
     };
 
-    let strf = BitMapInfoHeader {
-        size: 40,
-        width: 640,
-        height: 426,
-        planes: 1,
-        bit_count: 24,
-        compression: 0, // BI_RGB
-        size_image: 0,
-        x_pels_per_meter: 0,
-        y_pels_per_meter: 0,
-        clr_used: 0,
-        clr_important: 0,
-    };
+    // BITMAPINFOHEADER and the bottom-up pixel data of each frame
+    let bmp = Bmp::open("./resources/sample_640x426.bmp")?;
+    let (strf, frame) = bmp.to_dib(&EncodeOptions::default())?;
 
     let mut frames = Vec::new();
     let mut index = Vec::new();
 
-    let bmp = Bmp::open("./resources/sample_640x426.bmp")?;
     let mut offset = 4u32;
     for _ in 0..10 {
-        let size = bmp.data.len() as u32 + 8;
+        let size = frame.len() as u32 + 8;
         index.push(AVIOldIndex {
             chunk_id: [b'0', b'0', b'd', b'c'],
             flags: AVIIF_KEYFRAME,
@@ -66,15 +56,14 @@ fn main() -> Result<(), Error> {
             size,
         });
         offset += size;
-        frames.push(Chunk::new("00dc", bmp.data.clone().to_vec()));
+        frames.push(Chunk::new("00dc", frame.clone()));
     }
 
-    let mut buf = vec![0u8; 16 * index.len()].into_boxed_slice();
-    for (i, entry) in index.into_iter().enumerate() {
-        buf[i * 16..(i + 1) * 16]
-            .copy_from_slice(&unsafe { std::mem::transmute::<AVIOldIndex, [u8; 16]>(entry) }[..]);
+    let mut buf = ByteWriter::new();
+    for entry in &index {
+        buf.write(entry);
     }
-    let idx0 = Chunk::new("idx1", buf.to_vec());
+    let idx0 = Chunk::new("idx1", buf.into_bytes());
 
     let avi = Chunk::list_with_id(
         "RIFF",
@@ -83,25 +72,12 @@ fn main() -> Result<(), Error> {
             Chunk::list(
                 "hdrl",
                 vec![
-                    Chunk::new(
-                        "avih",
-                        (unsafe { std::mem::transmute::<AVIHeader, [u8; 56]>(avih) }).to_vec(),
-                    ),
+                    Chunk::new("avih", avih.to_bytes()),
                     Chunk::list(
                         "strl",
                         vec![
-                            Chunk::new(
-                                "strh",
-                                (unsafe { std::mem::transmute::<AVIStreamHeader, [u8; 56]>(strh) })
-                                    .to_vec(),
-                            ),
-                            Chunk::new(
-                                "strf",
-                                (unsafe {
-                                    std::mem::transmute::<BitMapInfoHeader, [u8; 40]>(strf)
-                                })
-                                .to_vec(),
-                            ), // TODO
+                            Chunk::new("strh", strh.to_bytes()),
+                            Chunk::new("strf", strf),
                         ],
                     ),
                     // TODO: stream header
@@ -120,7 +96,6 @@ fn main() -> Result<(), Error> {
 }
 
 /// ref: https://learn.microsoft.com/ja-jp/previous-versions/windows/desktop/api/Aviriff/ns-aviriff-avimainheader
-#[repr(C, packed)]
 struct AVIHeader {
     micro_sec_per_frame: u32,
     max_bytes_per_sec: u32,
@@ -136,7 +111,6 @@ struct AVIHeader {
 }
 
 /// https://learn.microsoft.com/en-us/previous-versions/windows/desktop/api/Aviriff/ns-aviriff-avioldindex
-#[repr(C, packed)]
 struct AVIOldIndex {
     chunk_id: [u8; 4],
     flags: u32,
@@ -149,7 +123,6 @@ const AVIIF_KEYFRAME: u32 = 0x10;
 // const AVIIF_COMPRESSOR: u32 = 0x100;
 
 /// https://learn.microsoft.com/en-us/previous-versions/ms779638(v=vs.85)
-#[repr(C, packed)]
 struct AVIStreamHeader {
     fcc_type: [u8; 4],
     fcc_handler: [u8; 4],
@@ -170,18 +143,54 @@ struct AVIStreamHeader {
     frame_bottom: i16,
 }
 
-/// https://learn.microsoft.com/ja-jp/windows/win32/api/wingdi/ns-wingdi-bitmapinfoheader
-#[repr(C, packed)]
-struct BitMapInfoHeader {
-    size: u32,
-    width: i32,
-    height: i32,
-    planes: u16,
-    bit_count: u16,
-    compression: u32,
-    size_image: u32,
-    x_pels_per_meter: i32,
-    y_pels_per_meter: i32,
-    clr_used: u32,
-    clr_important: u32,
+impl ToBytes for AVIHeader {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer
+            .u32_le(self.micro_sec_per_frame)
+            .u32_le(self.max_bytes_per_sec)
+            .u32_le(self.padding_granularity)
+            .u32_le(self.flags)
+            .u32_le(self.total_frames)
+            .u32_le(self.initial_frames)
+            .u32_le(self.streams)
+            .u32_le(self.suggested_buffer_size)
+            .u32_le(self.width)
+            .u32_le(self.height);
+        for reserved in self.reserved {
+            writer.u32_le(reserved);
+        }
+    }
+}
+
+impl ToBytes for AVIOldIndex {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer
+            .bytes(&self.chunk_id)
+            .u32_le(self.flags)
+            .u32_le(self.offset)
+            .u32_le(self.size);
+    }
+}
+
+impl ToBytes for AVIStreamHeader {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer
+            .bytes(&self.fcc_type)
+            .bytes(&self.fcc_handler)
+            .u32_le(self.flags)
+            .u16_le(self.priority)
+            .u16_le(self.language)
+            .u32_le(self.initial_frames)
+            .u32_le(self.scale)
+            .u32_le(self.rate)
+            .u32_le(self.start)
+            .u32_le(self.length)
+            .u32_le(self.suggested_buffer_size)
+            .u32_le(self.quality)
+            .u32_le(self.sample_size)
+            .i16_le(self.frame_left)
+            .i16_le(self.frame_top)
+            .i16_le(self.frame_right)
+            .i16_le(self.frame_bottom);
+    }
 }
@@ -0,0 +1,217 @@
+use std::io::{Error, ErrorKind};
+
+/// Types which are decoded from a fixed binary layout.
+pub trait FromBytes: Sized {
+    fn read_from(reader: &mut ByteReader) -> Result<Self, Error>;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Self::read_from(&mut ByteReader::new(bytes))
+    }
+}
+
+/// Types which are encoded into a fixed binary layout.
+pub trait ToBytes {
+    fn write_to(&self, writer: &mut ByteWriter);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = ByteWriter::new();
+        self.write_to(&mut writer);
+        writer.into_bytes()
+    }
+}
+
+/// Cursor over a byte slice which fails instead of panicking when the data is too short.
+pub struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+macro_rules! read_int {
+    ($le:ident, $be:ident, $type:ty) => {
+        pub fn $le(&mut self) -> Result<$type, Error> {
+            Ok(<$type>::from_le_bytes(self.array()?))
+        }
+
+        pub fn $be(&mut self) -> Result<$type, Error> {
+            Ok(<$type>::from_be_bytes(self.array()?))
+        }
+    };
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> ByteReader<'a> {
+        ByteReader { bytes, offset: 0 }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.offset
+    }
+
+    pub fn bytes(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if self.remaining() < length {
+            return Err(Error::new(ErrorKind::InvalidData, "Data is too short"));
+        }
+        let bytes = &self.bytes[self.offset..self.offset + length];
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    /// All the remaining bytes.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let bytes = &self.bytes[self.offset..];
+        self.offset = self.bytes.len();
+        bytes
+    }
+
+    /// Fail unless all bytes have been read, for data of an exact length.
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.remaining() != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Data is too long"));
+        }
+        Ok(())
+    }
+
+    pub fn skip(&mut self, length: usize) -> Result<(), Error> {
+        self.bytes(length).map(|_| ())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    read_int!(u16_le, u16_be, u16);
+    read_int!(i16_le, i16_be, i16);
+    read_int!(u32_le, u32_be, u32);
+    read_int!(i32_le, i32_be, i32);
+    read_int!(u64_le, u64_be, u64);
+
+    pub fn read<T: FromBytes>(&mut self) -> Result<T, Error> {
+        T::read_from(self)
+    }
+}
+
+/// Growable buffer with endian-aware writers.
+#[derive(Default)]
+pub struct ByteWriter {
+    bytes: Vec<u8>,
+}
+
+macro_rules! write_int {
+    ($le:ident, $be:ident, $type:ty) => {
+        pub fn $le(&mut self, value: $type) -> &mut ByteWriter {
+            self.bytes(&value.to_le_bytes())
+        }
+
+        pub fn $be(&mut self, value: $type) -> &mut ByteWriter {
+            self.bytes(&value.to_be_bytes())
+        }
+    };
+}
+
+impl ByteWriter {
+    pub fn new() -> ByteWriter {
+        ByteWriter::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut ByteWriter {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut ByteWriter {
+        self.bytes(&[value])
+    }
+
+    write_int!(u16_le, u16_be, u16);
+    write_int!(i16_le, i16_be, i16);
+    write_int!(u32_le, u32_be, u32);
+    write_int!(i32_le, i32_be, i32);
+    write_int!(u64_le, u64_be, u64);
+
+    pub fn write<T: ToBytes>(&mut self, value: &T) -> &mut ByteWriter {
+        value.write_to(self);
+        self
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bytes::{ByteReader, ByteWriter, FromBytes, ToBytes};
+    use std::io::Error;
+
+    #[derive(Debug, PartialEq)]
+    struct Header {
+        magic: [u8; 2],
+        size: u32,
+        width: i16,
+    }
+
+    impl FromBytes for Header {
+        fn read_from(reader: &mut ByteReader) -> Result<Header, Error> {
+            Ok(Header {
+                magic: reader.array()?,
+                size: reader.u32_le()?,
+                width: reader.i16_be()?,
+            })
+        }
+    }
+
+    impl ToBytes for Header {
+        fn write_to(&self, writer: &mut ByteWriter) {
+            writer
+                .bytes(&self.magic)
+                .u32_le(self.size)
+                .i16_be(self.width);
+        }
+    }
+
+    #[test]
+    fn test_endian() {
+        let bytes = [b'B', b'M', 0x01, 0x02, 0x03, 0x04, 0xff, 0xfe];
+        let header = Header::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            header,
+            Header {
+                magic: *b"BM",
+                size: 0x04030201,
+                width: -2,
+            }
+        );
+        assert_eq!(header.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_too_short() {
+        assert!(Header::from_bytes(&[b'B', b'M', 0x01, 0x02, 0x03, 0x04, 0xff]).is_err());
+
+        let mut reader = ByteReader::new(&[1, 2, 3]);
+        assert!(reader.u32_be().is_err());
+        assert_eq!(reader.remaining(), 3);
+        assert_eq!(reader.u16_be().unwrap(), 0x0102);
+        assert!(reader.finish().is_err());
+        assert_eq!(reader.rest(), [3]);
+        assert!(reader.finish().is_ok());
+    }
+}
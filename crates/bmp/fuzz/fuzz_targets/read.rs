@@ -0,0 +1,20 @@
+//! `Bmp::read` must return `Err` rather than panic for any input.
+//!
+//! Run `cargo +nightly fuzz run read` in `crates/bmp`.
+
+#![no_main]
+
+use bmp::bmp::{Bmp, DecodeOptions};
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    // RLE data can expand to the maximum size, keep each run fast
+    let options = DecodeOptions {
+        max_pixels: 1 << 20,
+    };
+    if let Ok(bmp) = Bmp::read_with_options(&mut Cursor::new(data), &options) {
+        bmp.as_normalized_rgba();
+        let _ = bmp.write(&mut Vec::new());
+    }
+});
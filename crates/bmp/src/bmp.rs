@@ -1,16 +1,34 @@
+use binary::bytes::{ByteReader, ByteWriter, FromBytes, ToBytes};
+use png::png::Png;
 use std::fs::File;
-use std::io::{Error, ErrorKind};
-use std::mem::size_of;
-use std::os::unix::fs::FileExt;
+use std::io::{self, BufReader, Cursor, Error, ErrorKind, Read, Seek, SeekFrom};
 use std::path::Path;
 
 pub struct Bmp {
+    /// Uncompressed pixel array as stored in the file.
+    /// Each row is padded to a multiple of 4 bytes, see `stride`.
     pub data: Box<[u8]>,
     pub width: usize,
     pub height: usize,
+    pub bit_count: u16,
+    /// The first row of `data` is the top of the image (negative height in the header).
+    pub top_down: bool,
+    /// RGB color table of 1, 4 and 8-bit images.
+    pub palette: Vec<[u8; 3]>,
+    /// The JPEG or PNG stream of BI_JPEG and BI_PNG bitmaps.
+    ///
+    /// PNG images are decoded into 32-bit BGRA `data`.
+    /// JPEG images are not decoded, and `data` is empty with `bit_count` 0.
+    pub embedded: Option<EmbeddedImage>,
+    /// ICC profile of V5 headers with `PROFILE_EMBEDDED` or `PROFILE_LINKED`.
+    pub icc_profile: Option<IccProfile>,
+    /// Channel masks of 16 and 32-bit images.
+    /// Without BI_BITFIELDS, 16-bit images are 5-5-5 and 32-bit images are BGRX.
+    pub masks: Option<ChannelMasks>,
+    pub header: ImageHeader,
 }
 
-#[repr(C, packed)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct BitmapFileStructure {
     pub file_type: [u8; 2],
     pub size: u32,
@@ -19,42 +37,159 @@ pub struct BitmapFileStructure {
     pub byte_offset: u32,
 }
 
-#[repr(C, packed)]
-pub struct BitmapV5Header {
-    pub size: u32,
-    pub width: u32,
-    pub height: u32,
+/// Layout of the info header, detected from its size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HeaderVersion {
+    /// BITMAPCOREHEADER (12 bytes), also used by OS/2 1.x. Dimensions are 16-bit.
+    ///
+    /// https://learn.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-bitmapcoreheader
+    Core,
+    /// OS/2 2.x BITMAPINFOHEADER2 (16 to 64 bytes). The first 40 bytes match BITMAPINFOHEADER.
+    Os2V2,
+    /// BITMAPINFOHEADER (40 bytes)
+    ///
+    /// https://learn.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-bitmapinfoheader
+    #[default]
+    Info,
+    /// BITMAPV2INFOHEADER (52 bytes), adding the RGB masks
+    V2,
+    /// BITMAPV3INFOHEADER (56 bytes), adding the alpha mask
+    V3,
+    /// BITMAPV4HEADER (108 bytes), adding the color space
+    ///
+    /// https://learn.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-bitmapv4header
+    V4,
+    /// BITMAPV5HEADER (124 bytes), adding the rendering intent and the ICC profile
+    ///
+    /// https://learn.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-bitmapv5header
+    V5,
+}
+
+impl HeaderVersion {
+    /// Headers larger than BITMAPV5HEADER are read as V5.
+    pub fn from_size(size: u32) -> Result<HeaderVersion, Error> {
+        Ok(match size {
+            12 => HeaderVersion::Core,
+            40 => HeaderVersion::Info,
+            52 => HeaderVersion::V2,
+            56 => HeaderVersion::V3,
+            108 => HeaderVersion::V4,
+            124.. => HeaderVersion::V5,
+            16..=64 if size.is_multiple_of(4) => HeaderVersion::Os2V2,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid header size")),
+        })
+    }
+
+    /// Size of the header written by `ImageHeader::to_bytes`.
+    pub fn size(&self) -> u32 {
+        match self {
+            HeaderVersion::Core => 12,
+            HeaderVersion::Os2V2 => 64,
+            HeaderVersion::Info => 40,
+            HeaderVersion::V2 => 52,
+            HeaderVersion::V3 => 56,
+            HeaderVersion::V4 => 108,
+            HeaderVersion::V5 => 124,
+        }
+    }
+}
+
+/// Info header of any version. Fields which the version does not have are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImageHeader {
+    pub version: HeaderVersion,
+    pub width: i32,
+    /// Negative for top-down bitmaps.
+    pub height: i32,
     pub planes: u16,
     pub bit_count: u16,
     pub compression: u32,
     pub size_image: u32,
-    pub xpels_per_meter: u32,
-    pub ypels_per_meter: u32,
+    pub xpels_per_meter: i32,
+    pub ypels_per_meter: i32,
     pub clr_used: u32,
     pub clr_important: u32,
-    pub red_mask: u32,
-    pub green_mask: u32,
-    pub blue_mask: u32,
-    pub alpha_mask: u32,
-    pub cs_type: u32,
+    /// V2 and later
+    pub masks: Option<ChannelMasks>,
+    /// V4 and later
+    pub color_space: Option<ColorSpace>,
+    /// V5
+    pub intent: Option<u32>,
+    /// V5
+    pub profile: Option<Profile>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelMasks {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+    /// Zero when there is no alpha channel, including V2 headers.
+    pub alpha: u32,
+}
+
+/// Color space fields of BITMAPV4HEADER.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColorSpace {
+    pub cs_type: ColorSpaceType,
+    /// Used with `ColorSpaceType::CalibratedRgb`.
     pub endpoints: CieXYZTriple,
+    /// Unsigned 16.16 fixed-point tone response curves, used with `ColorSpaceType::CalibratedRgb`.
     pub gamma_red: u32,
     pub gamma_green: u32,
     pub gamma_blue: u32,
-    pub intent: u32,
-    pub profile_data: u32,
-    pub profile_size: u32,
-    pub reserved: u32,
+}
+
+/// https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-wmf/eb4bbd50-b3ce-4917-895c-be31f214797f
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorSpaceType {
+    /// LCS_CALIBRATED_RGB, given by the endpoints and the gamma values
+    #[default]
+    CalibratedRgb,
+    /// LCS_sRGB
+    Srgb,
+    /// LCS_WINDOWS_COLOR_SPACE, the system default color space
+    WindowsColorSpace,
+    /// PROFILE_LINKED, the profile data is the file name of an ICC profile (V5)
+    ProfileLinked,
+    /// PROFILE_EMBEDDED, the profile data is an ICC profile (V5)
+    ProfileEmbedded,
+    Unknown(u32),
+}
+
+/// Complete image stream wrapped in a bitmap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmbeddedImage {
+    Jpeg(Vec<u8>),
+    Png(Vec<u8>),
+}
+
+/// ICC profile referred to by BITMAPV5HEADER.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IccProfile {
+    Embedded(Vec<u8>),
+    /// File name of the profile, stored as a null-terminated Windows-1252 string.
+    Linked(String),
+}
+
+/// Location of the ICC profile data in BITMAPV5HEADER.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Offset from the beginning of the info header.
+    pub offset: u32,
+    pub size: u32,
 }
 
 /// https://learn.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-ciexyztriple
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct CieXYZTriple {
     pub cie_xyz_red: CieXYZ,
     pub cie_xyz_green: CieXYZ,
     pub cie_xyz_blue: CieXYZ,
 }
 
-/// https://learn.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-ciexyztriple
+/// https://learn.microsoft.com/en-us/windows/win32/api/wingdi/ns-wingdi-ciexyz
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct CieXYZ {
     pub cie_xyz_x: FxPt2Dot30,
     pub cie_xyz_y: FxPt2Dot30,
@@ -64,60 +199,2015 @@ pub struct CieXYZ {
 /// Fixed-point values with a 2-bit integer part and a 30-bit fractional part
 pub type FxPt2Dot30 = u32;
 
+pub fn fxpt2dot30_to_f64(value: FxPt2Dot30) -> f64 {
+    value as f64 / (1u32 << 30) as f64
+}
+
+/// Values are clamped to the range from 0 to 4.
+pub fn f64_to_fxpt2dot30(value: f64) -> FxPt2Dot30 {
+    (value * (1u32 << 30) as f64)
+        .round()
+        .clamp(0.0, u32::MAX as f64) as u32
+}
+
+impl CieXYZ {
+    pub fn from_f64([x, y, z]: [f64; 3]) -> CieXYZ {
+        CieXYZ {
+            cie_xyz_x: f64_to_fxpt2dot30(x),
+            cie_xyz_y: f64_to_fxpt2dot30(y),
+            cie_xyz_z: f64_to_fxpt2dot30(z),
+        }
+    }
+
+    pub fn to_f64(&self) -> [f64; 3] {
+        [self.cie_xyz_x, self.cie_xyz_y, self.cie_xyz_z].map(fxpt2dot30_to_f64)
+    }
+}
+
+impl ColorSpace {
+    /// Gamma values of red, green and blue.
+    pub fn gamma(&self) -> [f64; 3] {
+        [self.gamma_red, self.gamma_green, self.gamma_blue].map(|gamma| gamma as f64 / 65536.0)
+    }
+}
+
+impl From<u32> for ColorSpaceType {
+    fn from(value: u32) -> ColorSpaceType {
+        match &value.to_be_bytes() {
+            [0, 0, 0, 0] => ColorSpaceType::CalibratedRgb,
+            b"sRGB" => ColorSpaceType::Srgb,
+            b"Win " => ColorSpaceType::WindowsColorSpace,
+            b"LINK" => ColorSpaceType::ProfileLinked,
+            b"MBED" => ColorSpaceType::ProfileEmbedded,
+            _ => ColorSpaceType::Unknown(value),
+        }
+    }
+}
+
+impl From<ColorSpaceType> for u32 {
+    fn from(value: ColorSpaceType) -> u32 {
+        match value {
+            ColorSpaceType::CalibratedRgb => 0,
+            ColorSpaceType::Srgb => u32::from_be_bytes(*b"sRGB"),
+            ColorSpaceType::WindowsColorSpace => u32::from_be_bytes(*b"Win "),
+            ColorSpaceType::ProfileLinked => u32::from_be_bytes(*b"LINK"),
+            ColorSpaceType::ProfileEmbedded => u32::from_be_bytes(*b"MBED"),
+            ColorSpaceType::Unknown(value) => value,
+        }
+    }
+}
+
+/// Values of the compression field.
+///
+/// https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-wmf/4e588f70-bd92-4a6f-b77f-35d0feaf7a57
+pub const BI_RGB: u32 = 0;
+pub const BI_RLE8: u32 = 1;
+pub const BI_RLE4: u32 = 2;
+pub const BI_BITFIELDS: u32 = 3;
+pub const BI_JPEG: u32 = 4;
+pub const BI_PNG: u32 = 5;
+pub const BI_ALPHABITFIELDS: u32 = 6;
+
+/// Options of `Bmp::write_with_options`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeOptions {
+    /// `Info`, `V4` or `V5`. Images with alpha are written with at least `V4`.
+    pub version: HeaderVersion,
+    /// Compress 8-bit images with BI_RLE8.
+    pub rle: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            version: HeaderVersion::Info,
+            rle: false,
+        }
+    }
+}
+
+/// Options of `Bmp::read_with_options`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Images with more pixels are rejected before the pixel data is allocated.
+    pub max_pixels: usize,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions {
+            // 16384 x 16384
+            max_pixels: 1 << 28,
+        }
+    }
+}
+
+impl BitmapFileStructure {
+    pub const SIZE: usize = 14;
+}
+
+impl FromBytes for BitmapFileStructure {
+    fn read_from(reader: &mut ByteReader) -> Result<BitmapFileStructure, Error> {
+        Ok(BitmapFileStructure {
+            file_type: reader.array()?,
+            size: reader.u32_le()?,
+            reserved1: reader.u16_le()?,
+            reserved2: reader.u16_le()?,
+            byte_offset: reader.u32_le()?,
+        })
+    }
+}
+
+impl ToBytes for BitmapFileStructure {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer
+            .bytes(&self.file_type)
+            .u32_le(self.size)
+            .u16_le(self.reserved1)
+            .u16_le(self.reserved2)
+            .u32_le(self.byte_offset);
+    }
+}
+
+impl FromBytes for ImageHeader {
+    /// Read a header of any version. `reader` must hold the whole header.
+    fn read_from(reader: &mut ByteReader) -> Result<ImageHeader, Error> {
+        let size = reader.u32_le()?;
+        let version = HeaderVersion::from_size(size)?;
+        if version == HeaderVersion::Core {
+            return Ok(ImageHeader {
+                version,
+                width: reader.u16_le()? as i32,
+                height: reader.u16_le()? as i32,
+                planes: reader.u16_le()?,
+                bit_count: reader.u16_le()?,
+                ..ImageHeader::default()
+            });
+        }
+
+        // limit the reader to the header so that a short OS/2 header leaves the rest zero
+        let mut bytes = [0u8; 120];
+        let length = (size as usize - 4).min(bytes.len());
+        bytes[..length].copy_from_slice(reader.bytes(length)?);
+        let mut reader = ByteReader::new(&bytes);
+        let mut header = ImageHeader {
+            version,
+            width: reader.i32_le()?,
+            height: reader.i32_le()?,
+            planes: reader.u16_le()?,
+            bit_count: reader.u16_le()?,
+            compression: reader.u32_le()?,
+            size_image: reader.u32_le()?,
+            xpels_per_meter: reader.i32_le()?,
+            ypels_per_meter: reader.i32_le()?,
+            clr_used: reader.u32_le()?,
+            clr_important: reader.u32_le()?,
+            ..ImageHeader::default()
+        };
+        if version >= HeaderVersion::V2 {
+            header.masks = Some(ChannelMasks {
+                red: reader.u32_le()?,
+                green: reader.u32_le()?,
+                blue: reader.u32_le()?,
+                alpha: match version {
+                    HeaderVersion::V2 => {
+                        reader.skip(4)?;
+                        0
+                    }
+                    _ => reader.u32_le()?,
+                },
+            });
+        }
+        if version >= HeaderVersion::V4 {
+            header.color_space = Some(ColorSpace {
+                cs_type: reader.u32_le()?.into(),
+                endpoints: reader.read()?,
+                gamma_red: reader.u32_le()?,
+                gamma_green: reader.u32_le()?,
+                gamma_blue: reader.u32_le()?,
+            });
+        }
+        if version >= HeaderVersion::V5 {
+            header.intent = Some(reader.u32_le()?);
+            header.profile = Some(Profile {
+                offset: reader.u32_le()?,
+                size: reader.u32_le()?,
+            });
+        }
+        Ok(header)
+    }
+}
+
+impl ToBytes for ImageHeader {
+    /// Write the header in the layout of `version`. Missing optional fields are written as zero.
+    fn write_to(&self, writer: &mut ByteWriter) {
+        let version = self.version;
+        writer.u32_le(version.size());
+        if version == HeaderVersion::Core {
+            writer
+                .u16_le(self.width as u16)
+                .u16_le(self.height as u16)
+                .u16_le(self.planes)
+                .u16_le(self.bit_count);
+            return;
+        }
+
+        writer
+            .i32_le(self.width)
+            .i32_le(self.height)
+            .u16_le(self.planes)
+            .u16_le(self.bit_count)
+            .u32_le(self.compression)
+            .u32_le(self.size_image)
+            .i32_le(self.xpels_per_meter)
+            .i32_le(self.ypels_per_meter)
+            .u32_le(self.clr_used)
+            .u32_le(self.clr_important);
+        if version == HeaderVersion::Os2V2 {
+            writer.bytes(&[0; 24]);
+        }
+        if version >= HeaderVersion::V2 {
+            let masks = self.masks.unwrap_or_default();
+            writer
+                .u32_le(masks.red)
+                .u32_le(masks.green)
+                .u32_le(masks.blue);
+        }
+        if version >= HeaderVersion::V3 {
+            writer.u32_le(self.masks.unwrap_or_default().alpha);
+        }
+        if version >= HeaderVersion::V4 {
+            let color_space = self.color_space.clone().unwrap_or_default();
+            writer
+                .u32_le(color_space.cs_type.into())
+                .write(&color_space.endpoints)
+                .u32_le(color_space.gamma_red)
+                .u32_le(color_space.gamma_green)
+                .u32_le(color_space.gamma_blue);
+        }
+        if version >= HeaderVersion::V5 {
+            let profile = self.profile.unwrap_or_default();
+            writer
+                .u32_le(self.intent.unwrap_or(0))
+                .u32_le(profile.offset)
+                .u32_le(profile.size)
+                .u32_le(0);
+        }
+    }
+}
+
+impl FromBytes for CieXYZTriple {
+    fn read_from(reader: &mut ByteReader) -> Result<CieXYZTriple, Error> {
+        Ok(CieXYZTriple {
+            cie_xyz_red: reader.read()?,
+            cie_xyz_green: reader.read()?,
+            cie_xyz_blue: reader.read()?,
+        })
+    }
+}
+
+impl ToBytes for CieXYZTriple {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer
+            .write(&self.cie_xyz_red)
+            .write(&self.cie_xyz_green)
+            .write(&self.cie_xyz_blue);
+    }
+}
+
+impl FromBytes for CieXYZ {
+    fn read_from(reader: &mut ByteReader) -> Result<CieXYZ, Error> {
+        Ok(CieXYZ {
+            cie_xyz_x: reader.u32_le()?,
+            cie_xyz_y: reader.u32_le()?,
+            cie_xyz_z: reader.u32_le()?,
+        })
+    }
+}
+
+impl ToBytes for CieXYZ {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer
+            .u32_le(self.cie_xyz_x)
+            .u32_le(self.cie_xyz_y)
+            .u32_le(self.cie_xyz_z);
+    }
+}
+
 impl Bmp {
     pub fn open(path: impl AsRef<Path>) -> Result<Bmp, Error> {
-        Bmp::read(&File::open(path).unwrap())
+        Bmp::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Bmp, Error> {
+        Bmp::read(&mut Cursor::new(bytes))
     }
 
-    pub fn read(file: &File) -> Result<Bmp, Error> {
-        let file_header = read_file_header(file)?;
+    /// Read a BMP file which starts at the current position of `reader`.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Bmp, Error> {
+        Bmp::read_with_options(reader, &DecodeOptions::default())
+    }
+
+    pub fn read_with_options<R: Read + Seek>(
+        reader: &mut R,
+        options: &DecodeOptions,
+    ) -> Result<Bmp, Error> {
+        let start = reader.stream_position()?;
+        let file_header = read_file_header(reader)?;
         if file_header.file_type != [b'B', b'M'] {
             return Err(Error::new(ErrorKind::InvalidData, "Not a BMP file"));
         }
+        let data_start = start + file_header.byte_offset as u64;
+        Bmp::read_info(reader, Some(data_start), options)
+    }
+
+    /// Read a packed DIB which starts at the current position of `reader`.
+    ///
+    /// The info header and the color table are directly followed by the pixel data,
+    /// without BITMAPFILEHEADER, as stored in the clipboard and in icons.
+    pub fn read_dib<R: Read + Seek>(reader: &mut R) -> Result<Bmp, Error> {
+        Bmp::read_dib_with_options(reader, &DecodeOptions::default())
+    }
+
+    pub fn read_dib_with_options<R: Read + Seek>(
+        reader: &mut R,
+        options: &DecodeOptions,
+    ) -> Result<Bmp, Error> {
+        Bmp::read_info(reader, None, options)
+    }
+
+    /// Read the info header at the current position of `reader` and the following data.
+    /// The pixel data is at `data_start`, or right after the color table when `None`.
+    ///
+    /// Every offset and size in the headers is checked against the end of the stream before reading.
+    fn read_info<R: Read + Seek>(
+        reader: &mut R,
+        data_start: Option<u64>,
+        options: &DecodeOptions,
+    ) -> Result<Bmp, Error> {
+        let info_start = reader.stream_position()?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(info_start))?;
+        let image_header = read_image_header(reader)?;
+        match (image_header.compression, image_header.bit_count) {
+            // OS/2 uses 3 for 1-bit Huffman encoding and 4 for RLE24
+            (BI_BITFIELDS | BI_JPEG, _) if image_header.version == HeaderVersion::Os2V2 => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Unsupported OS/2 compression",
+                ))
+            }
+            // the bit count is usually 0, and the stream has its own pixel format
+            (BI_JPEG | BI_PNG, _) => {}
+            (_, bit_count) if ![1, 4, 8, 16, 24, 32].contains(&bit_count) => {
+                return Err(Error::new(ErrorKind::Unsupported, "Unsupported bit count"));
+            }
+            (BI_RGB, _)
+            | (BI_RLE8, 8)
+            | (BI_RLE4, 4)
+            | (BI_BITFIELDS | BI_ALPHABITFIELDS, 16 | 32) => {}
+            (BI_RLE8 | BI_RLE4 | BI_BITFIELDS | BI_ALPHABITFIELDS, _) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Bit count does not match the compression",
+                ))
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Unsupported compression",
+                ))
+            }
+        }
+        let width = usize::try_from(image_header.width)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid width"))?;
+        let height = image_header.height.unsigned_abs() as usize;
+        if width
+            .checked_mul(height)
+            .is_none_or(|pixels| pixels > options.max_pixels)
+        {
+            return Err(Error::new(ErrorKind::InvalidData, "Image is too large"));
+        }
+        let masks = read_masks(reader, &image_header)?;
+        let palette = read_palette(reader, &image_header)?;
+
+        let mut bmp = Bmp {
+            data: Box::new([]),
+            width,
+            height,
+            bit_count: image_header.bit_count,
+            top_down: image_header.height < 0,
+            palette,
+            embedded: None,
+            icc_profile: None,
+            masks,
+            header: image_header,
+        };
+        let profile_range = bmp.profile_range(info_start);
+        match data_start {
+            Some(data_start) => {
+                if data_start > end {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Pixel data out of range",
+                    ));
+                }
+                reader.seek(SeekFrom::Start(data_start))?;
+            }
+            // a profile between the color table and the pixels is skipped
+            None => {
+                let position = reader.stream_position()?;
+                if let Some(range) = profile_range
+                    .clone()
+                    .filter(|range| range.contains(&position))
+                {
+                    reader.seek(SeekFrom::Start(range.end))?;
+                }
+            }
+        }
+        let is_compressed =
+            ![BI_RGB, BI_BITFIELDS, BI_ALPHABITFIELDS].contains(&bmp.header.compression);
+        if is_compressed && bmp.top_down {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Compressed bitmaps cannot be top-down",
+            ));
+        }
+        let mut compressed = Vec::new();
+        if is_compressed {
+            let size = bmp.header.size_image as usize;
+            let length = match size {
+                0 => reader.read_to_end(&mut compressed)?,
+                size => reader.take(size as u64).read_to_end(&mut compressed)?,
+            };
+            if length < size {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Unexpected end of file",
+                ));
+            }
+        }
+        bmp.data = match bmp.header.compression {
+            BI_RLE8 | BI_RLE4 => decode_rle(&compressed, &bmp)?,
+            BI_JPEG => {
+                bmp.bit_count = 0;
+                bmp.embedded = Some(EmbeddedImage::Jpeg(compressed));
+                Box::new([])
+            }
+            BI_PNG => {
+                // compare the size in IHDR before decoding the image
+                let mut ihdr = ByteReader::new(compressed.get(16..24).unwrap_or_default());
+                let size = (ihdr.u32_be().ok(), ihdr.u32_be().ok());
+                if size != (Some(width as u32), Some(height as u32)) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "PNG size does not match the header",
+                    ));
+                }
+                let png = Png::from_bytes(&compressed)?;
+                let decoded = Bmp::from_rgba(png.width, png.height, &png.to_rgba8())?;
+                bmp.bit_count = decoded.bit_count;
+                bmp.masks = decoded.masks;
+                bmp.embedded = Some(EmbeddedImage::Png(compressed));
+                decoded.data
+            }
+            _ => {
+                let position = reader.stream_position()?;
+                let size = bmp
+                    .stride()
+                    .checked_mul(height)
+                    .filter(|&size| position + size as u64 <= end)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Pixel data out of range"))?;
+                let mut data = vec![0u8; size].into_boxed_slice();
+                reader.read_exact(&mut data)?;
+                data
+            }
+        };
 
-        let image_header = read_image_header(file)?;
-        let width = image_header.width as usize;
-        let height = image_header.height as usize;
-        let byte_per_pixel = (image_header.bit_count as usize) / 8;
+        if let Some(range) = profile_range {
+            if range.end > end {
+                return Err(Error::new(ErrorKind::InvalidData, "Profile out of range"));
+            }
+            // leave the reader at the end of the pixel data
+            let data_end = reader.stream_position()?;
+            reader.seek(SeekFrom::Start(range.start))?;
+            let mut data = vec![0u8; (range.end - range.start) as usize];
+            reader.read_exact(&mut data)?;
+            reader.seek(SeekFrom::Start(data_end))?;
+            let is_linked = bmp.header.color_space.as_ref().map(|cs| cs.cs_type)
+                == Some(ColorSpaceType::ProfileLinked);
+            bmp.icc_profile = Some(if is_linked {
+                let name = data.split(|&byte| byte == 0).next().unwrap_or_default();
+                IccProfile::Linked(name.iter().map(|&byte| decode_windows_1252(byte)).collect())
+            } else {
+                IccProfile::Embedded(data)
+            });
+        }
 
-        let mut data = vec![0u8; width * height * byte_per_pixel].into_boxed_slice();
-        file.read_at(&mut data, file_header.byte_offset as u64)?;
+        Ok(bmp)
+    }
 
+    /// Position of the embedded or linked profile in the stream, located from the beginning
+    /// of the info header at `info_start`.
+    fn profile_range(&self, info_start: u64) -> Option<std::ops::Range<u64>> {
+        let color_space = self.header.color_space.as_ref()?;
+        let profile = self.header.profile?;
+        match color_space.cs_type {
+            ColorSpaceType::ProfileEmbedded | ColorSpaceType::ProfileLinked => {
+                let start = info_start + profile.offset as u64;
+                Some(start..start + profile.size as u64)
+            }
+            _ => None,
+        }
+    }
+
+    /// Bytes per row including the padding to a 4-byte boundary.
+    pub fn stride(&self) -> usize {
+        stride(self.width, self.bit_count)
+    }
+
+    /// Row of `data` which holds the `y`-th line from the top.
+    fn row(&self, y: usize) -> &[u8] {
+        let row = if self.top_down {
+            y
+        } else {
+            self.height - 1 - y
+        };
+        &self.data[row * self.stride()..(row + 1) * self.stride()]
+    }
+
+    /// Palettized image of `bit_count` 1, 4 or 8.
+    ///
+    /// `indices` has one palette index per pixel, from top to bottom.
+    pub fn from_indexed(
+        width: usize,
+        height: usize,
+        bit_count: u16,
+        palette: &[[u8; 3]],
+        indices: &[u8],
+    ) -> Result<Bmp, Error> {
+        if ![1, 4, 8].contains(&bit_count) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid bit count"));
+        }
+        if palette.is_empty() || palette.len() > 1 << bit_count {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid palette size"));
+        }
+        if indices.iter().any(|&index| index as usize >= palette.len()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Palette index out of range",
+            ));
+        }
+        let mut bmp = Bmp::new(width, height, bit_count, indices.len(), 1)?;
+        bmp.palette = palette.to_vec();
+        bmp.header.clr_used = palette.len() as u32;
+        let bits = bit_count as usize;
+        for (y, row) in indices.chunks(width.max(1)).enumerate() {
+            let stride = bmp.stride();
+            let line = &mut bmp.data[(height - 1 - y) * stride..(height - y) * stride];
+            for (x, &index) in row.iter().enumerate() {
+                let bit = x * bits;
+                line[bit >> 3] |= index << (8 - bits - (bit & 7));
+            }
+        }
+        Ok(bmp)
+    }
+
+    /// 24-bit image from RGB pixels, from top to bottom.
+    pub fn from_rgb(width: usize, height: usize, rgb: &[u8]) -> Result<Bmp, Error> {
+        let mut bmp = Bmp::new(width, height, 24, rgb.len(), 3)?;
+        let stride = bmp.stride();
+        for (y, row) in rgb.chunks(width.max(1) * 3).enumerate() {
+            let line = &mut bmp.data[(height - 1 - y) * stride..];
+            for (pixel, rgb) in line.chunks_exact_mut(3).zip(row.chunks_exact(3)) {
+                pixel.copy_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+            }
+        }
+        Ok(bmp)
+    }
+
+    /// 32-bit BGRA image with channel masks from RGBA pixels, from top to bottom.
+    pub fn from_rgba(width: usize, height: usize, rgba: &[u8]) -> Result<Bmp, Error> {
+        let mut bmp = Bmp::new(width, height, 32, rgba.len(), 4)?;
+        bmp.masks = Some(ChannelMasks {
+            red: 0x00ff0000,
+            green: 0x0000ff00,
+            blue: 0x000000ff,
+            alpha: 0xff000000,
+        });
+        bmp.header.compression = BI_BITFIELDS;
+        let stride = bmp.stride();
+        for (y, row) in rgba.chunks(width.max(1) * 4).enumerate() {
+            let line = &mut bmp.data[(height - 1 - y) * stride..];
+            for (pixel, rgba) in line.chunks_exact_mut(4).zip(row.chunks_exact(4)) {
+                pixel.copy_from_slice(&[rgba[2], rgba[1], rgba[0], rgba[3]]);
+            }
+        }
+        Ok(bmp)
+    }
+
+    /// Empty bottom-up image, checking that `length` bytes hold the pixels.
+    fn new(
+        width: usize,
+        height: usize,
+        bit_count: u16,
+        length: usize,
+        byte_per_pixel: usize,
+    ) -> Result<Bmp, Error> {
+        if width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(byte_per_pixel))
+            != Some(length)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Image size does not match",
+            ));
+        }
+        let too_large = || Error::new(ErrorKind::InvalidInput, "Image is too large");
+        let header = ImageHeader {
+            width: i32::try_from(width).map_err(|_| too_large())?,
+            height: i32::try_from(height).map_err(|_| too_large())?,
+            planes: 1,
+            bit_count,
+            ..ImageHeader::default()
+        };
+        let size = stride(width, bit_count)
+            .checked_mul(height)
+            .ok_or_else(too_large)?;
         Ok(Bmp {
-            data,
+            data: vec![0; size].into_boxed_slice(),
             width,
             height,
+            bit_count,
+            top_down: false,
+            palette: Vec::new(),
+            embedded: None,
+            icc_profile: None,
+            masks: None,
+            header,
         })
     }
 
+    /// Write an 8-bit palettized BMP file with BITMAPINFOHEADER.
+    ///
+    /// `indices` has one palette index per pixel, from top to bottom.
+    pub fn write_indexed(
+        width: usize,
+        height: usize,
+        palette: &[[u8; 3]],
+        indices: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        Bmp::from_indexed(width, height, 8, palette, indices)?.write(out)
+    }
+
+    /// Write a bottom-up BMP file with BITMAPINFOHEADER, or BITMAPV4HEADER for images with alpha.
+    pub fn write(&self, out: &mut Vec<u8>) -> Result<(), Error> {
+        self.write_with_options(out, &EncodeOptions::default())
+    }
+
+    pub fn write_with_options(
+        &self,
+        out: &mut Vec<u8>,
+        options: &EncodeOptions,
+    ) -> Result<(), Error> {
+        let (info, data) = self.to_dib(options)?;
+        let byte_offset = BitmapFileStructure::SIZE + info.len();
+        let size = u32::try_from(byte_offset + data.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Image is too large"))?;
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&BitmapFileStructure {
+                file_type: [b'B', b'M'],
+                size,
+                reserved1: 0,
+                reserved2: 0,
+                byte_offset: byte_offset as u32,
+            })
+            .bytes(&info)
+            .bytes(&data);
+        out.extend_from_slice(&writer.into_bytes());
+        Ok(())
+    }
+
+    /// Encode the image as a device-independent bitmap without the file header.
+    ///
+    /// The first is BITMAPINFO, the info header followed by the masks and the color table,
+    /// which is also the format of the `strf` chunk of AVI video streams.
+    /// The second is the pixel data from bottom to top, followed by the profile of V5 headers.
+    pub fn to_dib(&self, options: &EncodeOptions) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        if ![HeaderVersion::Info, HeaderVersion::V4, HeaderVersion::V5].contains(&options.version) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Unsupported header version",
+            ));
+        }
+        if ![1, 4, 8, 16, 24, 32].contains(&self.bit_count)
+            || self.stride().checked_mul(self.height) != Some(self.data.len())
+        {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid image data"));
+        }
+        if options.rle && self.bit_count != 8 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "RLE8 requires an 8-bit image",
+            ));
+        }
+
+        let mut data = Vec::with_capacity(self.data.len());
+        for y in (0..self.height).rev() {
+            if options.rle {
+                encode_rle8(&self.row(y)[..self.width], &mut data);
+            } else {
+                data.extend_from_slice(self.row(y));
+            }
+        }
+        if options.rle {
+            // replace the last end of line with the end of bitmap
+            data.truncate(data.len().saturating_sub(2));
+            data.extend_from_slice(&[0, 1]);
+        }
+
+        let masks = self.masks.filter(|_| matches!(self.bit_count, 16 | 32));
+        let version = options.version.max(self.minimum_version());
+        let color_table_size = if self.bit_count <= 8 {
+            self.palette.len() * 4
+        } else {
+            0
+        };
+        // the profile follows the pixel data, as in CF_DIBV5 of the clipboard
+        let (cs_type, profile_data) = match &self.icc_profile {
+            Some(profile) if version == HeaderVersion::V5 => match profile {
+                IccProfile::Embedded(data) => (ColorSpaceType::ProfileEmbedded, data.clone()),
+                IccProfile::Linked(name) => {
+                    let mut data = name
+                        .chars()
+                        .map(encode_windows_1252)
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| {
+                            Error::new(ErrorKind::InvalidInput, "Invalid profile file name")
+                        })?;
+                    data.push(0);
+                    (ColorSpaceType::ProfileLinked, data)
+                }
+            },
+            // a profile cannot be written without the V5 fields
+            _ => match self
+                .header
+                .color_space
+                .as_ref()
+                .map(|color_space| color_space.cs_type)
+            {
+                None | Some(ColorSpaceType::ProfileEmbedded | ColorSpaceType::ProfileLinked) => {
+                    (ColorSpaceType::Srgb, Vec::new())
+                }
+                Some(cs_type) => (cs_type, Vec::new()),
+            },
+        };
+        let profile = Profile {
+            offset: if profile_data.is_empty() {
+                0
+            } else {
+                u32::try_from(version.size() as usize + color_table_size + data.len())
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "Image is too large"))?
+            },
+            size: u32::try_from(profile_data.len())
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "Profile is too large"))?,
+        };
+
+        let compression = match masks {
+            _ if options.rle => BI_RLE8,
+            Some(masks) if Some(masks) != default_masks(self.bit_count) => BI_BITFIELDS,
+            _ => BI_RGB,
+        };
+        let header = ImageHeader {
+            version,
+            width: i32::try_from(self.width)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "Image is too large"))?,
+            height: i32::try_from(self.height)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "Image is too large"))?,
+            planes: 1,
+            bit_count: self.bit_count,
+            compression,
+            size_image: u32::try_from(data.len())
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "Image is too large"))?,
+            xpels_per_meter: self.header.xpels_per_meter,
+            ypels_per_meter: self.header.ypels_per_meter,
+            clr_used: if self.bit_count <= 8 {
+                self.palette.len() as u32
+            } else {
+                0
+            },
+            clr_important: 0,
+            masks: (version >= HeaderVersion::V4).then(|| masks.unwrap_or_default()),
+            color_space: (version >= HeaderVersion::V4).then(|| ColorSpace {
+                cs_type,
+                ..self.header.color_space.clone().unwrap_or_default()
+            }),
+            // LCS_GM_IMAGES
+            intent: (version >= HeaderVersion::V5).then(|| self.header.intent.unwrap_or(4)),
+            profile: (version >= HeaderVersion::V5).then_some(profile),
+        };
+
+        let mut writer = ByteWriter::new();
+        writer.write(&header);
+        if version == HeaderVersion::Info {
+            if let (Some(masks), BI_BITFIELDS) = (masks, compression) {
+                writer
+                    .u32_le(masks.red)
+                    .u32_le(masks.green)
+                    .u32_le(masks.blue);
+            }
+        }
+        if self.bit_count <= 8 {
+            for &[r, g, b] in &self.palette {
+                writer.bytes(&[b, g, r, 0]);
+            }
+        }
+        data.extend_from_slice(&profile_data);
+        Ok((writer.into_bytes(), data))
+    }
+
+    /// BITMAPINFOHEADER can hold an alpha mask only with BI_ALPHABITFIELDS, which is not widely
+    /// supported, so images with alpha need BITMAPV4HEADER.
+    fn minimum_version(&self) -> HeaderVersion {
+        if self.has_alpha() && matches!(self.bit_count, 16 | 32) {
+            HeaderVersion::V4
+        } else {
+            HeaderVersion::Info
+        }
+    }
+
+    /// The image has an alpha channel, given by the alpha mask of BI_BITFIELDS or BI_ALPHABITFIELDS.
+    pub fn has_alpha(&self) -> bool {
+        self.masks.is_some_and(|masks| masks.alpha != 0)
+    }
+
     /// Normalize the image data.
-    /// - Y-order from bottom-to-top to top-to-bottom
-    /// - pixel format from BGR to RGB
+    /// - Y-order from top to bottom, removing the row padding
+    /// - pixel format from BGR, palette indices or channel masks to RGB
     pub fn as_normalized_rgb(&self) -> Box<[u8]> {
-        let mut rgb_data = Vec::new();
+        let mut rgb_data = Vec::with_capacity(self.width * self.height * 3);
+        self.for_each_pixel(|[r, g, b, _]| rgb_data.extend_from_slice(&[r, g, b]));
+        rgb_data.into_boxed_slice()
+    }
+
+    /// Same as `as_normalized_rgb` with an alpha channel, which is 255 when `has_alpha` is false.
+    pub fn as_normalized_rgba(&self) -> Box<[u8]> {
+        let mut rgba_data = Vec::with_capacity(self.width * self.height * 4);
+        self.for_each_pixel(|rgba| rgba_data.extend_from_slice(&rgba));
+        rgba_data.into_boxed_slice()
+    }
+
+    /// Call `f` with the RGBA value of each pixel from top-left to bottom-right.
+    fn for_each_pixel(&self, mut f: impl FnMut([u8; 4])) {
+        let masks = self.masks.unwrap_or_default();
+        let channels = [masks.red, masks.green, masks.blue, masks.alpha].map(Channel::new);
         for y in 0..self.height {
-            for x in 0..self.width {
-                let i = (self.height - 1 - y) * self.width + x;
-                let b = self.data[i * 3];
-                let g = self.data[i * 3 + 1];
-                let r = self.data[i * 3 + 2];
-                rgb_data.push(r);
-                rgb_data.push(g);
-                rgb_data.push(b);
+            let row = self.row(y);
+            match self.bit_count {
+                1 | 4 | 8 => {
+                    let bits = self.bit_count as usize;
+                    let mask = ((1u16 << bits) - 1) as u8;
+                    for x in 0..self.width {
+                        let bit = x * bits;
+                        let index = (row[bit >> 3] >> (8 - bits - (bit & 7))) & mask;
+                        // indices outside the color table are black
+                        let [r, g, b] = self.palette.get(index as usize).unwrap_or(&[0, 0, 0]);
+                        f([*r, *g, *b, 255]);
+                    }
+                }
+                16 | 32 => {
+                    let byte_per_pixel = self.bit_count as usize / 8;
+                    for pixel in row.chunks_exact(byte_per_pixel).take(self.width) {
+                        let mut bytes = [0u8; 4];
+                        bytes[..byte_per_pixel].copy_from_slice(pixel);
+                        let value = u32::from_le_bytes(bytes);
+                        let [r, g, b, a] = channels.map(|channel| channel.extract(value));
+                        f([r, g, b, if masks.alpha == 0 { 255 } else { a }]);
+                    }
+                }
+                _ => {
+                    for pixel in row.chunks_exact(3).take(self.width) {
+                        f([pixel[2], pixel[1], pixel[0], 255]);
+                    }
+                }
             }
         }
-        rgb_data.into_boxed_slice()
     }
 }
 
-fn read_file_header(file: &File) -> Result<BitmapFileStructure, Error> {
-    let mut buf = [0u8; size_of::<BitmapFileStructure>()];
-    file.read_at(&mut buf, 0)?;
-    Ok(unsafe { std::mem::transmute(buf) })
+/// Position and width of a channel in a pixel value.
+#[derive(Clone, Copy)]
+struct Channel {
+    mask: u32,
+    shift: u32,
+    max: u32,
+}
+
+impl Channel {
+    fn new(mask: u32) -> Channel {
+        let shift = mask.trailing_zeros().min(31);
+        Channel {
+            mask,
+            shift,
+            max: mask >> shift,
+        }
+    }
+
+    /// Scale the channel value to 8 bits.
+    fn extract(&self, value: u32) -> u8 {
+        if self.max == 0 {
+            return 0;
+        }
+        let value = (value & self.mask) >> self.shift;
+        ((value as u64 * 255 + self.max as u64 / 2) / self.max as u64) as u8
+    }
+}
+
+/// Bytes per row, saturating instead of overflowing for widths which cannot be allocated.
+fn stride(width: usize, bit_count: u16) -> usize {
+    width.saturating_mul(bit_count as usize).div_ceil(32) * 4
+}
+
+fn read_file_header<R: Read>(reader: &mut R) -> Result<BitmapFileStructure, Error> {
+    let mut buf = [0u8; BitmapFileStructure::SIZE];
+    reader.read_exact(&mut buf)?;
+    BitmapFileStructure::from_bytes(&buf)
+}
+
+/// Read the info header of any version.
+fn read_image_header<R: Read>(reader: &mut R) -> Result<ImageHeader, Error> {
+    let mut size = [0u8; 4];
+    reader.read_exact(&mut size)?;
+    let size = u32::from_le_bytes(size);
+    HeaderVersion::from_size(size)?;
+
+    // the fields of headers larger than BITMAPV5HEADER are skipped
+    let mut buf = [0u8; 124];
+    buf[..4].copy_from_slice(&size.to_le_bytes());
+    let length = (size as usize).min(buf.len());
+    reader.read_exact(&mut buf[4..length])?;
+    let skipped = std::io::copy(
+        &mut reader.take(size as u64 - length as u64),
+        &mut std::io::sink(),
+    )?;
+    if skipped != size as u64 - length as u64 {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Unexpected end of file",
+        ));
+    }
+    ImageHeader::from_bytes(&buf[..length])
+}
+
+/// Channel masks of a 16 or 32-bit image.
+///
+/// BITMAPINFOHEADER is followed by 3 masks for BI_BITFIELDS and 4 for BI_ALPHABITFIELDS,
+/// while later versions hold the masks in the header.
+fn read_masks<R: Read>(
+    reader: &mut R,
+    header: &ImageHeader,
+) -> Result<Option<ChannelMasks>, Error> {
+    let masks = match (header.compression, header.bit_count) {
+        (BI_RGB, 16 | 32) => return Ok(default_masks(header.bit_count)),
+        (BI_BITFIELDS | BI_ALPHABITFIELDS, _) => match header.masks {
+            Some(masks) => masks,
+            None => {
+                let count = if header.compression == BI_BITFIELDS {
+                    3
+                } else {
+                    4
+                };
+                let mut buf = [0u8; 16];
+                reader.read_exact(&mut buf[..count * 4])?;
+                let mut reader = ByteReader::new(&buf);
+                ChannelMasks {
+                    red: reader.u32_le()?,
+                    green: reader.u32_le()?,
+                    blue: reader.u32_le()?,
+                    alpha: reader.u32_le()?,
+                }
+            }
+        },
+        _ => return Ok(None),
+    };
+
+    for mask in [masks.red, masks.green, masks.blue, masks.alpha] {
+        let bits = mask >> mask.trailing_zeros().min(31);
+        // each mask must be a contiguous run of bits within the pixel
+        if bits & bits.wrapping_add(1) != 0 || (header.bit_count == 16 && mask > 0xffff) {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid channel mask"));
+        }
+    }
+    Ok(Some(masks))
+}
+
+/// Masks of 16 and 32-bit images without BI_BITFIELDS.
+fn default_masks(bit_count: u16) -> Option<ChannelMasks> {
+    match bit_count {
+        16 => Some(ChannelMasks {
+            red: 0x7c00,
+            green: 0x03e0,
+            blue: 0x001f,
+            alpha: 0,
+        }),
+        32 => Some(ChannelMasks {
+            red: 0x00ff0000,
+            green: 0x0000ff00,
+            blue: 0x000000ff,
+            alpha: 0,
+        }),
+        _ => None,
+    }
+}
+
+/// Characters 0x80 to 0x9f of Windows-1252, which differ from Latin-1.
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+fn decode_windows_1252(byte: u8) -> char {
+    match byte {
+        0x80..=0x9f => WINDOWS_1252[byte as usize - 0x80],
+        _ => byte as char,
+    }
+}
+
+fn encode_windows_1252(c: char) -> Option<u8> {
+    match WINDOWS_1252.iter().position(|&other| other == c) {
+        Some(index) => Some(0x80 + index as u8),
+        None => u8::try_from(c)
+            .ok()
+            .filter(|byte| !(0x80..=0x9f).contains(byte)),
+    }
 }
 
-fn read_image_header(file: &File) -> Result<BitmapV5Header, Error> {
-    let mut buf = [0u8; size_of::<BitmapV5Header>()];
-    file.read_at(&mut buf, size_of::<BitmapFileStructure>() as u64)?;
-    Ok(unsafe { std::mem::transmute(buf) })
+/// Read the color table which follows the info header.
+///
+/// BITMAPCOREHEADER uses 3-byte RGBTRIPLE entries, the others 4-byte RGBQUAD.
+/// `clr_used` is the number of entries, or all `2^bit_count` entries are present when zero.
+/// Only the first `2^bit_count` entries are kept, and a true color image has no palette.
+fn read_palette<R: Read>(reader: &mut R, header: &ImageHeader) -> Result<Vec<[u8; 3]>, Error> {
+    let max_count = match header.bit_count {
+        1..=8 => 1usize << header.bit_count,
+        _ => 0,
+    };
+    let (count, entry_size) = match header.version {
+        HeaderVersion::Core => (max_count, 3),
+        _ if header.clr_used == 0 => (max_count, 4),
+        _ => (header.clr_used as usize, 4),
+    };
+
+    // entries beyond 2^bit_count, or any entries of a true color image, are skipped
+    let mut buf = vec![0u8; count.min(max_count) * entry_size];
+    reader.read_exact(&mut buf)?;
+    let skip = count.saturating_sub(max_count) as u64 * entry_size as u64;
+    if io::copy(&mut reader.take(skip), &mut io::sink())? < skip {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "Unexpected end of file",
+        ));
+    }
+    Ok(buf
+        .chunks_exact(entry_size)
+        .map(|entry| [entry[2], entry[1], entry[0]])
+        .collect())
+}
+
+/// Expand RLE8 or RLE4 data into bottom-up rows of `bmp.stride()` bytes.
+///
+/// Pixels outside the image are dropped and pixels which are not drawn are left zero.
+///
+/// https://learn.microsoft.com/en-us/windows/win32/gdi/bitmap-compression
+fn decode_rle(compressed: &[u8], bmp: &Bmp) -> Result<Box<[u8]>, Error> {
+    let stride = bmp.stride();
+    let is_rle4 = bmp.bit_count == 4;
+    let mut data = vec![0u8; stride * bmp.height];
+    let mut put = |x: usize, y: usize, value: u8| {
+        if x < bmp.width && y < bmp.height {
+            let row = &mut data[y * stride..(y + 1) * stride];
+            if is_rle4 {
+                row[x / 2] |= if x.is_multiple_of(2) {
+                    value << 4
+                } else {
+                    value & 0x0f
+                };
+            } else {
+                row[x] = value;
+            }
+        }
+    };
+    // value of the i-th pixel of a run or an absolute sequence
+    let pixel = |byte: u8, i: usize| match (is_rle4, i % 2) {
+        (false, _) => byte,
+        (true, 0) => byte >> 4,
+        (true, _) => byte & 0x0f,
+    };
+
+    let (mut x, mut y) = (0usize, 0usize);
+    let mut reader = ByteReader::new(compressed);
+    while reader.remaining() >= 2 {
+        let count = reader.u8()? as usize;
+        let value = reader.u8()?;
+        if count > 0 {
+            for i in 0..count {
+                put(x + i, y, pixel(value, i));
+            }
+            x += count;
+            continue;
+        }
+        match value {
+            // end of line
+            0 => {
+                x = 0;
+                y += 1;
+            }
+            // end of bitmap
+            1 => break,
+            // delta
+            2 => {
+                x += reader.u8()? as usize;
+                y += reader.u8()? as usize;
+                if x > bmp.width || y >= bmp.height {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "RLE delta out of bounds",
+                    ));
+                }
+            }
+            // absolute mode, padded to a 16-bit boundary
+            count => {
+                let count = count as usize;
+                let length = if is_rle4 { count.div_ceil(2) } else { count };
+                let bytes = reader.bytes(length)?;
+                for i in 0..count {
+                    let byte = bytes[if is_rle4 { i / 2 } else { i }];
+                    put(x + i, y, pixel(byte, i));
+                }
+                x += count;
+                reader.skip(length % 2)?;
+            }
+        }
+    }
+
+    Ok(data.into_boxed_slice())
+}
+
+/// Append a row of 8-bit indices compressed with RLE8 and the end of line to `out`.
+///
+/// Runs of 3 or more pixels are encoded, and the others are written in absolute mode.
+fn encode_rle8(row: &[u8], out: &mut Vec<u8>) {
+    let run_length = |start: usize| {
+        row[start..]
+            .iter()
+            .take(255)
+            .take_while(|&&value| value == row[start])
+            .count()
+    };
+
+    let mut x = 0;
+    while x < row.len() {
+        let length = run_length(x);
+        if length >= 3 {
+            out.extend_from_slice(&[length as u8, row[x]]);
+            x += length;
+            continue;
+        }
+
+        // literal pixels until the next run
+        let mut end = x + length;
+        while end < row.len() && end - x < 255 {
+            let length = run_length(end);
+            if length >= 3 {
+                break;
+            }
+            end = (end + length).min(x + 255);
+        }
+        let literal = &row[x..end];
+        if literal.len() < 3 {
+            // absolute mode needs at least 3 pixels
+            for &value in literal {
+                out.extend_from_slice(&[1, value]);
+            }
+        } else {
+            out.extend_from_slice(&[0, literal.len() as u8]);
+            out.extend_from_slice(literal);
+            if literal.len() % 2 == 1 {
+                out.push(0);
+            }
+        }
+        x = end;
+    }
+    out.extend_from_slice(&[0, 0]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use png::png::ColorType;
+
+    #[test]
+    fn test_from_bytes() -> Result<(), Error> {
+        let path = "../../resources/sample_640x426.bmp";
+        let bmp = Bmp::from_bytes(&std::fs::read(path)?)?;
+        assert_eq!((bmp.width, bmp.height), (640, 426));
+        assert_eq!(bmp.data, Bmp::open(path)?.data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_versions() -> Result<(), Error> {
+        let info = ImageHeader {
+            width: 3,
+            height: -2,
+            planes: 1,
+            bit_count: 24,
+            ..ImageHeader::default()
+        };
+        let v5 = ImageHeader {
+            version: HeaderVersion::V5,
+            masks: Some(ChannelMasks {
+                red: 0xff0000,
+                green: 0xff00,
+                blue: 0xff,
+                alpha: 0xff000000,
+            }),
+            color_space: Some(ColorSpace {
+                cs_type: ColorSpaceType::Srgb,
+                ..ColorSpace::default()
+            }),
+            intent: Some(4),
+            profile: Some(Profile::default()),
+            ..info.clone()
+        };
+        let v2 = ImageHeader {
+            version: HeaderVersion::V2,
+            masks: Some(ChannelMasks {
+                alpha: 0,
+                ..v5.masks.unwrap()
+            }),
+            ..info.clone()
+        };
+        let core = ImageHeader {
+            version: HeaderVersion::Core,
+            height: 2,
+            ..info.clone()
+        };
+        for header in [info, v5, v2, core] {
+            let bytes = header.to_bytes();
+            assert_eq!(bytes.len(), header.version.size() as usize);
+            assert_eq!(read_image_header(&mut &bytes[..])?, header);
+            assert!(read_image_header(&mut &bytes[..bytes.len() - 1]).is_err());
+        }
+
+        // a short OS/2 2.x header leaves the other fields zero
+        let mut os2 = ByteWriter::new();
+        os2.u32_le(16).i32_le(5).i32_le(7).u16_le(1).u16_le(24);
+        let header = read_image_header(&mut &os2.into_bytes()[..])?;
+        assert_eq!(header.version, HeaderVersion::Os2V2);
+        assert_eq!((header.width, header.height, header.compression), (5, 7, 0));
+
+        // unknown fields of a larger header are skipped
+        let mut large = v5_bytes_with_size(132);
+        large.extend_from_slice(&[1, 2, 3]);
+        let mut reader = &large[..];
+        assert_eq!(read_image_header(&mut reader)?.version, HeaderVersion::V5);
+        assert_eq!(reader, [1, 2, 3]);
+
+        for size in [8, 13, 100] {
+            assert!(HeaderVersion::from_size(size).is_err());
+        }
+
+        Ok(())
+    }
+
+    fn v5_bytes_with_size(size: u32) -> Vec<u8> {
+        let mut bytes = ImageHeader {
+            version: HeaderVersion::V5,
+            ..ImageHeader::default()
+        }
+        .to_bytes();
+        bytes[..4].copy_from_slice(&size.to_le_bytes());
+        bytes.resize(size as usize, 0);
+        bytes
+    }
+
+    #[test]
+    fn test_write_indexed() -> Result<(), Error> {
+        let palette = [[255, 0, 0], [0, 0, 255]];
+        let mut out = Vec::new();
+        Bmp::write_indexed(3, 2, &palette, &[0, 1, 0, 1, 1, 1], &mut out)?;
+
+        let mut reader = &out[..];
+        let file_header = read_file_header(&mut reader)?;
+        let image_header = read_image_header(&mut reader)?;
+        assert_eq!(file_header.size as usize, out.len());
+        assert_eq!((image_header.width, image_header.height), (3, 2));
+        assert_eq!((image_header.bit_count, image_header.clr_used), (8, 2));
+        assert_eq!(&reader[..8], [0, 0, 255, 0, 255, 0, 0, 0]);
+        // bottom row first, padded to 4 bytes
+        assert_eq!(
+            &out[file_header.byte_offset as usize..],
+            [1, 1, 1, 0, 0, 1, 0, 0]
+        );
+
+        assert!(Bmp::write_indexed(3, 2, &palette, &[2; 6], &mut out).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_round_trip() -> Result<(), Error> {
+        let (width, height) = (7, 5);
+        let rgba = (0..width * height * 4)
+            .map(|i| (i * 37 % 256) as u8)
+            .collect::<Vec<_>>();
+        let rgb = rgba
+            .chunks_exact(4)
+            .flat_map(|pixel| &pixel[..3])
+            .copied()
+            .collect::<Vec<_>>();
+        let palette = [[0, 0, 0], [255, 0, 0], [0, 255, 0], [0, 0, 255]];
+        let indices = (0..width * height)
+            .map(|i| (i % 7 % 4) as u8)
+            .collect::<Vec<_>>();
+        let expected = |indices: &[u8]| {
+            indices
+                .iter()
+                .flat_map(|&index| palette[index as usize])
+                .collect::<Vec<_>>()
+        };
+        let bits = indices.iter().map(|index| index % 2).collect::<Vec<_>>();
+
+        let images = [
+            (
+                Bmp::from_indexed(width, height, 1, &palette[..2], &bits)?,
+                expected(&bits),
+            ),
+            (
+                Bmp::from_indexed(width, height, 4, &palette, &indices)?,
+                expected(&indices),
+            ),
+            (
+                Bmp::from_indexed(width, height, 8, &palette, &indices)?,
+                expected(&indices),
+            ),
+            (Bmp::from_rgb(width, height, &rgb)?, rgb.clone()),
+            (Bmp::from_rgba(width, height, &rgba)?, rgb.clone()),
+        ];
+        for (image, expected) in &images {
+            for version in [HeaderVersion::Info, HeaderVersion::V4, HeaderVersion::V5] {
+                let mut out = Vec::new();
+                image.write_with_options(
+                    &mut out,
+                    &EncodeOptions {
+                        version,
+                        rle: false,
+                    },
+                )?;
+                let bmp = Bmp::from_bytes(&out)?;
+                assert_eq!(bmp.header.version, version.max(image.minimum_version()));
+                assert_eq!(*bmp.as_normalized_rgb(), **expected);
+                assert_eq!(bmp.as_normalized_rgba(), image.as_normalized_rgba());
+            }
+        }
+        assert_eq!(*images[4].0.as_normalized_rgba(), rgba);
+
+        // a packed DIB is the output of to_dib without the file header
+        let (info, data) = images[2].0.to_dib(&EncodeOptions::default())?;
+        let bmp = Bmp::read_dib(&mut Cursor::new([info, data].concat()))?;
+        assert_eq!(bmp.as_normalized_rgb(), images[2].0.as_normalized_rgb());
+
+        // images with alpha are written with BITMAPV4HEADER and BI_BITFIELDS
+        let mut out = Vec::new();
+        images[4].0.write(&mut out)?;
+        let bmp = Bmp::from_bytes(&out)?;
+        assert_eq!(bmp.header.version, HeaderVersion::V4);
+        assert_eq!(bmp.header.compression, BI_BITFIELDS);
+        assert!(bmp.has_alpha());
+
+        assert!(images[3]
+            .0
+            .write_with_options(
+                &mut out,
+                &EncodeOptions {
+                    version: HeaderVersion::V2,
+                    rle: false
+                }
+            )
+            .is_err());
+        assert!(Bmp::from_indexed(width, height, 1, &palette, &indices).is_err());
+        assert!(Bmp::from_rgb(width, height, &rgba).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_rle8() -> Result<(), Error> {
+        let palette = (0..=255).map(|i| [i, i, i]).collect::<Vec<_>>();
+        let rows: [&[u8]; 4] = [
+            &[5; 300],
+            &[1, 2, 3, 4, 5, 6, 7, 8, 9, 9, 9, 9, 1, 2, 0, 0],
+            &[1, 2, 1, 2, 7, 7, 7, 7, 7, 3, 3],
+            &[9, 8, 7],
+        ];
+        for row in rows {
+            let image = Bmp::from_indexed(row.len(), 2, 8, &palette, &[row, row].concat())?;
+            let mut out = Vec::new();
+            image.write_with_options(
+                &mut out,
+                &EncodeOptions {
+                    rle: true,
+                    ..EncodeOptions::default()
+                },
+            )?;
+            let bmp = Bmp::from_bytes(&out)?;
+            assert_eq!(bmp.header.compression, BI_RLE8);
+            assert_eq!(bmp.data, image.data);
+            assert_eq!(&out[out.len() - 2..], [0, 1]);
+        }
+
+        let mut out = Vec::new();
+        Bmp::from_indexed(300, 1, 8, &palette, &[5; 300])?.write_with_options(
+            &mut out,
+            &EncodeOptions {
+                rle: true,
+                ..EncodeOptions::default()
+            },
+        )?;
+        assert!(out.ends_with(&[255, 5, 45, 5, 0, 1]));
+
+        let image = Bmp::from_rgb(1, 1, &[0; 3])?;
+        assert!(image
+            .write_with_options(
+                &mut out,
+                &EncodeOptions {
+                    rle: true,
+                    ..EncodeOptions::default()
+                }
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_color_space() -> Result<(), Error> {
+        assert_eq!(ColorSpaceType::from(0x73524742), ColorSpaceType::Srgb);
+        for cs_type in [0, 0x73524742, 0x57696e20, 0x4c494e4b, 0x4d424544, 1] {
+            assert_eq!(u32::from(ColorSpaceType::from(cs_type)), cs_type);
+        }
+
+        assert_eq!(fxpt2dot30_to_f64(1 << 30), 1.0);
+        assert_eq!(fxpt2dot30_to_f64(3 << 29), 1.5);
+        let xyz = [0.4124, 0.2126, 0.0193];
+        let converted = CieXYZ::from_f64(xyz).to_f64();
+        assert!(xyz.iter().zip(converted).all(|(a, b)| (a - b).abs() < 1e-9));
+        let color_space = ColorSpace {
+            gamma_red: 0x00023333,
+            ..ColorSpace::default()
+        };
+        assert!((color_space.gamma()[0] - 2.2).abs() < 1e-4);
+
+        let mut image = Bmp::from_rgb(2, 1, &[1, 2, 3, 4, 5, 6])?;
+        for profile in [
+            IccProfile::Embedded(b"profile data".to_vec()),
+            IccProfile::Linked("C:\\profiles\\café €.icc".to_string()),
+        ] {
+            image.icc_profile = Some(profile);
+            let mut out = Vec::new();
+            image.write_with_options(
+                &mut out,
+                &EncodeOptions {
+                    version: HeaderVersion::V5,
+                    rle: false,
+                },
+            )?;
+            let bmp = Bmp::from_bytes(&out)?;
+            assert_eq!(bmp.icc_profile, image.icc_profile);
+            assert_eq!(*bmp.as_normalized_rgb(), [1, 2, 3, 4, 5, 6]);
+
+            // a packed DIB has the profile after the pixel data
+            let options = EncodeOptions {
+                version: HeaderVersion::V5,
+                rle: false,
+            };
+            let (info, data) = image.to_dib(&options)?;
+            let mut reader = Cursor::new([&info[..], &data].concat());
+            let bmp = Bmp::read_dib(&mut reader)?;
+            assert_eq!(bmp.icc_profile, image.icc_profile);
+            assert_eq!(*bmp.as_normalized_rgb(), [1, 2, 3, 4, 5, 6]);
+            assert_eq!(reader.position() as usize, info.len() + 8);
+        }
+
+        // a profile between the color table and the pixel data is skipped
+        let (info, data) = image.to_dib(&EncodeOptions {
+            version: HeaderVersion::V5,
+            rle: false,
+        })?;
+        let (pixels, profile) = data.split_at(8);
+        let mut dib = [&info[..], profile, pixels].concat();
+        // bV5ProfileData
+        dib[112..116].copy_from_slice(&(info.len() as u32).to_le_bytes());
+        let mut reader = Cursor::new(dib);
+        let bmp = Bmp::read_dib(&mut reader)?;
+        assert_eq!(bmp.icc_profile, image.icc_profile);
+        assert_eq!(*bmp.as_normalized_rgb(), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(reader.position() as usize, info.len() + data.len());
+
+        // V4 headers cannot refer to a profile
+        let mut out = Vec::new();
+        image.write_with_options(
+            &mut out,
+            &EncodeOptions {
+                version: HeaderVersion::V4,
+                rle: false,
+            },
+        )?;
+        let bmp = Bmp::from_bytes(&out)?;
+        assert_eq!(bmp.icc_profile, None);
+        assert_eq!(
+            bmp.header.color_space.unwrap().cs_type,
+            ColorSpaceType::Srgb
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_embedded_image() -> Result<(), Error> {
+        let rgba = [255, 0, 0, 255, 0, 255, 0, 128, 0, 0, 255, 0, 9, 9, 9, 9];
+        let png = Png {
+            width: 2,
+            height: 2,
+            color_type: ColorType::TruecolorAlpha,
+            data: Box::new(rgba),
+            ..Png::default()
+        };
+        let mut stream = Vec::new();
+        png.write(&mut stream)?;
+        let header = ImageHeader {
+            width: 2,
+            height: 2,
+            planes: 1,
+            compression: BI_PNG,
+            size_image: stream.len() as u32,
+            ..ImageHeader::default()
+        };
+        let bmp = Bmp::from_bytes(&bmp_bitfields(header.clone(), &[], &stream))?;
+        assert_eq!(bmp.embedded, Some(EmbeddedImage::Png(stream.clone())));
+        assert!(bmp.has_alpha());
+        assert_eq!(*bmp.as_normalized_rgba(), rgba);
+
+        // the size in the header must match the PNG image
+        let wrong_size = ImageHeader {
+            width: 3,
+            ..header.clone()
+        };
+        assert!(Bmp::from_bytes(&bmp_bitfields(wrong_size, &[], &stream)).is_err());
+
+        // JPEG streams are returned as they are
+        let jpeg = [0xff, 0xd8, 0xff, 0xd9];
+        let header = ImageHeader {
+            compression: BI_JPEG,
+            size_image: 4,
+            ..header
+        };
+        let bmp = Bmp::from_bytes(&bmp_bitfields(header, &[], &jpeg))?;
+        assert_eq!(bmp.embedded, Some(EmbeddedImage::Jpeg(jpeg.to_vec())));
+        assert!(bmp.data.is_empty());
+
+        Ok(())
+    }
+
+    /// 24-bit BMP file with the rows in the order given.
+    fn bmp_24bit(width: i32, height: i32, rows: &[&[u8]]) -> Vec<u8> {
+        let pixels = rows.concat();
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&BitmapFileStructure {
+                file_type: [b'B', b'M'],
+                size: (54 + pixels.len()) as u32,
+                byte_offset: 54,
+                ..BitmapFileStructure::default()
+            })
+            .write(&ImageHeader {
+                width,
+                height,
+                planes: 1,
+                bit_count: 24,
+                ..ImageHeader::default()
+            })
+            .bytes(&pixels);
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn test_row_padding_and_orientation() -> Result<(), Error> {
+        // 2x2 pixels: 6 bytes of BGR and 2 bytes of padding per row
+        let top: &[u8] = &[0, 0, 255, 0, 255, 0, 0, 0];
+        let bottom: &[u8] = &[255, 0, 0, 255, 255, 255, 0, 0];
+        let expected = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+
+        let bottom_up = Bmp::from_bytes(&bmp_24bit(2, 2, &[bottom, top]))?;
+        assert_eq!(bottom_up.stride(), 8);
+        assert!(!bottom_up.top_down);
+        assert_eq!(&bottom_up.as_normalized_rgb()[..], expected);
+
+        let top_down = Bmp::from_bytes(&bmp_24bit(2, -2, &[top, bottom]))?;
+        assert_eq!(top_down.height, 2);
+        assert!(top_down.top_down);
+        assert_eq!(&top_down.as_normalized_rgb()[..], expected);
+
+        assert!(Bmp::from_bytes(&bmp_24bit(-2, 2, &[bottom, top])).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_palette() -> Result<(), Error> {
+        // 8-bit with RGBQUAD entries
+        let palette = [[255, 0, 0], [0, 0, 255], [10, 20, 30]];
+        let mut out = Vec::new();
+        Bmp::write_indexed(3, 2, &palette, &[0, 1, 2, 2, 1, 0], &mut out)?;
+        let bmp = Bmp::from_bytes(&out)?;
+        assert_eq!(bmp.palette, palette);
+        assert_eq!(
+            &bmp.as_normalized_rgb()[..],
+            [palette[0], palette[1], palette[2], palette[2], palette[1], palette[0]].concat()
+        );
+
+        // 1-bit with BITMAPCOREHEADER and RGBTRIPLE entries, 10 pixels per row
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&BitmapFileStructure {
+                file_type: [b'B', b'M'],
+                byte_offset: 14 + 12 + 6,
+                ..BitmapFileStructure::default()
+            })
+            .write(&ImageHeader {
+                version: HeaderVersion::Core,
+                width: 10,
+                height: 2,
+                planes: 1,
+                bit_count: 1,
+                ..ImageHeader::default()
+            })
+            .bytes(&[0, 0, 0, 255, 255, 255])
+            .bytes(&[0b11000000, 0b01000000, 0, 0])
+            .bytes(&[0b10101010, 0b10000000, 0, 0]);
+        let bmp = Bmp::from_bytes(&writer.into_bytes())?;
+        let gray = bmp
+            .as_normalized_rgb()
+            .chunks(3)
+            .map(|rgb| rgb[0] / 255)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            gray,
+            [1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+
+        // 4-bit with only 2 entries in clr_used
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&BitmapFileStructure {
+                file_type: [b'B', b'M'],
+                byte_offset: 14 + 40 + 8,
+                ..BitmapFileStructure::default()
+            })
+            .write(&ImageHeader {
+                width: 3,
+                height: 1,
+                planes: 1,
+                bit_count: 4,
+                clr_used: 2,
+                ..ImageHeader::default()
+            })
+            .bytes(&[0, 0, 255, 0, 0, 255, 0, 0])
+            .bytes(&[0x01, 0x20, 0, 0]);
+        let bmp = Bmp::from_bytes(&writer.into_bytes())?;
+        assert_eq!(bmp.palette.len(), 2);
+        assert_eq!(
+            &bmp.as_normalized_rgb()[..],
+            [255, 0, 0, 0, 255, 0, 0, 0, 0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_packed_dib_color_table() -> Result<(), Error> {
+        // 1-bit with 4 entries in clr_used, the pixels follow all of them
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&ImageHeader {
+                width: 2,
+                height: 1,
+                planes: 1,
+                bit_count: 1,
+                clr_used: 4,
+                ..ImageHeader::default()
+            })
+            .bytes(&[0, 0, 255, 0, 255, 0, 0, 0])
+            .bytes(&[0, 255, 0, 0, 9, 9, 9, 0])
+            .bytes(&[0b01000000, 0, 0, 0]);
+        let bmp = Bmp::read_dib(&mut Cursor::new(writer.into_bytes()))?;
+        assert_eq!(bmp.palette, [[255, 0, 0], [0, 0, 255]]);
+        assert_eq!(&bmp.as_normalized_rgb()[..], [255, 0, 0, 0, 0, 255]);
+
+        // 24-bit with an optimization palette of 2 entries
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&ImageHeader {
+                width: 1,
+                height: 1,
+                planes: 1,
+                bit_count: 24,
+                clr_used: 2,
+                ..ImageHeader::default()
+            })
+            .bytes(&[1, 2, 3, 0, 4, 5, 6, 0])
+            .bytes(&[30, 20, 10, 0]);
+        let bmp = Bmp::read_dib(&mut Cursor::new(writer.into_bytes()))?;
+        assert!(bmp.palette.is_empty());
+        assert_eq!(&bmp.as_normalized_rgb()[..], [10, 20, 30]);
+
+        // the color table is truncated
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&ImageHeader {
+                width: 1,
+                height: 1,
+                planes: 1,
+                bit_count: 24,
+                clr_used: 256,
+                ..ImageHeader::default()
+            })
+            .bytes(&[30, 20, 10, 0]);
+        assert!(Bmp::read_dib(&mut Cursor::new(writer.into_bytes())).is_err());
+
+        Ok(())
+    }
+
+    /// Palettized BMP file with the compressed data, using gray palette entries equal to the index.
+    fn bmp_rle(compression: u32, bit_count: u16, width: i32, height: i32, data: &[u8]) -> Vec<u8> {
+        let colors = 1usize << bit_count;
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&BitmapFileStructure {
+                file_type: [b'B', b'M'],
+                byte_offset: (14 + 40 + colors * 4) as u32,
+                ..BitmapFileStructure::default()
+            })
+            .write(&ImageHeader {
+                width,
+                height,
+                planes: 1,
+                bit_count,
+                compression,
+                size_image: data.len() as u32,
+                ..ImageHeader::default()
+            });
+        for i in 0..colors {
+            writer.bytes(&[i as u8, i as u8, i as u8, 0]);
+        }
+        writer.bytes(data);
+        writer.into_bytes()
+    }
+
+    /// Palette indices from top to bottom.
+    fn indices(bmp: &Bmp) -> Vec<u8> {
+        bmp.as_normalized_rgb().iter().step_by(3).copied().collect()
+    }
+
+    #[test]
+    fn test_rle8() -> Result<(), Error> {
+        // the example in the documentation of bitmap compression
+        let data = [
+            0x03, 0x04, 0x05, 0x06, 0x00, 0x03, 0x45, 0x56, 0x67, 0x00, 0x02, 0x78, 0x00, 0x02,
+            0x05, 0x01, 0x02, 0x78, 0x00, 0x00, 0x09, 0x1e, 0x00, 0x01,
+        ];
+        let bmp = Bmp::from_bytes(&bmp_rle(BI_RLE8, 8, 20, 3, &data))?;
+        let mut expected = vec![0u8; 60];
+        expected[..9].copy_from_slice(&[0x1e; 9]);
+        expected[38..40].copy_from_slice(&[0x78; 2]);
+        expected[40..53].copy_from_slice(&[
+            0x04, 0x04, 0x04, 0x06, 0x06, 0x06, 0x06, 0x06, 0x45, 0x56, 0x67, 0x78, 0x78,
+        ]);
+        assert_eq!(indices(&bmp), expected);
+
+        // delta beyond the bottom of the image
+        let data = [0x00, 0x02, 0x00, 0x03, 0x00, 0x01];
+        assert!(Bmp::from_bytes(&bmp_rle(BI_RLE8, 8, 20, 3, &data)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rle4() -> Result<(), Error> {
+        let data = [
+            0x03, 0x04, 0x05, 0x06, 0x00, 0x06, 0x45, 0x56, 0x67, 0x00, 0x04, 0x78, 0x00, 0x02,
+            0x05, 0x01, 0x04, 0x78, 0x00, 0x00, 0x09, 0x1e, 0x00, 0x01,
+        ];
+        let bmp = Bmp::from_bytes(&bmp_rle(BI_RLE4, 4, 32, 3, &data))?;
+        let mut expected = vec![0u8; 96];
+        expected[..9].copy_from_slice(&[1, 14, 1, 14, 1, 14, 1, 14, 1]);
+        expected[55..59].copy_from_slice(&[7, 8, 7, 8]);
+        expected[64..82].copy_from_slice(&[0, 4, 0, 0, 6, 0, 6, 0, 4, 5, 5, 6, 6, 7, 7, 8, 7, 8]);
+        assert_eq!(indices(&bmp), expected);
+
+        assert!(Bmp::from_bytes(&bmp_rle(BI_RLE4, 8, 32, 3, &data)).is_err());
+
+        Ok(())
+    }
+
+    /// BMP file with the header, the masks following it and the pixel data.
+    fn bmp_bitfields(header: ImageHeader, masks: &[u32], data: &[u8]) -> Vec<u8> {
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&BitmapFileStructure {
+                file_type: [b'B', b'M'],
+                byte_offset: (14 + header.version.size() as usize + masks.len() * 4) as u32,
+                ..BitmapFileStructure::default()
+            })
+            .write(&header);
+        for &mask in masks {
+            writer.u32_le(mask);
+        }
+        writer.bytes(data);
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn test_bitfields_16bit() -> Result<(), Error> {
+        let header = ImageHeader {
+            width: 2,
+            height: -1,
+            planes: 1,
+            bit_count: 16,
+            ..ImageHeader::default()
+        };
+        // white and pure green in 5-5-5
+        let data = [0xff, 0x7f, 0xe0, 0x03];
+        let bmp = Bmp::from_bytes(&bmp_bitfields(header.clone(), &[], &data))?;
+        assert!(!bmp.has_alpha());
+        assert_eq!(*bmp.as_normalized_rgb(), [255, 255, 255, 0, 255, 0]);
+
+        // 5-6-5 masks after BITMAPINFOHEADER, the lowest bit of green is half of a step
+        let header = ImageHeader {
+            compression: BI_BITFIELDS,
+            ..header
+        };
+        let data = [0x1f, 0x00, 0x40, 0x04];
+        let bmp = Bmp::from_bytes(&bmp_bitfields(
+            header.clone(),
+            &[0xf800, 0x07e0, 0x001f],
+            &data,
+        ))?;
+        assert_eq!(*bmp.as_normalized_rgb(), [0, 0, 255, 0, 138, 0]);
+
+        // masks must be contiguous and fit in 16 bits
+        assert!(Bmp::from_bytes(&bmp_bitfields(
+            header.clone(),
+            &[0xf801, 0x07e0, 0x001e],
+            &data
+        ))
+        .is_err());
+        assert!(
+            Bmp::from_bytes(&bmp_bitfields(header, &[0xff0000, 0x07e0, 0x001f], &data)).is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitfields_32bit() -> Result<(), Error> {
+        // RGBA byte order with BI_ALPHABITFIELDS
+        let header = ImageHeader {
+            width: 2,
+            height: -1,
+            planes: 1,
+            bit_count: 32,
+            compression: BI_ALPHABITFIELDS,
+            ..ImageHeader::default()
+        };
+        let masks = [0x000000ff, 0x0000ff00, 0x00ff0000, 0xff000000];
+        let data = [10, 20, 30, 40, 50, 60, 70, 255];
+        let bmp = Bmp::from_bytes(&bmp_bitfields(header.clone(), &masks, &data))?;
+        assert!(bmp.has_alpha());
+        assert_eq!(*bmp.as_normalized_rgba(), data);
+        assert_eq!(*bmp.as_normalized_rgb(), [10, 20, 30, 50, 60, 70]);
+
+        // the masks in BITMAPV5HEADER, 10-10-10 without alpha
+        let header = ImageHeader {
+            version: HeaderVersion::V5,
+            compression: BI_BITFIELDS,
+            masks: Some(ChannelMasks {
+                red: 0x3ff00000,
+                green: 0x000ffc00,
+                blue: 0x000003ff,
+                alpha: 0,
+            }),
+            ..header
+        };
+        let data = [0xff, 0x03, 0x00, 0x00, 0x00, 0x00, 0xf0, 0x3f];
+        let bmp = Bmp::from_bytes(&bmp_bitfields(header, &[], &data))?;
+        assert!(!bmp.has_alpha());
+        assert_eq!(*bmp.as_normalized_rgba(), [0, 0, 255, 255, 255, 0, 0, 255]);
+
+        // BI_RGB ignores the fourth byte
+        let header = ImageHeader {
+            width: 1,
+            height: -1,
+            planes: 1,
+            bit_count: 32,
+            ..ImageHeader::default()
+        };
+        let bmp = Bmp::from_bytes(&bmp_bitfields(header, &[], &[1, 2, 3, 4]))?;
+        assert_eq!(*bmp.as_normalized_rgba(), [3, 2, 1, 255]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed() -> Result<(), Error> {
+        // the size is checked before allocating the pixel data
+        let huge = ImageHeader {
+            width: 100000,
+            height: -100000,
+            planes: 1,
+            bit_count: 32,
+            ..ImageHeader::default()
+        };
+        assert!(Bmp::from_bytes(&bmp_bitfields(huge, &[], &[0; 16])).is_err());
+        let image = bmp_24bit(3, 2, &[&[0; 12], &[0; 12]]);
+        let options = DecodeOptions { max_pixels: 5 };
+        assert!(Bmp::read_with_options(&mut Cursor::new(&image), &options).is_err());
+        let options = DecodeOptions { max_pixels: 6 };
+        assert!(Bmp::read_with_options(&mut Cursor::new(&image), &options).is_ok());
+
+        // the pixel data must be within the file
+        let mut offset = image.clone();
+        offset[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Bmp::from_bytes(&offset).is_err());
+
+        let mut samples = vec![image];
+        let mut out = Vec::new();
+        let mut rgba = Bmp::from_rgba(3, 3, &[7; 36])?;
+        rgba.icc_profile = Some(IccProfile::Embedded(vec![1; 20]));
+        rgba.write_with_options(
+            &mut out,
+            &EncodeOptions {
+                version: HeaderVersion::V5,
+                rle: false,
+            },
+        )?;
+        samples.push(out.clone());
+        out.clear();
+        let palette = [[1, 2, 3], [4, 5, 6]];
+        Bmp::from_indexed(9, 3, 8, &palette, &[1; 27])?.write_with_options(
+            &mut out,
+            &EncodeOptions {
+                rle: true,
+                ..EncodeOptions::default()
+            },
+        )?;
+        samples.push(out.clone());
+        out.clear();
+        Bmp::from_indexed(9, 3, 1, &palette, &[0; 27])?.write(&mut out)?;
+        samples.push(out);
+
+        // truncated or corrupted files are errors, not panics
+        let options = DecodeOptions {
+            max_pixels: 1 << 16,
+        };
+        let mut state = 0x2545f491u32;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as usize
+        };
+        for sample in &samples {
+            for length in 0..sample.len() {
+                assert!(Bmp::from_bytes(&sample[..length]).is_err());
+            }
+            for _ in 0..2000 {
+                let mut corrupted = sample.clone();
+                for _ in 0..1 + random() % 4 {
+                    let index = random() % corrupted.len();
+                    corrupted[index] = random() as u8;
+                }
+                if let Ok(bmp) = Bmp::read_with_options(&mut Cursor::new(&corrupted), &options) {
+                    bmp.as_normalized_rgba();
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
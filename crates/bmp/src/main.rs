@@ -16,14 +16,14 @@ fn main() {
 
     let mut window = Window::new(0, 0, bmp.width as i32, bmp.height as i32, "image");
     window.draw(move |f| {
-        RgbImage::new(
-            bmp.as_normalized_rgb().as_ref(),
-            bmp.width as i32,
-            bmp.height as i32,
-            ColorDepth::Rgb8,
-        )
-        .unwrap()
-        .draw(0, 0, f.w(), f.h());
+        let (data, depth) = if bmp.has_alpha() {
+            (bmp.as_normalized_rgba(), ColorDepth::Rgba8)
+        } else {
+            (bmp.as_normalized_rgb(), ColorDepth::Rgb8)
+        };
+        RgbImage::new(data.as_ref(), bmp.width as i32, bmp.height as i32, depth)
+            .unwrap()
+            .draw(0, 0, f.w(), f.h());
     });
     window.show();
     App::default().run().unwrap();
@@ -0,0 +1,588 @@
+use binary::bytes::{ByteReader, ByteWriter, FromBytes, ToBytes};
+use bmp::bmp::{Bmp, ChannelMasks, DecodeOptions, ImageHeader, BI_RGB};
+use png::png::{ColorType, Png};
+use std::fs::File;
+use std::io::{Cursor, Error, ErrorKind, Read};
+use std::path::Path;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Icon (.ico) or cursor (.cur) file, a directory of BMP or PNG images.
+///
+/// https://learn.microsoft.com/en-us/previous-versions/ms997538(v=msdn.10)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ico {
+    pub resource_type: ResourceType,
+    pub images: Vec<IconImage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceType {
+    Icon = 1,
+    Cursor = 2,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconImage {
+    pub width: usize,
+    pub height: usize,
+    /// RGBA pixels from top to bottom.
+    pub rgba: Box<[u8]>,
+    /// Hotspot of cursors from the top-left corner. Icons do not have one.
+    pub hotspot: (u16, u16),
+    pub format: ImageFormat,
+}
+
+/// Encoding of an image in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Packed DIB with a 1-bit AND mask for transparency.
+    Bmp,
+    Png,
+}
+
+/// ICONDIR without the entries
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IconDir {
+    pub reserved: u16,
+    pub resource_type: u16,
+    pub count: u16,
+}
+
+/// ICONDIRENTRY
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IconDirEntry {
+    /// 0 means 256 pixels.
+    pub width: u8,
+    /// 0 means 256 pixels.
+    pub height: u8,
+    pub color_count: u8,
+    pub reserved: u8,
+    /// Color planes of icons, the horizontal hotspot of cursors.
+    pub planes: u16,
+    /// Bits per pixel of icons, the vertical hotspot of cursors.
+    pub bit_count: u16,
+    pub bytes_in_res: u32,
+    /// Offset from the beginning of the file.
+    pub image_offset: u32,
+}
+
+impl IconDir {
+    pub const SIZE: usize = 6;
+}
+
+impl IconDirEntry {
+    pub const SIZE: usize = 16;
+}
+
+impl FromBytes for IconDir {
+    fn read_from(reader: &mut ByteReader) -> Result<IconDir, Error> {
+        Ok(IconDir {
+            reserved: reader.u16_le()?,
+            resource_type: reader.u16_le()?,
+            count: reader.u16_le()?,
+        })
+    }
+}
+
+impl ToBytes for IconDir {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer
+            .u16_le(self.reserved)
+            .u16_le(self.resource_type)
+            .u16_le(self.count);
+    }
+}
+
+impl FromBytes for IconDirEntry {
+    fn read_from(reader: &mut ByteReader) -> Result<IconDirEntry, Error> {
+        Ok(IconDirEntry {
+            width: reader.u8()?,
+            height: reader.u8()?,
+            color_count: reader.u8()?,
+            reserved: reader.u8()?,
+            planes: reader.u16_le()?,
+            bit_count: reader.u16_le()?,
+            bytes_in_res: reader.u32_le()?,
+            image_offset: reader.u32_le()?,
+        })
+    }
+}
+
+impl ToBytes for IconDirEntry {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer
+            .u8(self.width)
+            .u8(self.height)
+            .u8(self.color_count)
+            .u8(self.reserved)
+            .u16_le(self.planes)
+            .u16_le(self.bit_count)
+            .u32_le(self.bytes_in_res)
+            .u32_le(self.image_offset);
+    }
+}
+
+impl Ico {
+    pub fn open(path: impl AsRef<Path>) -> Result<Ico, Error> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Ico::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Ico, Error> {
+        let mut reader = ByteReader::new(bytes);
+        let dir: IconDir = reader.read()?;
+        let resource_type = match (dir.reserved, dir.resource_type) {
+            (0, 1) => ResourceType::Icon,
+            (0, 2) => ResourceType::Cursor,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Not an icon file")),
+        };
+        let entries = (0..dir.count)
+            .map(|_| reader.read::<IconDirEntry>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let images = entries
+            .iter()
+            .map(|entry| {
+                let start = entry.image_offset as usize;
+                let data = start
+                    .checked_add(entry.bytes_in_res as usize)
+                    .and_then(|end| bytes.get(start..end))
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Image out of range"))?;
+                let mut image = decode_image(data, entry)?;
+                if resource_type == ResourceType::Cursor {
+                    image.hotspot = (entry.planes, entry.bit_count);
+                }
+                Ok(image)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Ico {
+            resource_type,
+            images,
+        })
+    }
+
+    /// Write the images in order, each as a 32-bit BMP or a PNG.
+    pub fn write(&self, out: &mut Vec<u8>) -> Result<(), Error> {
+        let count = u16::try_from(self.images.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Too many images"))?;
+        let encoded = self
+            .images
+            .iter()
+            .map(encode_image)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut writer = ByteWriter::new();
+        writer.write(&IconDir {
+            reserved: 0,
+            resource_type: self.resource_type as u16,
+            count,
+        });
+        let mut offset = IconDir::SIZE + IconDirEntry::SIZE * self.images.len();
+        for (image, data) in self.images.iter().zip(&encoded) {
+            let (planes, bit_count) = match self.resource_type {
+                ResourceType::Icon => (1, 32),
+                ResourceType::Cursor => image.hotspot,
+            };
+            writer.write(&IconDirEntry {
+                // 256 wraps to 0
+                width: image.width as u8,
+                height: image.height as u8,
+                color_count: 0,
+                reserved: 0,
+                planes,
+                bit_count,
+                bytes_in_res: data.len() as u32,
+                image_offset: u32::try_from(offset)
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "Icon is too large"))?,
+            });
+            offset += data.len();
+        }
+        for data in &encoded {
+            writer.bytes(data);
+        }
+        out.extend_from_slice(&writer.into_bytes());
+        Ok(())
+    }
+}
+
+/// Decode an image, taking its size from the image itself.
+///
+/// The size in `entry` is often inaccurate, so it only bounds the image before the pixels are allocated.
+fn decode_image(data: &[u8], entry: &IconDirEntry) -> Result<IconImage, Error> {
+    let max_size = (
+        if entry.width == 0 {
+            256
+        } else {
+            entry.width as usize
+        },
+        if entry.height == 0 {
+            256
+        } else {
+            entry.height as usize
+        },
+    );
+
+    if data.starts_with(&PNG_SIGNATURE) {
+        let mut ihdr = ByteReader::new(data.get(16..24).unwrap_or_default());
+        if ihdr.u32_be()? as usize > max_size.0 || ihdr.u32_be()? as usize > max_size.1 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Image is larger than the directory entry",
+            ));
+        }
+        let png = Png::from_bytes(data)?;
+        return Ok(IconImage {
+            width: png.width,
+            height: png.height,
+            rgba: png.to_rgba8(),
+            hotspot: (0, 0),
+            format: ImageFormat::Png,
+        });
+    }
+
+    // the height in the info header counts the rows of both the XOR bitmap and the AND mask,
+    // so halve it to read the XOR bitmap as a packed DIB
+    let mut data = data.to_vec();
+    let header_size = ByteReader::new(&data).u32_le()?;
+    let field = if header_size == 12 { 6..8 } else { 8..12 };
+    let height = data
+        .get_mut(field)
+        .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Unexpected end of file"))?;
+    match height.len() {
+        2 => {
+            let value = u16::from_le_bytes([height[0], height[1]]) / 2;
+            height.copy_from_slice(&value.to_le_bytes());
+        }
+        _ => {
+            let value = i32::from_le_bytes([height[0], height[1], height[2], height[3]]) / 2;
+            height.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    let mut reader = Cursor::new(&data[..]);
+    let options = DecodeOptions {
+        max_pixels: max_size.0 * max_size.1,
+    };
+    let mut bmp = Bmp::read_dib_with_options(&mut reader, &options)?;
+    // the fourth byte of 32-bit icons is alpha
+    if bmp.bit_count == 32 && bmp.header.compression == BI_RGB {
+        bmp.masks = Some(ChannelMasks {
+            red: 0x00ff0000,
+            green: 0x0000ff00,
+            blue: 0x000000ff,
+            alpha: 0xff000000,
+        });
+    }
+    let mut rgba = bmp.as_normalized_rgba();
+
+    // use the AND mask unless the image has alpha values, where 1 is transparent
+    if bmp.bit_count != 32 || rgba.chunks_exact(4).all(|pixel| pixel[3] == 0) {
+        let stride = bmp.width.div_ceil(32) * 4;
+        let start = reader.position() as usize;
+        let mask = data
+            .get(start..start + stride * bmp.height)
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "AND mask is truncated"))?;
+        for (y, row) in rgba.chunks_exact_mut(bmp.width.max(1) * 4).enumerate() {
+            let line = if bmp.top_down { y } else { bmp.height - 1 - y };
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let is_transparent = mask[line * stride + x / 8] & (0x80 >> (x % 8)) != 0;
+                pixel[3] = if is_transparent { 0 } else { 255 };
+            }
+        }
+    }
+
+    Ok(IconImage {
+        width: bmp.width,
+        height: bmp.height,
+        rgba,
+        hotspot: (0, 0),
+        format: ImageFormat::Bmp,
+    })
+}
+
+fn encode_image(image: &IconImage) -> Result<Vec<u8>, Error> {
+    let (width, height) = (image.width, image.height);
+    if !(1..=256).contains(&width) || !(1..=256).contains(&height) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Icon images must be 1 to 256 pixels",
+        ));
+    }
+    if image.rgba.len() != width * height * 4 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Image size does not match",
+        ));
+    }
+
+    let mut out = Vec::new();
+    match image.format {
+        ImageFormat::Png => {
+            let png = Png {
+                width,
+                height,
+                color_type: ColorType::TruecolorAlpha,
+                data: image.rgba.clone(),
+                ..Png::default()
+            };
+            png.write(&mut out)?;
+        }
+        ImageFormat::Bmp => {
+            let xor = Bmp::from_rgba(width, height, &image.rgba)?.data;
+            // the AND mask keeps transparent pixels for programs which ignore alpha
+            let stride = width.div_ceil(32) * 4;
+            let mut and = vec![0u8; stride * height];
+            for (y, row) in image.rgba.chunks_exact(width * 4).enumerate() {
+                let line = height - 1 - y;
+                for (x, pixel) in row.chunks_exact(4).enumerate() {
+                    if pixel[3] == 0 {
+                        and[line * stride + x / 8] |= 0x80 >> (x % 8);
+                    }
+                }
+            }
+            let mut writer = ByteWriter::new();
+            writer
+                .write(&ImageHeader {
+                    width: width as i32,
+                    height: height as i32 * 2,
+                    planes: 1,
+                    bit_count: 32,
+                    compression: BI_RGB,
+                    size_image: (xor.len() + and.len()) as u32,
+                    ..ImageHeader::default()
+                })
+                .bytes(&xor)
+                .bytes(&and);
+            out = writer.into_bytes();
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image with a transparent top-left pixel.
+    fn image(width: usize, height: usize, format: ImageFormat) -> IconImage {
+        let mut rgba = (0..width * height * 4)
+            .map(|i| (i * 7 % 256) as u8 | 1)
+            .collect::<Vec<_>>();
+        rgba[3] = 0;
+        IconImage {
+            width,
+            height,
+            rgba: rgba.into_boxed_slice(),
+            hotspot: (0, 0),
+            format,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), Error> {
+        let ico = Ico {
+            resource_type: ResourceType::Icon,
+            images: vec![
+                image(16, 16, ImageFormat::Bmp),
+                image(33, 20, ImageFormat::Bmp),
+                image(256, 256, ImageFormat::Png),
+            ],
+        };
+        let mut out = Vec::new();
+        ico.write(&mut out)?;
+        assert_eq!(Ico::from_bytes(&out)?, ico);
+
+        // 256 pixels are stored as 0
+        let mut reader = ByteReader::new(&out[IconDir::SIZE + IconDirEntry::SIZE * 2..]);
+        let entry: IconDirEntry = reader.read()?;
+        assert_eq!((entry.width, entry.height), (0, 0));
+        assert_eq!(&out[entry.image_offset as usize..][..8], PNG_SIGNATURE);
+
+        let cursor = Ico {
+            resource_type: ResourceType::Cursor,
+            images: vec![IconImage {
+                hotspot: (3, 5),
+                ..image(32, 32, ImageFormat::Bmp)
+            }],
+        };
+        out.clear();
+        cursor.write(&mut out)?;
+        assert_eq!(Ico::from_bytes(&out)?, cursor);
+
+        let too_large = Ico {
+            resource_type: ResourceType::Icon,
+            images: vec![image(257, 1, ImageFormat::Png)],
+        };
+        assert!(too_large.write(&mut out).is_err());
+
+        Ok(())
+    }
+
+    /// Icon file with a single BMP image.
+    fn icon_file(width: u8, height: u8, dib: &[u8]) -> Vec<u8> {
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&IconDir {
+                reserved: 0,
+                resource_type: 1,
+                count: 1,
+            })
+            .write(&IconDirEntry {
+                width,
+                height,
+                planes: 1,
+                bit_count: 32,
+                bytes_in_res: dib.len() as u32,
+                image_offset: (IconDir::SIZE + IconDirEntry::SIZE) as u32,
+                ..IconDirEntry::default()
+            })
+            .bytes(dib);
+        writer.into_bytes()
+    }
+
+    /// 2x2 DIB with the given bit count, rows from the bottom and the AND mask.
+    fn make_dib(bit_count: u16, palette: &[[u8; 4]], rows: [&[u8]; 2], mask: [u8; 2]) -> Vec<u8> {
+        let mut writer = ByteWriter::new();
+        writer.write(&ImageHeader {
+            width: 2,
+            height: 4,
+            planes: 1,
+            bit_count,
+            clr_used: palette.len() as u32,
+            ..ImageHeader::default()
+        });
+        for color in palette {
+            writer.bytes(color);
+        }
+        for row in rows {
+            writer.bytes(row);
+        }
+        for row in mask {
+            writer.bytes(&[row, 0, 0, 0]);
+        }
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn test_and_mask() -> Result<(), Error> {
+        // 24-bit image, the bottom-right pixel is transparent
+        let dib = make_dib(
+            24,
+            &[],
+            [&[1, 2, 3, 4, 5, 6, 0, 0], &[7, 8, 9, 10, 11, 12, 0, 0]],
+            [0x40, 0x00],
+        );
+        let mut bytes = icon_file(2, 2, &dib);
+
+        let ico = Ico::from_bytes(&bytes)?;
+        assert_eq!(ico.images[0].format, ImageFormat::Bmp);
+        assert_eq!(
+            *ico.images[0].rgba,
+            [9, 8, 7, 255, 12, 11, 10, 255, 3, 2, 1, 255, 6, 5, 4, 0]
+        );
+
+        // the image must be within the file
+        bytes.truncate(bytes.len() - 1);
+        assert!(Ico::from_bytes(&bytes).is_err());
+
+        // the AND mask must not be truncated
+        let truncated = &dib[..dib.len() - 4];
+        assert!(Ico::from_bytes(&icon_file(2, 2, truncated)).is_err());
+
+        // 32-bit images without any alpha value fall back to the AND mask
+        let dib32 = make_dib(
+            32,
+            &[],
+            [&[1, 2, 3, 0, 4, 5, 6, 0], &[7, 8, 9, 0, 10, 11, 12, 0]],
+            [0x40, 0x00],
+        );
+        let ico = Ico::from_bytes(&icon_file(2, 2, &dib32))?;
+        assert_eq!(
+            *ico.images[0].rgba,
+            [9, 8, 7, 255, 12, 11, 10, 255, 3, 2, 1, 255, 6, 5, 4, 0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_palette() -> Result<(), Error> {
+        // BGRX
+        let palette = [[255, 0, 0, 0], [0, 0, 255, 0]];
+        // indices 0, 1 at the bottom and 1, 0 at the top
+        for (bit_count, bottom, top) in [
+            (1, 0b0100_0000, 0b1000_0000),
+            (4, 0x01, 0x10),
+            (8, 0x00, 0x01),
+        ] {
+            let rows: [&[u8]; 2] = if bit_count == 8 {
+                [&[0, 1, 0, 0], &[1, 0, 0, 0]]
+            } else {
+                [&[bottom, 0, 0, 0], &[top, 0, 0, 0]]
+            };
+            let ico = Ico::from_bytes(&icon_file(
+                2,
+                2,
+                &make_dib(bit_count, &palette, rows, [0x80, 0]),
+            ))?;
+            assert_eq!(
+                *ico.images[0].rgba,
+                [255, 0, 0, 255, 0, 0, 255, 255, 0, 0, 255, 0, 255, 0, 0, 255],
+                "{bit_count}-bit"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_size_bound() -> Result<(), Error> {
+        let dib = make_dib(
+            24,
+            &[],
+            [&[1, 2, 3, 4, 5, 6, 0, 0], &[7, 8, 9, 10, 11, 12, 0, 0]],
+            [0, 0],
+        );
+        // the size is taken from the image, bounded by the directory entry
+        let ico = Ico::from_bytes(&icon_file(4, 4, &dib))?;
+        assert_eq!((ico.images[0].width, ico.images[0].height), (2, 2));
+        let error = Ico::from_bytes(&icon_file(1, 2, &dib)).unwrap_err();
+        assert_eq!(error.to_string(), "Image is too large");
+
+        // a 16384x16384 RLE8 image is rejected before the pixels are allocated
+        let mut writer = ByteWriter::new();
+        writer
+            .write(&ImageHeader {
+                width: 16384,
+                height: 32768,
+                planes: 1,
+                bit_count: 8,
+                compression: bmp::bmp::BI_RLE8,
+                size_image: 2,
+                clr_used: 1,
+                ..ImageHeader::default()
+            })
+            .bytes(&[0, 0, 0, 0])
+            .bytes(&[0, 1]);
+        let error = Ico::from_bytes(&icon_file(0, 0, &writer.into_bytes())).unwrap_err();
+        assert_eq!(error.to_string(), "Image is too large");
+
+        let png = Png {
+            width: 2,
+            height: 2,
+            color_type: ColorType::TruecolorAlpha,
+            data: Box::new([0; 16]),
+            ..Png::default()
+        };
+        let mut data = Vec::new();
+        png.write(&mut data)?;
+        let ico = Ico::from_bytes(&icon_file(3, 2, &data))?;
+        assert_eq!((ico.images[0].width, ico.images[0].height), (2, 2));
+        assert!(Ico::from_bytes(&icon_file(2, 1, &data)).is_err());
+
+        Ok(())
+    }
+}
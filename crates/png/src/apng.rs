@@ -0,0 +1,665 @@
+use crate::png::{decode_image_data, read_chunks, Chunk, ColorType, EncodeOptions, Png, SIGNATURE};
+use crate::util::{invalid_data, read_exact};
+use binary::bytes::ByteReader;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// Animated PNG
+///
+/// https://www.w3.org/TR/png/#apng-frame-based-animation
+pub struct Apng {
+    pub width: usize,
+    pub height: usize,
+    /// Number of times to loop the animation, 0 for infinite
+    pub num_plays: u32,
+    pub frames: Vec<Frame>,
+}
+
+pub struct Frame {
+    pub control: FrameControl,
+    /// Fully composited canvas in 8-bit RGBA
+    pub canvas: Box<[u8]>,
+}
+
+/// https://www.w3.org/TR/png/#fcTL-chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameControl {
+    pub sequence_number: u32,
+    pub width: u32,
+    pub height: u32,
+    pub x_offset: u32,
+    pub y_offset: u32,
+    pub delay_num: u16,
+    pub delay_den: u16,
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp,
+}
+
+/// How the frame region is treated before rendering the next frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisposeOp {
+    /// Leave the canvas as is
+    None = 0,
+    /// Clear the region to fully transparent black
+    Background = 1,
+    /// Revert the region to the canvas before rendering this frame
+    Previous = 2,
+}
+
+/// How the frame is rendered onto the canvas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendOp {
+    /// Overwrite the region
+    Source = 0,
+    /// Alpha-composite over the region
+    Over = 1,
+}
+
+impl FrameControl {
+    pub fn read(chunk: &Chunk) -> Result<FrameControl, Error> {
+        read_exact(&chunk.data, |reader| {
+            Ok(FrameControl {
+                sequence_number: reader.u32_be()?,
+                width: reader.u32_be()?,
+                height: reader.u32_be()?,
+                x_offset: reader.u32_be()?,
+                y_offset: reader.u32_be()?,
+                delay_num: reader.u16_be()?,
+                delay_den: reader.u16_be()?,
+                dispose_op: match reader.u8()? {
+                    0 => DisposeOp::None,
+                    1 => DisposeOp::Background,
+                    2 => DisposeOp::Previous,
+                    _ => return Err(invalid_data("Unknown dispose op")),
+                },
+                blend_op: match reader.u8()? {
+                    0 => BlendOp::Source,
+                    1 => BlendOp::Over,
+                    _ => return Err(invalid_data("Unknown blend op")),
+                },
+            })
+        })
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let mut data = Vec::with_capacity(26);
+        for value in [
+            self.sequence_number,
+            self.width,
+            self.height,
+            self.x_offset,
+            self.y_offset,
+        ] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&self.delay_num.to_be_bytes());
+        data.extend_from_slice(&self.delay_den.to_be_bytes());
+        data.push(self.dispose_op as u8);
+        data.push(self.blend_op as u8);
+        Chunk::new([b'f', b'c', b'T', b'L'], data)
+    }
+
+    /// Frame delay in seconds. A denominator of 0 is treated as 100.
+    pub fn delay(&self) -> f64 {
+        let den = if self.delay_den == 0 {
+            100
+        } else {
+            self.delay_den
+        };
+        self.delay_num as f64 / den as f64
+    }
+}
+
+impl Apng {
+    pub fn open(path: impl AsRef<Path>) -> Result<Apng, Error> {
+        Apng::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read<R: Read>(reader: R) -> Result<Apng, Error> {
+        Apng::from_chunks(&read_chunks(reader)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Apng, Error> {
+        Apng::read(bytes)
+    }
+
+    pub fn from_chunks(chunks: &[Chunk]) -> Result<Apng, Error> {
+        let png = Png::from_chunks(chunks)?;
+
+        let mut num_frames = None;
+        let mut num_plays = 0;
+        let mut next_sequence_number = 0;
+        let mut frame_data: Vec<(FrameControl, Vec<u8>)> = Vec::new();
+
+        for chunk in chunks {
+            match chunk.chunk_type {
+                [b'a', b'c', b'T', b'L'] => {
+                    let (frames, plays) = read_exact(&chunk.data, |reader| {
+                        Ok((reader.u32_be()?, reader.u32_be()?))
+                    })?;
+                    num_frames = Some(frames);
+                    num_plays = plays;
+                }
+                [b'f', b'c', b'T', b'L'] => {
+                    let control = FrameControl::read(chunk)?;
+                    if control.sequence_number != next_sequence_number {
+                        return Err(invalid_data("Invalid sequence number"));
+                    }
+                    next_sequence_number += 1;
+                    frame_data.push((control, Vec::new()));
+                }
+                [b'I', b'D', b'A', b'T'] => {
+                    // IDAT is a part of the animation only if fcTL precedes it
+                    if let Some((_, data)) = frame_data.last_mut() {
+                        data.extend_from_slice(&chunk.data);
+                    }
+                }
+                [b'f', b'd', b'A', b'T'] => {
+                    let mut reader = ByteReader::new(&chunk.data);
+                    if reader.u32_be()? != next_sequence_number {
+                        return Err(invalid_data("Invalid sequence number"));
+                    }
+                    next_sequence_number += 1;
+                    let (_, data) = frame_data
+                        .last_mut()
+                        .ok_or_else(|| invalid_data("fdAT chunk without fcTL chunk"))?;
+                    data.extend_from_slice(reader.rest());
+                }
+                _ => (),
+            }
+        }
+
+        let num_frames = num_frames.ok_or_else(|| invalid_data("Not an animated PNG"))?;
+        if num_frames as usize != frame_data.len() {
+            return Err(invalid_data("Number of frames does not match"));
+        }
+
+        let mut canvas = vec![0u8; png.width * png.height * 4];
+        let mut frames = Vec::with_capacity(frame_data.len());
+        for (i, (control, data)) in frame_data.into_iter().enumerate() {
+            let (x_offset, y_offset) = (control.x_offset as usize, control.y_offset as usize);
+            let (width, height) = (control.width as usize, control.height as usize);
+            if width == 0
+                || height == 0
+                || x_offset + width > png.width
+                || y_offset + height > png.height
+            {
+                return Err(invalid_data("Frame region is out of the canvas"));
+            }
+
+            let image = decode_frame(&png, width, height, &data)?;
+
+            let previous = canvas.clone();
+            for y in 0..height {
+                for x in 0..width {
+                    let source = &image[(y * width + x) * 4..][..4];
+                    let target =
+                        &mut canvas[((y_offset + y) * png.width + x_offset + x) * 4..][..4];
+                    match control.blend_op {
+                        BlendOp::Source => target.copy_from_slice(source),
+                        BlendOp::Over => blend_over(source, target),
+                    }
+                }
+            }
+
+            frames.push(Frame {
+                control,
+                canvas: canvas.clone().into_boxed_slice(),
+            });
+
+            let dispose_op = match control.dispose_op {
+                // the first frame is disposed to the background instead of the previous canvas
+                DisposeOp::Previous if i == 0 => DisposeOp::Background,
+                dispose_op => dispose_op,
+            };
+            for y in y_offset..y_offset + height {
+                let region = (y * png.width + x_offset) * 4..(y * png.width + x_offset + width) * 4;
+                match dispose_op {
+                    DisposeOp::None => {}
+                    DisposeOp::Background => canvas[region].fill(0),
+                    DisposeOp::Previous => {
+                        canvas[region.clone()].copy_from_slice(&previous[region])
+                    }
+                }
+            }
+        }
+
+        Ok(Apng {
+            width: png.width,
+            height: png.height,
+            num_plays,
+            frames,
+        })
+    }
+}
+
+/// Encoder for animated PNG from 8-bit RGBA frames.
+///
+/// Each frame is cropped to the region changed from the canvas, and the dispose and blend ops
+/// are chosen to minimize the compressed size. The first frame is stored in IDAT chunks so that
+/// decoders without APNG support show it as a static image.
+pub struct ApngEncoder {
+    pub width: usize,
+    pub height: usize,
+    /// Number of times to loop the animation, 0 for infinite
+    pub num_plays: u32,
+    frames: Vec<(Box<[u8]>, u16, u16)>,
+}
+
+/// A frame region and its compressed image data.
+struct EncodedFrame {
+    x_offset: usize,
+    y_offset: usize,
+    width: usize,
+    height: usize,
+    blend_op: BlendOp,
+    data: Vec<u8>,
+}
+
+impl ApngEncoder {
+    pub fn new(width: usize, height: usize) -> ApngEncoder {
+        ApngEncoder {
+            width,
+            height,
+            num_plays: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Add a frame shown for `delay_num / delay_den` seconds.
+    pub fn add_frame(
+        &mut self,
+        rgba: Box<[u8]>,
+        delay_num: u16,
+        delay_den: u16,
+    ) -> Result<(), Error> {
+        if rgba.len() != self.width * self.height * 4 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Frame size does not match",
+            ));
+        }
+        self.frames.push((rgba, delay_num, delay_den));
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let mut out = Vec::new();
+        self.write(&mut out)?;
+        File::create(path)?.write_all(&out)
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) -> Result<(), Error> {
+        if self.frames.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "No frames"));
+        }
+        if self.width == 0 || self.height == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Image is empty"));
+        }
+
+        // the first frame covers the whole canvas
+        let mut encoded = vec![(
+            DisposeOp::None,
+            self.encode_region(
+                &self.frames[0].0,
+                0,
+                0,
+                self.width,
+                self.height,
+                BlendOp::Source,
+            ),
+        )];
+
+        // canvas before the previous frame is rendered
+        let mut before_previous = vec![0u8; self.width * self.height * 4];
+        for i in 1..self.frames.len() {
+            let previous = &self.frames[i - 1].0;
+            let previous_region = &encoded[i - 1].1;
+
+            let mut cleared = previous.to_vec();
+            for y in previous_region.y_offset..previous_region.y_offset + previous_region.height {
+                let start = (y * self.width + previous_region.x_offset) * 4;
+                cleared[start..start + previous_region.width * 4].fill(0);
+            }
+
+            let mut candidates = vec![
+                (DisposeOp::None, previous.to_vec()),
+                (DisposeOp::Background, cleared),
+            ];
+            // dispose op "previous" of the first frame is same as "background"
+            if i > 1 {
+                candidates.push((DisposeOp::Previous, before_previous));
+            }
+
+            let mut best: Option<(DisposeOp, Vec<u8>, EncodedFrame)> = None;
+            for (dispose_op, base) in candidates {
+                for blend_op in [BlendOp::Source, BlendOp::Over] {
+                    let Some(frame) = self.encode_difference(&base, &self.frames[i].0, blend_op)
+                    else {
+                        continue;
+                    };
+                    if best
+                        .as_ref()
+                        .is_none_or(|(_, _, b)| frame.data.len() < b.data.len())
+                    {
+                        best = Some((dispose_op, base.clone(), frame));
+                    }
+                }
+            }
+
+            let (dispose_op, base, frame) = best.unwrap();
+            encoded[i - 1].0 = dispose_op;
+            encoded.push((DisposeOp::None, frame));
+            before_previous = base;
+        }
+
+        let png = Png {
+            width: self.width,
+            height: self.height,
+            color_type: ColorType::TruecolorAlpha,
+            ..Png::default()
+        };
+        out.extend_from_slice(&SIGNATURE);
+        png.image_header_chunk().write(out);
+
+        let mut animation_control = (self.frames.len() as u32).to_be_bytes().to_vec();
+        animation_control.extend_from_slice(&self.num_plays.to_be_bytes());
+        Chunk::new([b'a', b'c', b'T', b'L'], animation_control).write(out);
+
+        let mut sequence_number = 0;
+        for (i, (dispose_op, frame)) in encoded.into_iter().enumerate() {
+            let (_, delay_num, delay_den) = self.frames[i];
+            FrameControl {
+                sequence_number,
+                width: frame.width as u32,
+                height: frame.height as u32,
+                x_offset: frame.x_offset as u32,
+                y_offset: frame.y_offset as u32,
+                delay_num,
+                delay_den,
+                dispose_op,
+                blend_op: frame.blend_op,
+            }
+            .to_chunk()
+            .write(out);
+            sequence_number += 1;
+
+            if i == 0 {
+                Chunk::new([b'I', b'D', b'A', b'T'], frame.data).write(out);
+            } else {
+                let mut data = sequence_number.to_be_bytes().to_vec();
+                data.extend(frame.data);
+                Chunk::new([b'f', b'd', b'A', b'T'], data).write(out);
+                sequence_number += 1;
+            }
+        }
+
+        Chunk::new([b'I', b'E', b'N', b'D'], Vec::new()).write(out);
+
+        Ok(())
+    }
+
+    /// Encode the region of `target` which differs from `base`.
+    ///
+    /// Returns `None` if the blend op cannot reproduce `target` exactly.
+    fn encode_difference(
+        &self,
+        base: &[u8],
+        target: &[u8],
+        blend_op: BlendOp,
+    ) -> Option<EncodedFrame> {
+        let pixel = |canvas: &[u8], x: usize, y: usize| -> [u8; 4] {
+            let i = (y * self.width + x) * 4;
+            [canvas[i], canvas[i + 1], canvas[i + 2], canvas[i + 3]]
+        };
+
+        // bounding box of the changed pixels
+        let (mut left, mut top, mut right, mut bottom) = (self.width, self.height, 0, 0);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if pixel(base, x, y) != pixel(target, x, y) {
+                    left = left.min(x);
+                    top = top.min(y);
+                    right = right.max(x + 1);
+                    bottom = bottom.max(y + 1);
+                }
+            }
+        }
+        if left >= right {
+            // nothing changed, but a frame must have at least one pixel
+            (left, top, right, bottom) = (0, 0, 1, 1);
+        }
+
+        let mut region = Vec::with_capacity((right - left) * (bottom - top) * 4);
+        for y in top..bottom {
+            for x in left..right {
+                let (before, after) = (pixel(base, x, y), pixel(target, x, y));
+                match blend_op {
+                    BlendOp::Source => region.extend_from_slice(&after),
+                    BlendOp::Over => {
+                        // leave unchanged pixels transparent
+                        let source = if before == after { [0; 4] } else { after };
+                        let mut blended = before;
+                        blend_over(&source, &mut blended);
+                        if blended != after {
+                            return None;
+                        }
+                        region.extend_from_slice(&source);
+                    }
+                }
+            }
+        }
+
+        Some(self.encode_region(&region, left, top, right - left, bottom - top, blend_op))
+    }
+
+    fn encode_region(
+        &self,
+        rgba: &[u8],
+        x_offset: usize,
+        y_offset: usize,
+        width: usize,
+        height: usize,
+        blend_op: BlendOp,
+    ) -> EncodedFrame {
+        let png = Png {
+            width,
+            height,
+            color_type: ColorType::TruecolorAlpha,
+            data: rgba.into(),
+            ..Png::default()
+        };
+        EncodedFrame {
+            x_offset,
+            y_offset,
+            width,
+            height,
+            blend_op,
+            data: png.encode_image_data(&EncodeOptions::default()),
+        }
+    }
+}
+
+/// Decode the image data of a frame into 8-bit RGBA using the format of the default image.
+fn decode_frame(png: &Png, width: usize, height: usize, data: &[u8]) -> Result<Box<[u8]>, Error> {
+    let mut frame = Png {
+        width,
+        height,
+        bit_depth: png.bit_depth,
+        color_type: png.color_type,
+        palette: png.palette.clone(),
+        transparency: png.transparency.clone(),
+        interlaced: png.interlaced,
+        ..Png::default()
+    };
+    frame.data = decode_image_data(data, &frame)?;
+    Ok(frame.to_rgba8())
+}
+
+/// https://www.w3.org/TR/png/#13Alpha-channel-processing
+fn blend_over(source: &[u8], target: &mut [u8]) {
+    let source_alpha = source[3] as u32;
+    let target_alpha = target[3] as u32 * (255 - source_alpha) / 255;
+    let alpha = source_alpha + target_alpha;
+    if alpha == 0 {
+        target.fill(0);
+        return;
+    }
+    for i in 0..3 {
+        target[i] =
+            ((source[i] as u32 * source_alpha + target[i] as u32 * target_alpha) / alpha) as u8;
+    }
+    target[3] = alpha as u8;
+}
+
+#[cfg(test)]
+mod test {
+    use crate::apng::*;
+
+    fn image_data(width: usize, height: usize, rgba: [u8; 4]) -> Vec<u8> {
+        let mut line = vec![0]; // filter type: None
+        line.extend(rgba.repeat(width));
+        zlib::zlib::deflate(&line.repeat(height))
+    }
+
+    fn frame_control(
+        sequence_number: u32,
+        (x_offset, y_offset, width, height): (u32, u32, u32, u32),
+        dispose_op: DisposeOp,
+        blend_op: BlendOp,
+    ) -> Chunk {
+        FrameControl {
+            sequence_number,
+            width,
+            height,
+            x_offset,
+            y_offset,
+            delay_num: 1,
+            delay_den: 10,
+            dispose_op,
+            blend_op,
+        }
+        .to_chunk()
+    }
+
+    fn frame_data(sequence_number: u32, data: Vec<u8>) -> Chunk {
+        let mut chunk_data = sequence_number.to_be_bytes().to_vec();
+        chunk_data.extend(data);
+        Chunk::new([b'f', b'd', b'A', b'T'], chunk_data)
+    }
+
+    #[test]
+    fn test_composite_frames() {
+        let red = [255, 0, 0, 255];
+        let half_blue = [0, 0, 255, 128];
+        let green = [0, 255, 0, 255];
+
+        let mut image_header = Vec::new();
+        image_header.extend_from_slice(&2u32.to_be_bytes());
+        image_header.extend_from_slice(&2u32.to_be_bytes());
+        image_header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        let mut animation_control = 3u32.to_be_bytes().to_vec();
+        animation_control.extend_from_slice(&2u32.to_be_bytes());
+
+        let chunks = vec![
+            Chunk::new([b'I', b'H', b'D', b'R'], image_header),
+            Chunk::new([b'a', b'c', b'T', b'L'], animation_control),
+            frame_control(0, (0, 0, 2, 2), DisposeOp::None, BlendOp::Source),
+            Chunk::new([b'I', b'D', b'A', b'T'], image_data(2, 2, red)),
+            frame_control(1, (1, 0, 1, 2), DisposeOp::Previous, BlendOp::Over),
+            frame_data(2, image_data(1, 2, half_blue)),
+            frame_control(3, (0, 1, 1, 1), DisposeOp::Background, BlendOp::Source),
+            frame_data(4, image_data(1, 1, green)),
+            Chunk::new([b'I', b'E', b'N', b'D'], Vec::new()),
+        ];
+
+        let apng = Apng::from_chunks(&chunks).unwrap();
+        assert_eq!(apng.num_plays, 2);
+        assert_eq!(apng.frames.len(), 3);
+        assert_eq!(apng.frames[0].control.delay(), 0.1);
+
+        assert_eq!(&apng.frames[0].canvas[..], &red.repeat(4)[..]);
+
+        let blended = [127, 0, 128, 255];
+        assert_eq!(
+            &apng.frames[1].canvas[..],
+            &[red, blended, red, blended].concat()[..]
+        );
+
+        // the second frame is reverted before the third frame is rendered
+        assert_eq!(
+            &apng.frames[2].canvas[..],
+            &[red, red, green, red].concat()[..]
+        );
+    }
+
+    #[test]
+    fn test_invalid_sequence_number() {
+        let mut image_header = Vec::new();
+        image_header.extend_from_slice(&1u32.to_be_bytes());
+        image_header.extend_from_slice(&1u32.to_be_bytes());
+        image_header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        let mut animation_control = 1u32.to_be_bytes().to_vec();
+        animation_control.extend_from_slice(&0u32.to_be_bytes());
+
+        let chunks = vec![
+            Chunk::new([b'I', b'H', b'D', b'R'], image_header),
+            Chunk::new([b'a', b'c', b'T', b'L'], animation_control),
+            Chunk::new([b'I', b'D', b'A', b'T'], image_data(1, 1, [0, 0, 0, 0])),
+            frame_control(1, (0, 0, 1, 1), DisposeOp::None, BlendOp::Source),
+            Chunk::new([b'I', b'E', b'N', b'D'], Vec::new()),
+        ];
+
+        assert!(Apng::from_chunks(&chunks).is_err());
+    }
+
+    #[test]
+    fn test_encoder_round_trip() -> Result<(), Error> {
+        let (width, height) = (8, 6);
+        let mut frames = Vec::new();
+        for i in 0..4 {
+            let mut rgba = [32, 64, 96, 255].repeat(width * height);
+            // a small moving square
+            for y in 2..4 {
+                for x in i..i + 2 {
+                    rgba[(y * width + x) * 4..][..4].copy_from_slice(&[255, 255, 0, 255]);
+                }
+            }
+            frames.push(rgba.into_boxed_slice());
+        }
+        // a semi-transparent frame
+        frames.push([0, 0, 0, 64].repeat(width * height).into_boxed_slice());
+
+        let mut encoder = ApngEncoder::new(width, height);
+        encoder.num_plays = 3;
+        for frame in &frames {
+            encoder.add_frame(frame.clone(), 1, 30)?;
+        }
+        let path = std::env::temp_dir().join("apng_test_encoder_round_trip.png");
+        encoder.save(&path)?;
+
+        let apng = Apng::open(&path)?;
+        assert_eq!(apng.num_plays, 3);
+        assert_eq!(apng.frames.len(), frames.len());
+        for (decoded, frame) in apng.frames.iter().zip(frames.iter()) {
+            assert_eq!(decoded.canvas, *frame);
+            assert_eq!(
+                (decoded.control.delay_num, decoded.control.delay_den),
+                (1, 30)
+            );
+        }
+
+        // frames are cropped to the changed region
+        let control = apng.frames[2].control;
+        assert!(control.width * control.height < (width * height) as u32);
+
+        // the first frame is also the static image
+        let png = Png::open(&path)?;
+        assert_eq!(png.to_rgba8(), frames[0]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use clap::Parser;
+use png::color::{ColorInfo, IccProfile};
+use png::edit::ChunkEditor;
+use png::metadata::{Metadata, PhysicalDimensions};
+use png::text::Text;
+use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
+use std::path::Path;
+
+#[derive(Parser, Debug)]
+#[command(about)]
+/// Add, replace or remove ancillary chunks without re-encoding the image
+struct Args {
+    /// Input file paths, edited in place unless --output is given
+    #[arg(required = true)]
+    inputs: Vec<String>,
+    /// Output file path (only with a single input)
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Remove all chunks of the type (e.g. tEXt, iCCP)
+    #[arg(long, value_name = "TYPE")]
+    remove: Vec<String>,
+    /// Remove text chunks with the keyword
+    #[arg(long, value_name = "KEYWORD")]
+    remove_text: Vec<String>,
+    /// Set a text chunk, replacing text chunks with the same keyword
+    #[arg(long, value_name = "KEYWORD=TEXT")]
+    text: Vec<String>,
+    /// Set the resolution in dots per inch (pHYs)
+    #[arg(long)]
+    dpi: Option<f64>,
+    /// Embed an ICC profile (iCCP), removing sRGB
+    #[arg(long, value_name = "PATH")]
+    icc: Option<String>,
+}
+
+fn main() -> Result<(), Error> {
+    let args = Args::parse();
+    if args.output.is_some() && args.inputs.len() != 1 {
+        return Err(Error::new(InvalidInput, "--output requires a single input"));
+    }
+
+    for input in &args.inputs {
+        let mut editor = ChunkEditor::open(input)?;
+        edit(&mut editor, &args)?;
+        editor.save(args.output.as_ref().unwrap_or(input))?;
+    }
+
+    Ok(())
+}
+
+fn edit(editor: &mut ChunkEditor, args: &Args) -> Result<(), Error> {
+    for chunk_type in &args.remove {
+        let chunk_type: [u8; 4] = chunk_type
+            .as_bytes()
+            .try_into()
+            .map_err(|_| Error::new(InvalidInput, "Chunk type must be 4 letters"))?;
+        editor.remove(chunk_type)?;
+    }
+
+    let mut keywords = args.remove_text.clone();
+    let mut texts = Vec::new();
+    for text in &args.text {
+        let (keyword, text) = text
+            .split_once('=')
+            .ok_or_else(|| Error::new(InvalidInput, "Text must be KEYWORD=TEXT"))?;
+        keywords.push(keyword.to_string());
+        texts.push(if text.chars().all(|c| (c as u32) < 256) {
+            Text::Latin1 {
+                keyword: keyword.to_string(),
+                text: text.to_string(),
+            }
+        } else {
+            Text::International {
+                keyword: keyword.to_string(),
+                compressed: false,
+                language_tag: String::new(),
+                translated_keyword: String::new(),
+                text: text.to_string(),
+            }
+        });
+    }
+    editor.retain(|chunk| match Text::read(chunk) {
+        Ok(text) => !keywords.iter().any(|keyword| keyword == text.keyword()),
+        Err(_) => true,
+    });
+    for text in texts {
+        editor.insert(text.to_chunk()?)?;
+    }
+
+    if let Some(dpi) = args.dpi {
+        let metadata = Metadata {
+            physical_dimensions: Some(PhysicalDimensions::from_dpi(dpi, dpi)),
+            ..Metadata::default()
+        };
+        for chunk in metadata.to_chunks_after_palette()? {
+            editor.replace(chunk)?;
+        }
+    }
+
+    if let Some(path) = &args.icc {
+        let color = ColorInfo {
+            icc_profile: Some(IccProfile {
+                name: profile_name(Path::new(path)),
+                profile: std::fs::read(path)?,
+            }),
+            ..ColorInfo::default()
+        };
+        editor.remove([b's', b'R', b'G', b'B'])?;
+        for chunk in color.to_chunks()? {
+            editor.replace(chunk)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The iCCP profile name: the Latin-1 characters of the file stem, at most 79.
+fn profile_name(path: &Path) -> String {
+    let name: String = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .filter(|&c| c != '\0' && (c as u32) < 0x100)
+        .take(79)
+        .collect();
+    let name = name.trim();
+    if name.is_empty() {
+        "ICC profile".to_string()
+    } else {
+        name.to_string()
+    }
+}
@@ -0,0 +1,205 @@
+use clap::Parser;
+use png::metadata::PhysicalUnit;
+use png::png::{read_chunks, Chunk, IHDRChunk, Png};
+use png::text::Text;
+use std::fs::File;
+use std::io::{BufReader, Error};
+
+#[derive(Parser, Debug)]
+#[command(about)]
+/// Print PNG chunk structure
+struct Args {
+    /// Input file path
+    #[command()]
+    path_str: String,
+}
+
+fn main() -> Result<(), Error> {
+    let args = Args::parse();
+    let chunks = read_chunks(BufReader::new(File::open(args.path_str)?))?;
+
+    let mut offset = 8;
+    for chunk in &chunks {
+        print_chunk(chunk, offset);
+        offset += chunk.length as usize + 12;
+    }
+
+    let png = Png::from_chunks(&chunks)?;
+    print_image_data(&png, &chunks)?;
+
+    Ok(())
+}
+
+fn print_chunk(chunk: &Chunk, offset: usize) {
+    let flags = [
+        if chunk.is_critical() {
+            "critical"
+        } else {
+            "ancillary"
+        },
+        if chunk.is_public() {
+            "public"
+        } else {
+            "private"
+        },
+        if chunk.is_safe_to_copy() {
+            "safe-to-copy"
+        } else {
+            "unsafe-to-copy"
+        },
+    ]
+    .join(", ");
+
+    let crc = if chunk.is_crc_valid() {
+        color("CRC OK", 2)
+    } else {
+        color(&format!("CRC NG ({:08x})", chunk.compute_crc()), 1)
+    };
+
+    println!(
+        "{} {} {} {} {}",
+        color(&format!("{:#010x}", offset), 239),
+        String::from_utf8_lossy(&chunk.chunk_type),
+        color(&format!("{:>10}", chunk.length), 6),
+        color(&format!("[{}]", flags), 239),
+        crc
+    );
+
+    for field in describe(chunk) {
+        println!("    {}", field);
+    }
+}
+
+fn describe(chunk: &Chunk) -> Vec<String> {
+    let data = &chunk.data;
+    match chunk.chunk_type {
+        [b'I', b'H', b'D', b'R'] => match IHDRChunk::from_chunk(chunk) {
+            Ok(header) => vec![
+                format!("width: {}", header.width),
+                format!("height: {}", header.height),
+                format!("bit depth: {}", header.bit_depth),
+                format!("color type: {}", header.color_type),
+                format!("compression method: {}", header.compression_method),
+                format!("filter method: {}", header.filter_method),
+                format!("interlace method: {}", header.interlace_method),
+            ],
+            Err(error) => vec![color(&error.to_string(), 1)],
+        },
+        [b'P', b'L', b'T', b'E'] => vec![format!("entries: {}", data.len() / 3)],
+        [b't', b'E', b'X', b't'] | [b'z', b'T', b'X', b't'] | [b'i', b'T', b'X', b't'] => {
+            match Text::read(chunk) {
+                Ok(text) => vec![format!("{}: {}", text.keyword(), text.text())],
+                Err(error) => vec![color(&error.to_string(), 1)],
+            }
+        }
+        [b'p', b'H', b'Y', b's'] if data.len() == 9 => {
+            let mut metadata = png::metadata::Metadata::default();
+            // color type does not matter for pHYs
+            if metadata
+                .read(chunk, png::png::ColorType::Truecolor)
+                .is_err()
+            {
+                return vec![color("Invalid pHYs chunk", 1)];
+            }
+            let physical_dimensions = metadata.physical_dimensions.unwrap();
+            let mut fields = vec![format!(
+                "pixels per unit: {} x {} ({})",
+                physical_dimensions.pixels_per_unit_x,
+                physical_dimensions.pixels_per_unit_y,
+                match physical_dimensions.unit {
+                    PhysicalUnit::Meter => "meter",
+                    PhysicalUnit::Unknown => "unknown",
+                }
+            )];
+            if let Some((dpi_x, dpi_y)) = physical_dimensions.dpi() {
+                fields.push(format!("dpi: {:.2} x {:.2}", dpi_x, dpi_y));
+            }
+            fields
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn print_image_data(png: &Png, chunks: &[Chunk]) -> Result<(), Error> {
+    let mut compressed = Vec::new();
+    for chunk in chunks {
+        if chunk.chunk_type == [b'I', b'D', b'A', b'T'] {
+            compressed.extend_from_slice(&chunk.data);
+        }
+    }
+    let inflated = zlib::zlib::inflate(&compressed)?;
+
+    println!();
+    println!("raw size: {}", inflated.len());
+    println!(
+        "compressed size: {} ({:.1}%)",
+        compressed.len(),
+        compressed.len() as f64 * 100.0 / inflated.len().max(1) as f64
+    );
+
+    println!("filter types:");
+    for (name, count) in ["None", "Sub", "Up", "Average", "Paeth"]
+        .iter()
+        .zip(filter_histogram(png, &inflated))
+    {
+        println!("    {:<8}{}", name, color(&count.to_string(), 6));
+    }
+
+    Ok(())
+}
+
+/// Count the filter types of the scanlines, following the Adam7 passes of interlaced images.
+fn filter_histogram(png: &Png, inflated: &[u8]) -> [usize; 5] {
+    let mut histogram = [0usize; 5];
+    let mut offset = 0;
+    for length in png.scanline_lengths() {
+        let Some(&filter_type) = inflated.get(offset) else {
+            break;
+        };
+        if let Some(count) = histogram.get_mut(filter_type as usize) {
+            *count += 1;
+        }
+        offset += 1 + length;
+    }
+    histogram
+}
+
+fn color(text: &str, color: u8) -> String {
+    format!("\x1b[38;5;{}m{}\x1b[m", color, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(path: &str) -> Result<[usize; 5], Error> {
+        let chunks = read_chunks(BufReader::new(File::open(path)?))?;
+        let png = Png::from_chunks(&chunks)?;
+        let compressed = chunks
+            .iter()
+            .filter(|chunk| chunk.chunk_type == [b'I', b'D', b'A', b'T'])
+            .flat_map(|chunk| chunk.data.iter().copied())
+            .collect::<Vec<_>>();
+        Ok(filter_histogram(&png, &zlib::zlib::inflate(&compressed)?))
+    }
+
+    #[test]
+    fn test_filter_histogram() -> Result<(), Error> {
+        // the suite uses filter type y % 5 for each row of a pass
+        assert_eq!(histogram("tests/suite/rgb8_n.png")?, [4, 3, 3, 3, 3]);
+        assert_eq!(histogram("tests/suite/rgb8_i.png")?, [9, 9, 6, 4, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_image_header() {
+        let mut data = vec![0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 1];
+        let fields = describe(&Chunk::new([b'I', b'H', b'D', b'R'], data.clone()));
+        assert_eq!(fields[0], "width: 3");
+        assert_eq!(fields[6], "interlace method: 1");
+
+        data.pop();
+        let fields = describe(&Chunk::new([b'I', b'H', b'D', b'R'], data));
+        assert_eq!(fields, [color("Invalid IHDR chunk", 1)]);
+    }
+}
@@ -0,0 +1,72 @@
+use clap::{Parser, ValueEnum};
+use png::optimize::{optimize, MetadataPolicy, OptimizeOptions};
+use png::png::{read_chunks, Png};
+use std::fs::File;
+use std::io::ErrorKind::Unsupported;
+use std::io::{BufReader, Error, Write};
+
+#[derive(Parser, Debug)]
+#[command(about)]
+/// Losslessly reduce the size of a PNG file
+struct Args {
+    /// Input file path
+    #[command()]
+    input: String,
+    /// Output file path
+    #[command()]
+    output: String,
+    /// Ancillary chunks to keep
+    #[arg(short, long, value_enum, default_value_t = Policy::Keep)]
+    metadata: Policy,
+    /// Try only the default compression level
+    #[arg(short, long)]
+    fast: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Policy {
+    Keep,
+    Color,
+    Strip,
+}
+
+fn main() -> Result<(), Error> {
+    let args = Args::parse();
+    let input = File::open(&args.input)?;
+    let input_size = input.metadata()?.len();
+    let chunks = read_chunks(BufReader::new(input))?;
+    if chunks
+        .iter()
+        .any(|chunk| chunk.chunk_type == [b'a', b'c', b'T', b'L'])
+    {
+        return Err(Error::new(Unsupported, "APNG is not supported"));
+    }
+    let png = Png::from_chunks(&chunks)?;
+
+    let mut options = OptimizeOptions {
+        metadata: match args.metadata {
+            Policy::Keep => MetadataPolicy::Keep,
+            Policy::Color => MetadataPolicy::KeepColor,
+            Policy::Strip => MetadataPolicy::Strip,
+        },
+        ..OptimizeOptions::default()
+    };
+    if args.fast {
+        options.levels = vec![6];
+    }
+    let mut optimized = optimize(&png, &options)?;
+    if optimized.len() as u64 >= input_size && options.metadata == MetadataPolicy::Keep {
+        // The original encoding is already smaller.
+        optimized = std::fs::read(&args.input)?;
+    }
+
+    File::create(&args.output)?.write_all(&optimized)?;
+    println!(
+        "{} -> {} bytes ({:.1}%)",
+        input_size,
+        optimized.len(),
+        optimized.len() as f64 * 100.0 / input_size.max(1) as f64
+    );
+
+    Ok(())
+}
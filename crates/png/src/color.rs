@@ -0,0 +1,427 @@
+use crate::png::Chunk;
+use crate::util::{decode_latin1, encode_keyword, invalid_data, read_exact, split_null};
+use binary::bytes::ByteReader;
+use std::io::Error;
+
+/// Color space information from gAMA, cHRM, sRGB, iCCP, cICP, mDCv and cLLi chunks.
+///
+/// https://www.w3.org/TR/png/#11addnlcolinfo
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColorInfo {
+    /// gAMA: image gamma times 100000
+    pub gamma: Option<u32>,
+    /// cHRM
+    pub chromaticities: Option<Chromaticities>,
+    /// sRGB
+    pub srgb: Option<RenderingIntent>,
+    /// iCCP
+    pub icc_profile: Option<IccProfile>,
+    /// cICP
+    pub cicp: Option<CodingIndependentCodePoints>,
+    /// mDCv
+    pub mastering_display: Option<MasteringDisplayColorVolume>,
+    /// cLLi
+    pub content_light_level: Option<ContentLightLevel>,
+}
+
+/// https://www.w3.org/TR/png/#11cHRM
+///
+/// Each value is the CIE 1931 x or y coordinate times 100000.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chromaticities {
+    pub white_point: (u32, u32),
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32),
+}
+
+/// https://www.w3.org/TR/png/#11sRGB
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderingIntent {
+    Perceptual = 0,
+    RelativeColorimetric = 1,
+    Saturation = 2,
+    AbsoluteColorimetric = 3,
+}
+
+/// https://www.w3.org/TR/png/#11iCCP
+#[derive(Debug, Clone, PartialEq)]
+pub struct IccProfile {
+    pub name: String,
+    /// Decompressed ICC profile
+    pub profile: Vec<u8>,
+}
+
+/// https://www.w3.org/TR/png/#cICP-chunk
+///
+/// Code points are defined in ITU-T H.273.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodingIndependentCodePoints {
+    pub color_primaries: u8,
+    pub transfer_function: u8,
+    pub matrix_coefficients: u8,
+    pub video_full_range: bool,
+}
+
+/// https://www.w3.org/TR/png/#mDCv-chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MasteringDisplayColorVolume {
+    /// Red, green and blue chromaticities in units of 0.00002
+    pub primaries: [(u16, u16); 3],
+    /// White point chromaticity in units of 0.00002
+    pub white_point: (u16, u16),
+    /// Maximum luminance in units of 0.0001 cd/m2
+    pub max_luminance: u32,
+    /// Minimum luminance in units of 0.0001 cd/m2
+    pub min_luminance: u32,
+}
+
+/// https://www.w3.org/TR/png/#cLLi-chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContentLightLevel {
+    /// Maximum content light level in units of 0.0001 cd/m2
+    pub max_content_light_level: u32,
+    /// Maximum frame-average light level in units of 0.0001 cd/m2
+    pub max_frame_average_light_level: u32,
+}
+
+/// Mapping between encoded sample values and linear light, both normalized to 0.0-1.0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferFunction {
+    Linear,
+    Srgb,
+    /// ITU-R BT.709
+    Bt709,
+    /// Power law with the encoding exponent, e.g. 0.45455 for gAMA=45455
+    Gamma(f64),
+    /// Not supported by this crate, e.g. PQ or HLG. Samples are used as encoded without correction.
+    Unknown,
+}
+
+impl TransferFunction {
+    pub fn to_linear(&self, value: f64) -> f64 {
+        match *self {
+            TransferFunction::Linear => value,
+            TransferFunction::Srgb => {
+                if value <= 0.04045 {
+                    value / 12.92
+                } else {
+                    ((value + 0.055) / 1.055).powf(2.4)
+                }
+            }
+            TransferFunction::Bt709 => {
+                if value < 0.081 {
+                    value / 4.5
+                } else {
+                    ((value + 0.099) / 1.099).powf(1.0 / 0.45)
+                }
+            }
+            TransferFunction::Gamma(gamma) => value.powf(1.0 / gamma),
+            TransferFunction::Unknown => value,
+        }
+    }
+
+    pub fn from_linear(&self, value: f64) -> f64 {
+        match *self {
+            TransferFunction::Linear => value,
+            TransferFunction::Srgb => {
+                if value <= 0.0031308 {
+                    value * 12.92
+                } else {
+                    1.055 * value.powf(1.0 / 2.4) - 0.055
+                }
+            }
+            TransferFunction::Bt709 => {
+                if value < 0.018 {
+                    value * 4.5
+                } else {
+                    1.099 * value.powf(0.45) - 0.099
+                }
+            }
+            TransferFunction::Gamma(gamma) => value.powf(gamma),
+            TransferFunction::Unknown => value,
+        }
+    }
+}
+
+impl ColorInfo {
+    pub fn read(&mut self, chunk: &Chunk) -> Result<(), Error> {
+        let data = &chunk.data[..];
+        match chunk.chunk_type {
+            [b'g', b'A', b'M', b'A'] => {
+                self.gamma = Some(read_exact(data, |reader| reader.u32_be())?);
+            }
+            [b'c', b'H', b'R', b'M'] => {
+                self.chromaticities = Some(read_exact(data, |reader| {
+                    Ok(Chromaticities {
+                        white_point: (reader.u32_be()?, reader.u32_be()?),
+                        red: (reader.u32_be()?, reader.u32_be()?),
+                        green: (reader.u32_be()?, reader.u32_be()?),
+                        blue: (reader.u32_be()?, reader.u32_be()?),
+                    })
+                })?);
+            }
+            [b's', b'R', b'G', b'B'] => {
+                self.srgb = Some(match read_exact(data, |reader| reader.u8())? {
+                    0 => RenderingIntent::Perceptual,
+                    1 => RenderingIntent::RelativeColorimetric,
+                    2 => RenderingIntent::Saturation,
+                    3 => RenderingIntent::AbsoluteColorimetric,
+                    _ => return Err(invalid_data("Unknown rendering intent")),
+                });
+            }
+            [b'i', b'C', b'C', b'P'] => {
+                let (name, rest) = split_null(data)?;
+                let mut reader = ByteReader::new(rest);
+                if reader.u8()? != 0 {
+                    return Err(invalid_data("Unknown compression method"));
+                }
+                self.icc_profile = Some(IccProfile {
+                    name: decode_latin1(name),
+                    profile: zlib::zlib::inflate(reader.rest())?,
+                });
+            }
+            [b'c', b'I', b'C', b'P'] => {
+                self.cicp = Some(read_exact(data, |reader| {
+                    Ok(CodingIndependentCodePoints {
+                        color_primaries: reader.u8()?,
+                        transfer_function: reader.u8()?,
+                        matrix_coefficients: reader.u8()?,
+                        video_full_range: reader.u8()? == 1,
+                    })
+                })?);
+            }
+            [b'm', b'D', b'C', b'v'] => {
+                self.mastering_display = Some(read_exact(data, |reader| {
+                    Ok(MasteringDisplayColorVolume {
+                        primaries: [
+                            (reader.u16_be()?, reader.u16_be()?),
+                            (reader.u16_be()?, reader.u16_be()?),
+                            (reader.u16_be()?, reader.u16_be()?),
+                        ],
+                        white_point: (reader.u16_be()?, reader.u16_be()?),
+                        max_luminance: reader.u32_be()?,
+                        min_luminance: reader.u32_be()?,
+                    })
+                })?);
+            }
+            [b'c', b'L', b'L', b'i'] => {
+                self.content_light_level = Some(read_exact(data, |reader| {
+                    Ok(ContentLightLevel {
+                        max_content_light_level: reader.u32_be()?,
+                        max_frame_average_light_level: reader.u32_be()?,
+                    })
+                })?);
+            }
+            _ => return Err(invalid_data("Not a color information chunk")),
+        }
+        Ok(())
+    }
+
+    /// Fails if the profile name is not 1-79 bytes of Latin-1.
+    pub fn to_chunks(&self) -> Result<Vec<Chunk>, Error> {
+        let mut chunks = Vec::new();
+
+        if let Some(cicp) = &self.cicp {
+            chunks.push(Chunk::new(
+                [b'c', b'I', b'C', b'P'],
+                vec![
+                    cicp.color_primaries,
+                    cicp.transfer_function,
+                    cicp.matrix_coefficients,
+                    cicp.video_full_range as u8,
+                ],
+            ));
+        }
+        if let Some(chromaticities) = &self.chromaticities {
+            let mut data = Vec::new();
+            for (x, y) in [
+                chromaticities.white_point,
+                chromaticities.red,
+                chromaticities.green,
+                chromaticities.blue,
+            ] {
+                data.extend_from_slice(&x.to_be_bytes());
+                data.extend_from_slice(&y.to_be_bytes());
+            }
+            chunks.push(Chunk::new([b'c', b'H', b'R', b'M'], data));
+        }
+        if let Some(gamma) = self.gamma {
+            chunks.push(Chunk::new(
+                [b'g', b'A', b'M', b'A'],
+                gamma.to_be_bytes().to_vec(),
+            ));
+        }
+        if let Some(icc_profile) = &self.icc_profile {
+            let mut data = encode_keyword(&icc_profile.name)?;
+            data.push(0);
+            data.push(0); // compression method
+            data.extend(zlib::zlib::deflate(&icc_profile.profile));
+            chunks.push(Chunk::new([b'i', b'C', b'C', b'P'], data));
+        }
+        if let Some(intent) = self.srgb {
+            chunks.push(Chunk::new([b's', b'R', b'G', b'B'], vec![intent as u8]));
+        }
+        if let Some(mastering_display) = &self.mastering_display {
+            let mut data = Vec::new();
+            for (x, y) in mastering_display
+                .primaries
+                .iter()
+                .chain([&mastering_display.white_point])
+            {
+                data.extend_from_slice(&x.to_be_bytes());
+                data.extend_from_slice(&y.to_be_bytes());
+            }
+            data.extend_from_slice(&mastering_display.max_luminance.to_be_bytes());
+            data.extend_from_slice(&mastering_display.min_luminance.to_be_bytes());
+            chunks.push(Chunk::new([b'm', b'D', b'C', b'v'], data));
+        }
+        if let Some(content_light_level) = &self.content_light_level {
+            let mut data = Vec::new();
+            data.extend_from_slice(&content_light_level.max_content_light_level.to_be_bytes());
+            data.extend_from_slice(
+                &content_light_level
+                    .max_frame_average_light_level
+                    .to_be_bytes(),
+            );
+            chunks.push(Chunk::new([b'c', b'L', b'L', b'i'], data));
+        }
+
+        Ok(chunks)
+    }
+
+    /// Determine the transfer function of the encoded samples.
+    ///
+    /// Chunks are prioritized as cICP, sRGB and gAMA. Images without any of them are assumed to be sRGB.
+    /// ICC profiles are not interpreted, and cICP transfer functions other than BT.709, sRGB and
+    /// linear are reported as `Unknown`.
+    pub fn transfer_function(&self) -> Result<TransferFunction, Error> {
+        if let Some(cicp) = &self.cicp {
+            return match cicp.transfer_function {
+                1 | 6 | 14 | 15 => Ok(TransferFunction::Bt709),
+                8 => Ok(TransferFunction::Linear),
+                13 => Ok(TransferFunction::Srgb),
+                _ => Ok(TransferFunction::Unknown),
+            };
+        }
+        if self.srgb.is_some() {
+            return Ok(TransferFunction::Srgb);
+        }
+        if let Some(gamma) = self.gamma {
+            if gamma == 0 {
+                return Err(invalid_data("Gamma must not be zero"));
+            }
+            return Ok(TransferFunction::Gamma(gamma as f64 / 100000.0));
+        }
+        Ok(TransferFunction::Srgb)
+    }
+}
+
+/// Build a lookup table converting 8-bit samples from one transfer function to another.
+///
+/// The table is the identity if either of them is `Unknown`.
+pub fn gamma_table(source: TransferFunction, target: TransferFunction) -> [u8; 256] {
+    let mut table = [0u8; 256];
+    if source == TransferFunction::Unknown || target == TransferFunction::Unknown {
+        for (i, value) in table.iter_mut().enumerate() {
+            *value = i as u8;
+        }
+        return table;
+    }
+    for (i, value) in table.iter_mut().enumerate() {
+        let linear = source.to_linear(i as f64 / 255.0);
+        *value = (target.from_linear(linear).clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use crate::color::*;
+
+    #[test]
+    fn test_round_trip() {
+        let color = ColorInfo {
+            gamma: Some(45455),
+            chromaticities: Some(Chromaticities {
+                white_point: (31270, 32900),
+                red: (64000, 33000),
+                green: (30000, 60000),
+                blue: (15000, 6000),
+            }),
+            srgb: Some(RenderingIntent::Perceptual),
+            icc_profile: Some(IccProfile {
+                name: "Display P3".to_string(),
+                profile: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            }),
+            cicp: Some(CodingIndependentCodePoints {
+                color_primaries: 9,
+                transfer_function: 16,
+                matrix_coefficients: 0,
+                video_full_range: true,
+            }),
+            mastering_display: Some(MasteringDisplayColorVolume {
+                primaries: [(35400, 14600), (8500, 39850), (6550, 2300)],
+                white_point: (15635, 16450),
+                max_luminance: 10000000,
+                min_luminance: 1,
+            }),
+            content_light_level: Some(ContentLightLevel {
+                max_content_light_level: 10000000,
+                max_frame_average_light_level: 4000000,
+            }),
+        };
+
+        let mut decoded = ColorInfo::default();
+        for chunk in color.to_chunks().unwrap() {
+            decoded.read(&chunk).unwrap();
+        }
+        assert_eq!(decoded, color);
+
+        // profile names are 1-79 bytes of Latin-1
+        for name in ["", "プロファイル", &"a".repeat(80)] {
+            let color = ColorInfo {
+                icc_profile: Some(IccProfile {
+                    name: name.to_string(),
+                    profile: vec![1, 2, 3],
+                }),
+                ..ColorInfo::default()
+            };
+            assert!(color.to_chunks().is_err());
+        }
+    }
+
+    #[test]
+    fn test_transfer_function() {
+        let color = ColorInfo {
+            gamma: Some(100000),
+            ..ColorInfo::default()
+        };
+        assert_eq!(
+            color.transfer_function().unwrap(),
+            TransferFunction::Gamma(1.0)
+        );
+
+        let table = gamma_table(TransferFunction::Srgb, TransferFunction::Linear);
+        assert_eq!(table[0], 0);
+        assert_eq!(table[188], 128);
+        assert_eq!(table[255], 255);
+
+        // PQ
+        let color = ColorInfo {
+            cicp: Some(CodingIndependentCodePoints {
+                color_primaries: 9,
+                transfer_function: 16,
+                matrix_coefficients: 0,
+                video_full_range: true,
+            }),
+            ..ColorInfo::default()
+        };
+        assert_eq!(
+            color.transfer_function().unwrap(),
+            TransferFunction::Unknown
+        );
+        let table = gamma_table(TransferFunction::Unknown, TransferFunction::Srgb);
+        assert_eq!(table[128], 128);
+    }
+}
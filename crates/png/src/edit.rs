@@ -0,0 +1,381 @@
+use crate::png::{read_chunks, Chunk, SIGNATURE};
+use std::fs::{self, File};
+use std::io::ErrorKind::{InvalidData, InvalidInput};
+use std::io::{BufReader, Error, Write};
+use std::path::Path;
+
+/// Edit the chunk list of a PNG file without decoding the image.
+///
+/// IDAT and the other chunks that are not edited are copied byte for byte,
+/// and the CRC of every chunk is recomputed when written.
+///
+/// https://www.w3.org/TR/png/#14Ordering
+pub struct ChunkEditor {
+    chunks: Vec<Chunk>,
+}
+
+/// Ancillary chunks which must appear before PLTE.
+const BEFORE_PALETTE: [[u8; 4]; 8] = [
+    [b'c', b'H', b'R', b'M'],
+    [b'g', b'A', b'M', b'A'],
+    [b'i', b'C', b'C', b'P'],
+    [b's', b'B', b'I', b'T'],
+    [b's', b'R', b'G', b'B'],
+    [b'c', b'I', b'C', b'P'],
+    [b'm', b'D', b'C', b'v'],
+    [b'c', b'L', b'L', b'i'],
+];
+
+/// Other known ancillary chunks, which are inserted just before IDAT.
+const AFTER_PALETTE: [[u8; 4]; 11] = [
+    [b't', b'R', b'N', b'S'],
+    [b'b', b'K', b'G', b'D'],
+    [b'h', b'I', b'S', b'T'],
+    [b'p', b'H', b'Y', b's'],
+    [b's', b'P', b'L', b'T'],
+    [b'o', b'F', b'F', b's'],
+    [b'e', b'X', b'I', b'f'],
+    [b't', b'I', b'M', b'E'],
+    [b't', b'E', b'X', b't'],
+    [b'z', b'T', b'X', b't'],
+    [b'i', b'T', b'X', b't'],
+];
+
+/// Ancillary chunks which may appear more than once.
+const MULTIPLE: [[u8; 4]; 4] = [
+    [b's', b'P', b'L', b'T'],
+    [b't', b'E', b'X', b't'],
+    [b'z', b'T', b'X', b't'],
+    [b'i', b'T', b'X', b't'],
+];
+
+/// Chunks which belong to the image data and cannot be edited.
+const IMAGE_DATA: [[u8; 4]; 6] = [
+    [b'I', b'H', b'D', b'R'],
+    [b'I', b'D', b'A', b'T'],
+    [b'I', b'E', b'N', b'D'],
+    [b'a', b'c', b'T', b'L'],
+    [b'f', b'c', b'T', b'L'],
+    [b'f', b'd', b'A', b'T'],
+];
+
+const PALETTE: [u8; 4] = [b'P', b'L', b'T', b'E'];
+
+impl ChunkEditor {
+    pub fn open(path: impl AsRef<Path>) -> Result<ChunkEditor, Error> {
+        ChunkEditor::from_chunks(read_chunks(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ChunkEditor, Error> {
+        ChunkEditor::from_chunks(read_chunks(bytes)?)
+    }
+
+    /// A CRC mismatch in a critical chunk is an error. Corrupted ancillary chunks are dropped,
+    /// since they would otherwise be written with a recomputed CRC.
+    pub fn from_chunks(mut chunks: Vec<Chunk>) -> Result<ChunkEditor, Error> {
+        if let Some(chunk) = chunks
+            .iter()
+            .find(|chunk| chunk.is_critical() && !chunk.is_crc_valid())
+        {
+            return Err(Error::new(
+                InvalidData,
+                format!(
+                    "CRC mismatch in {} chunk",
+                    String::from_utf8_lossy(&chunk.chunk_type)
+                ),
+            ));
+        }
+        chunks.retain(|chunk| chunk.is_crc_valid());
+        if chunks.first().map(|chunk| chunk.chunk_type) != Some([b'I', b'H', b'D', b'R']) {
+            return Err(Error::new(InvalidData, "Missing IHDR chunk"));
+        }
+        if chunks.last().map(|chunk| chunk.chunk_type) != Some([b'I', b'E', b'N', b'D']) {
+            return Err(Error::new(InvalidData, "Missing IEND chunk"));
+        }
+        if !chunks
+            .iter()
+            .any(|chunk| chunk.chunk_type == [b'I', b'D', b'A', b'T'])
+        {
+            return Err(Error::new(InvalidData, "Missing IDAT chunk"));
+        }
+        Ok(ChunkEditor { chunks })
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// Add a chunk at the last position allowed by the chunk ordering rules.
+    ///
+    /// Fails if the chunk type may appear only once and already exists.
+    pub fn insert(&mut self, chunk: Chunk) -> Result<(), Error> {
+        check_editable(chunk.chunk_type)?;
+        if !MULTIPLE.contains(&chunk.chunk_type) && self.contains(chunk.chunk_type) {
+            return Err(Error::new(
+                InvalidInput,
+                format!(
+                    "{} chunk already exists",
+                    String::from_utf8_lossy(&chunk.chunk_type)
+                ),
+            ));
+        }
+        let index = self.insert_position(chunk.chunk_type);
+        self.chunks.insert(index, chunk);
+        Ok(())
+    }
+
+    /// Replace all chunks of the same type with `chunk`, or insert it if there is none.
+    ///
+    /// Replacing PLTE removes unknown chunks which are not safe to copy.
+    pub fn replace(&mut self, chunk: Chunk) -> Result<(), Error> {
+        if chunk.chunk_type != PALETTE {
+            check_editable(chunk.chunk_type)?;
+        }
+        let index = self
+            .chunks
+            .iter()
+            .position(|c| c.chunk_type == chunk.chunk_type);
+        let chunk_type = chunk.chunk_type;
+        match index {
+            Some(index) => {
+                self.chunks[index] = chunk;
+                let mut i = 0;
+                self.chunks.retain(|c| {
+                    i += 1;
+                    i - 1 <= index || c.chunk_type != chunk_type
+                });
+            }
+            None => {
+                let index = self.insert_position(chunk_type);
+                self.chunks.insert(index, chunk);
+            }
+        }
+
+        if chunk_type == PALETTE {
+            // unknown unsafe-to-copy chunks may depend on the modified critical chunk
+            self.chunks
+                .retain(|c| c.is_critical() || c.is_safe_to_copy() || is_known(c.chunk_type));
+        }
+        Ok(())
+    }
+
+    /// Remove all chunks of the type, returning the number of removed chunks.
+    pub fn remove(&mut self, chunk_type: [u8; 4]) -> Result<usize, Error> {
+        check_editable(chunk_type)?;
+        let length = self.chunks.len();
+        self.chunks.retain(|chunk| chunk.chunk_type != chunk_type);
+        Ok(length - self.chunks.len())
+    }
+
+    /// Keep only the ancillary chunks for which `f` returns true. Critical chunks are always kept.
+    pub fn retain(&mut self, mut f: impl FnMut(&Chunk) -> bool) {
+        self.chunks.retain(|chunk| {
+            IMAGE_DATA.contains(&chunk.chunk_type) || chunk.is_critical() || f(chunk)
+        });
+    }
+
+    pub fn contains(&self, chunk_type: [u8; 4]) -> bool {
+        self.chunks
+            .iter()
+            .any(|chunk| chunk.chunk_type == chunk_type)
+    }
+
+    /// Write to a temporary file next to `path` and rename it over `path`,
+    /// so the original is left intact if writing fails.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut out = Vec::new();
+        self.write(&mut out);
+
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp_path = path.with_file_name(temp_name);
+        let result = File::create(&temp_path)
+            .and_then(|mut file| {
+                file.write_all(&out)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp_path, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&SIGNATURE);
+        for chunk in &self.chunks {
+            chunk.write(out);
+        }
+    }
+
+    fn insert_position(&self, chunk_type: [u8; 4]) -> usize {
+        let image_data = self
+            .chunks
+            .iter()
+            .position(|chunk| {
+                chunk.chunk_type == [b'I', b'D', b'A', b'T']
+                    || chunk.chunk_type == [b'f', b'c', b'T', b'L']
+            })
+            .unwrap_or(self.chunks.len() - 1);
+        if BEFORE_PALETTE.contains(&chunk_type) {
+            self.chunks[..image_data]
+                .iter()
+                .position(|chunk| chunk.chunk_type == PALETTE)
+                .unwrap_or(image_data)
+        } else if chunk_type == PALETTE {
+            self.chunks[..image_data]
+                .iter()
+                .position(|chunk| {
+                    !BEFORE_PALETTE.contains(&chunk.chunk_type) && !chunk.is_critical()
+                })
+                .unwrap_or(image_data)
+        } else {
+            image_data
+        }
+    }
+}
+
+fn is_known(chunk_type: [u8; 4]) -> bool {
+    BEFORE_PALETTE.contains(&chunk_type)
+        || AFTER_PALETTE.contains(&chunk_type)
+        || IMAGE_DATA.contains(&chunk_type)
+}
+
+fn check_editable(chunk_type: [u8; 4]) -> Result<(), Error> {
+    if !chunk_type.iter().all(|c| c.is_ascii_alphabetic()) {
+        return Err(Error::new(InvalidInput, "Invalid chunk type"));
+    }
+    if IMAGE_DATA.contains(&chunk_type) || chunk_type[0].is_ascii_uppercase() {
+        return Err(Error::new(
+            InvalidInput,
+            format!(
+                "{} chunk cannot be edited",
+                String::from_utf8_lossy(&chunk_type)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metadata::{Metadata, PhysicalDimensions};
+    use crate::png::{ColorType, Png};
+    use crate::text::Text;
+
+    fn sample() -> Vec<u8> {
+        let png = Png {
+            width: 2,
+            height: 1,
+            bit_depth: 1,
+            color_type: ColorType::Indexed,
+            data: Box::new([0b01000000]),
+            palette: vec![[0, 0, 0], [255, 255, 255]],
+            ..Png::default()
+        };
+        let mut out = Vec::new();
+        png.write(&mut out).unwrap();
+        out
+    }
+
+    fn types(editor: &ChunkEditor) -> Vec<String> {
+        editor
+            .chunks()
+            .iter()
+            .map(|chunk| String::from_utf8_lossy(&chunk.chunk_type).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_edit_chunks() -> Result<(), Error> {
+        let original = sample();
+        let mut editor = ChunkEditor::from_bytes(&original)?;
+        let text = Text::Latin1 {
+            keyword: "Title".to_string(),
+            text: "sample".to_string(),
+        };
+        editor.insert(text.to_chunk()?)?;
+        editor.insert(text.to_chunk()?)?;
+        editor.replace(Chunk::new(
+            [b'g', b'A', b'M', b'A'],
+            45455u32.to_be_bytes().to_vec(),
+        ))?;
+        let metadata = Metadata {
+            physical_dimensions: Some(PhysicalDimensions::from_dpi(300.0, 300.0)),
+            ..Metadata::default()
+        };
+        for chunk in metadata.to_chunks_after_palette()? {
+            editor.replace(chunk)?;
+        }
+        assert!(editor
+            .insert(Chunk::new([b'g', b'A', b'M', b'A'], vec![0; 4]))
+            .is_err());
+        assert!(editor.remove([b'I', b'D', b'A', b'T']).is_err());
+        assert_eq!(
+            types(&editor),
+            ["IHDR", "gAMA", "PLTE", "tEXt", "tEXt", "pHYs", "IDAT", "IEND"]
+        );
+
+        assert_eq!(editor.remove([b't', b'E', b'X', b't'])?, 2);
+
+        let mut edited = Vec::new();
+        editor.write(&mut edited);
+        let editor = ChunkEditor::from_bytes(&edited)?;
+        assert!(editor.chunks().iter().all(Chunk::is_crc_valid));
+
+        let decoded = Png::from_chunks(editor.chunks())?;
+        assert_eq!(decoded.color.gamma, Some(45455));
+        let idat = |chunks: &[Chunk]| {
+            chunks
+                .iter()
+                .find(|chunk| chunk.chunk_type == [b'I', b'D', b'A', b'T'])
+                .map(|chunk| chunk.data.clone())
+        };
+        assert_eq!(idat(editor.chunks()), idat(&read_chunks(&original[..])?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_crc_mismatch() -> Result<(), Error> {
+        let error = ChunkEditor::open("tests/suite/x_idat_crc.png")
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), InvalidData);
+
+        let editor = ChunkEditor::open("tests/suite/bad_ancillary_crc.png")?;
+        assert_eq!(types(&editor), ["IHDR", "IDAT", "IEND"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_palette_drops_unsafe_chunks() -> Result<(), Error> {
+        let mut editor = ChunkEditor::from_bytes(&sample())?;
+        // private chunks: "prVt" is safe to copy, "prVT" is not
+        editor.insert(Chunk::new([b'p', b'r', b'V', b't'], vec![1]))?;
+        editor.insert(Chunk::new([b'p', b'r', b'V', b'T'], vec![2]))?;
+
+        editor.replace(Chunk::new(PALETTE, vec![255, 0, 0, 0, 0, 255]))?;
+        assert_eq!(types(&editor), ["IHDR", "PLTE", "prVt", "IDAT", "IEND"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("png-edit-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("sample.png");
+        fs::write(&path, b"original")?;
+
+        let editor = ChunkEditor::from_bytes(&sample())?;
+        editor.save(&path)?;
+        assert_eq!(fs::read(&path)?, sample());
+        assert_eq!(fs::read_dir(&dir)?.count(), 1);
+
+        fs::remove_dir_all(&dir)
+    }
+}
@@ -1,3 +1,14 @@
 extern crate core;
 
+pub mod apng;
+pub mod color;
+pub mod crc;
+pub mod edit;
+pub mod metadata;
+pub mod optimize;
 pub mod png;
+pub mod quantize;
+pub mod stream;
+pub mod text;
+pub mod transparency;
+mod util;
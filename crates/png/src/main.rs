@@ -16,10 +16,10 @@ fn main() {
     let mut window = Window::new(0, 0, bmp.width as i32, bmp.height as i32, "image");
     window.draw(move |f| {
         RgbImage::new(
-            bmp.data.as_ref(),
+            bmp.to_rgba8().as_ref(),
             bmp.width as i32,
             bmp.height as i32,
-            ColorDepth::Rgb8,
+            ColorDepth::Rgba8,
         )
         .unwrap()
         .draw(0, 0, f.w(), f.h());
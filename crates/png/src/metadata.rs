@@ -0,0 +1,477 @@
+use crate::png::{Chunk, ColorType};
+use crate::util::{decode_latin1, encode_latin1, invalid_data, read_exact, split_null};
+use binary::bytes::ByteReader;
+use std::io::{Error, ErrorKind};
+
+/// Ancillary information from pHYs, tIME, sBIT, hIST, sPLT, oFFs and eXIf chunks.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// pHYs
+    pub physical_dimensions: Option<PhysicalDimensions>,
+    /// tIME
+    pub time: Option<Time>,
+    /// sBIT
+    pub significant_bits: Option<SignificantBits>,
+    /// hIST: frequency of each palette entry
+    pub histogram: Option<Vec<u16>>,
+    /// sPLT
+    pub suggested_palettes: Vec<SuggestedPalette>,
+    /// oFFs
+    pub offsets: Option<Offsets>,
+    /// eXIf
+    pub exif: Option<Exif>,
+}
+
+/// https://www.w3.org/TR/png/#11pHYs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhysicalDimensions {
+    pub pixels_per_unit_x: u32,
+    pub pixels_per_unit_y: u32,
+    pub unit: PhysicalUnit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhysicalUnit {
+    /// Only the aspect ratio is known
+    Unknown = 0,
+    Meter = 1,
+}
+
+impl PhysicalDimensions {
+    pub fn from_dpi(dpi_x: f64, dpi_y: f64) -> PhysicalDimensions {
+        PhysicalDimensions {
+            pixels_per_unit_x: (dpi_x / 0.0254).round() as u32,
+            pixels_per_unit_y: (dpi_y / 0.0254).round() as u32,
+            unit: PhysicalUnit::Meter,
+        }
+    }
+
+    /// Pixels per inch in (x, y), if the unit is known.
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        match self.unit {
+            PhysicalUnit::Meter => Some((
+                self.pixels_per_unit_x as f64 * 0.0254,
+                self.pixels_per_unit_y as f64 * 0.0254,
+            )),
+            PhysicalUnit::Unknown => None,
+        }
+    }
+}
+
+/// https://www.w3.org/TR/png/#11tIME
+///
+/// Time of the last modification in UTC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// https://www.w3.org/TR/png/#11sBIT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignificantBits {
+    Gray(u8),
+    /// Also used for indexed-color images
+    Rgb(u8, u8, u8),
+    GrayAlpha(u8, u8),
+    Rgba(u8, u8, u8, u8),
+}
+
+/// https://www.w3.org/TR/png/#11sPLT
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuggestedPalette {
+    pub name: String,
+    /// 8 or 16
+    pub sample_depth: u8,
+    pub entries: Vec<SuggestedPaletteEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SuggestedPaletteEntry {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+    pub alpha: u16,
+    pub frequency: u16,
+}
+
+/// https://ftp-osl.osuosl.org/pub/libpng/documents/pngext-1.5.0.html#C.oFFs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Offsets {
+    pub x: i32,
+    pub y: i32,
+    pub unit: OffsetUnit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OffsetUnit {
+    Pixel = 0,
+    Micrometer = 1,
+}
+
+/// https://www.w3.org/TR/png/#eXIf
+///
+/// Raw Exif data starting with the TIFF header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exif {
+    pub data: Vec<u8>,
+}
+
+/// An entry of an Exif image file directory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExifEntry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    /// The value itself if it fits in 4 bytes, otherwise the offset to the value
+    pub value_offset: [u8; 4],
+}
+
+const EXIF_TAG_ORIENTATION: u16 = 0x0112;
+const EXIF_TYPE_SHORT: u16 = 3;
+
+impl Exif {
+    fn is_little_endian(&self) -> Result<bool, Error> {
+        match self.data.get(..4) {
+            Some([b'I', b'I', 42, 0]) => Ok(true),
+            Some([b'M', b'M', 0, 42]) => Ok(false),
+            _ => Err(invalid_data("Invalid TIFF header")),
+        }
+    }
+
+    fn read_u16(&self, offset: usize) -> Result<u16, Error> {
+        let bytes = self
+            .data
+            .get(offset..offset + 2)
+            .ok_or_else(|| invalid_data("Exif data is too short"))?;
+        let bytes = [bytes[0], bytes[1]];
+        Ok(if self.is_little_endian()? {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn read_u32(&self, offset: usize) -> Result<u32, Error> {
+        let bytes = self
+            .data
+            .get(offset..offset + 4)
+            .ok_or_else(|| invalid_data("Exif data is too short"))?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Ok(if self.is_little_endian()? {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    /// Read the entries of the first image file directory (IFD0).
+    pub fn entries(&self) -> Result<Vec<ExifEntry>, Error> {
+        let ifd_offset = self.read_u32(4)? as usize;
+        let count = self.read_u16(ifd_offset)? as usize;
+
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let offset = ifd_offset + 2 + i * 12;
+            let value_offset = self
+                .data
+                .get(offset + 8..offset + 12)
+                .ok_or_else(|| invalid_data("Exif data is too short"))?;
+            entries.push(ExifEntry {
+                tag: self.read_u16(offset)?,
+                field_type: self.read_u16(offset + 2)?,
+                count: self.read_u32(offset + 4)?,
+                value_offset: [
+                    value_offset[0],
+                    value_offset[1],
+                    value_offset[2],
+                    value_offset[3],
+                ],
+            });
+        }
+        Ok(entries)
+    }
+
+    /// The orientation tag (1-8) of IFD0.
+    pub fn orientation(&self) -> Result<Option<u16>, Error> {
+        let little_endian = self.is_little_endian()?;
+        let entry = self
+            .entries()?
+            .into_iter()
+            .find(|entry| entry.tag == EXIF_TAG_ORIENTATION);
+        match entry {
+            Some(entry) if entry.field_type == EXIF_TYPE_SHORT && entry.count == 1 => {
+                let bytes = [entry.value_offset[0], entry.value_offset[1]];
+                Ok(Some(if little_endian {
+                    u16::from_le_bytes(bytes)
+                } else {
+                    u16::from_be_bytes(bytes)
+                }))
+            }
+            Some(_) => Err(invalid_data("Invalid orientation tag")),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Metadata {
+    pub fn read(&mut self, chunk: &Chunk, color_type: ColorType) -> Result<(), Error> {
+        let data = &chunk.data[..];
+        match chunk.chunk_type {
+            [b'p', b'H', b'Y', b's'] => {
+                self.physical_dimensions = Some(read_exact(data, |reader| {
+                    Ok(PhysicalDimensions {
+                        pixels_per_unit_x: reader.u32_be()?,
+                        pixels_per_unit_y: reader.u32_be()?,
+                        unit: match reader.u8()? {
+                            0 => PhysicalUnit::Unknown,
+                            1 => PhysicalUnit::Meter,
+                            _ => return Err(invalid_data("Unknown unit")),
+                        },
+                    })
+                })?);
+            }
+            [b't', b'I', b'M', b'E'] => {
+                self.time = Some(read_exact(data, |reader| {
+                    Ok(Time {
+                        year: reader.u16_be()?,
+                        month: reader.u8()?,
+                        day: reader.u8()?,
+                        hour: reader.u8()?,
+                        minute: reader.u8()?,
+                        second: reader.u8()?,
+                    })
+                })?);
+            }
+            [b's', b'B', b'I', b'T'] => {
+                self.significant_bits = Some(read_exact(data, |reader| {
+                    Ok(match color_type {
+                        ColorType::Grayscale => SignificantBits::Gray(reader.u8()?),
+                        ColorType::Truecolor | ColorType::Indexed => {
+                            SignificantBits::Rgb(reader.u8()?, reader.u8()?, reader.u8()?)
+                        }
+                        ColorType::GrayscaleAlpha => {
+                            SignificantBits::GrayAlpha(reader.u8()?, reader.u8()?)
+                        }
+                        ColorType::TruecolorAlpha => SignificantBits::Rgba(
+                            reader.u8()?,
+                            reader.u8()?,
+                            reader.u8()?,
+                            reader.u8()?,
+                        ),
+                    })
+                })?);
+            }
+            [b'h', b'I', b'S', b'T'] => {
+                let mut reader = ByteReader::new(data);
+                let mut histogram = Vec::with_capacity(data.len() / 2);
+                while reader.remaining() > 0 {
+                    histogram.push(reader.u16_be()?);
+                }
+                self.histogram = Some(histogram);
+            }
+            [b's', b'P', b'L', b'T'] => {
+                let (name, rest) = split_null(data)?;
+                let mut reader = ByteReader::new(rest);
+                let sample_depth = reader.u8()?;
+                if sample_depth != 8 && sample_depth != 16 {
+                    return Err(invalid_data("Invalid sample depth"));
+                }
+                let mut entries = Vec::new();
+                while reader.remaining() > 0 {
+                    entries.push(if sample_depth == 8 {
+                        SuggestedPaletteEntry {
+                            red: reader.u8()? as u16,
+                            green: reader.u8()? as u16,
+                            blue: reader.u8()? as u16,
+                            alpha: reader.u8()? as u16,
+                            frequency: reader.u16_be()?,
+                        }
+                    } else {
+                        SuggestedPaletteEntry {
+                            red: reader.u16_be()?,
+                            green: reader.u16_be()?,
+                            blue: reader.u16_be()?,
+                            alpha: reader.u16_be()?,
+                            frequency: reader.u16_be()?,
+                        }
+                    });
+                }
+                self.suggested_palettes.push(SuggestedPalette {
+                    name: decode_latin1(name),
+                    sample_depth,
+                    entries,
+                });
+            }
+            [b'o', b'F', b'F', b's'] => {
+                self.offsets = Some(read_exact(data, |reader| {
+                    Ok(Offsets {
+                        x: reader.i32_be()?,
+                        y: reader.i32_be()?,
+                        unit: match reader.u8()? {
+                            0 => OffsetUnit::Pixel,
+                            1 => OffsetUnit::Micrometer,
+                            _ => return Err(invalid_data("Unknown unit")),
+                        },
+                    })
+                })?);
+            }
+            [b'e', b'X', b'I', b'f'] => {
+                self.exif = Some(Exif {
+                    data: data.to_vec(),
+                });
+            }
+            _ => return Err(invalid_data("Not a metadata chunk")),
+        }
+        Ok(())
+    }
+
+    /// Chunks which must be placed before PLTE.
+    pub fn to_chunks_before_palette(&self) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+
+        if let Some(significant_bits) = self.significant_bits {
+            let data = match significant_bits {
+                SignificantBits::Gray(gray) => vec![gray],
+                SignificantBits::Rgb(r, g, b) => vec![r, g, b],
+                SignificantBits::GrayAlpha(gray, alpha) => vec![gray, alpha],
+                SignificantBits::Rgba(r, g, b, a) => vec![r, g, b, a],
+            };
+            chunks.push(Chunk::new([b's', b'B', b'I', b'T'], data));
+        }
+
+        chunks
+    }
+
+    /// Chunks which must be placed after PLTE and before IDAT.
+    pub fn to_chunks_after_palette(&self) -> Result<Vec<Chunk>, Error> {
+        let mut chunks = Vec::new();
+
+        if let Some(histogram) = &self.histogram {
+            let data = histogram.iter().flat_map(|v| v.to_be_bytes()).collect();
+            chunks.push(Chunk::new([b'h', b'I', b'S', b'T'], data));
+        }
+        if let Some(physical_dimensions) = &self.physical_dimensions {
+            let mut data = Vec::new();
+            data.extend_from_slice(&physical_dimensions.pixels_per_unit_x.to_be_bytes());
+            data.extend_from_slice(&physical_dimensions.pixels_per_unit_y.to_be_bytes());
+            data.push(physical_dimensions.unit as u8);
+            chunks.push(Chunk::new([b'p', b'H', b'Y', b's'], data));
+        }
+        for palette in &self.suggested_palettes {
+            let mut data = encode_latin1(&palette.name)?;
+            data.push(0);
+            data.push(palette.sample_depth);
+            for entry in &palette.entries {
+                let samples = [entry.red, entry.green, entry.blue, entry.alpha];
+                match palette.sample_depth {
+                    8 => data.extend(samples.iter().map(|&v| v as u8)),
+                    16 => data.extend(samples.iter().flat_map(|v| v.to_be_bytes())),
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            "Sample depth must be 8 or 16",
+                        ))
+                    }
+                }
+                data.extend_from_slice(&entry.frequency.to_be_bytes());
+            }
+            chunks.push(Chunk::new([b's', b'P', b'L', b'T'], data));
+        }
+        if let Some(offsets) = &self.offsets {
+            let mut data = Vec::new();
+            data.extend_from_slice(&offsets.x.to_be_bytes());
+            data.extend_from_slice(&offsets.y.to_be_bytes());
+            data.push(offsets.unit as u8);
+            chunks.push(Chunk::new([b'o', b'F', b'F', b's'], data));
+        }
+        if let Some(exif) = &self.exif {
+            chunks.push(Chunk::new([b'e', b'X', b'I', b'f'], exif.data.clone()));
+        }
+        if let Some(time) = &self.time {
+            let mut data = time.year.to_be_bytes().to_vec();
+            data.extend_from_slice(&[time.month, time.day, time.hour, time.minute, time.second]);
+            chunks.push(Chunk::new([b't', b'I', b'M', b'E'], data));
+        }
+
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metadata::*;
+
+    #[test]
+    fn test_round_trip() {
+        let metadata = Metadata {
+            physical_dimensions: Some(PhysicalDimensions::from_dpi(300.0, 300.0)),
+            time: Some(Time {
+                year: 2024,
+                month: 5,
+                day: 1,
+                hour: 12,
+                minute: 34,
+                second: 56,
+            }),
+            significant_bits: Some(SignificantBits::Rgb(5, 6, 5)),
+            histogram: Some(vec![10, 0, 3]),
+            suggested_palettes: vec![SuggestedPalette {
+                name: "web".to_string(),
+                sample_depth: 16,
+                entries: vec![SuggestedPaletteEntry {
+                    red: 0xffff,
+                    green: 0x8000,
+                    blue: 0,
+                    alpha: 0xffff,
+                    frequency: 42,
+                }],
+            }],
+            offsets: Some(Offsets {
+                x: -10,
+                y: 20,
+                unit: OffsetUnit::Pixel,
+            }),
+            exif: Some(Exif {
+                data: vec![b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 0],
+            }),
+        };
+
+        let mut decoded = Metadata::default();
+        for chunk in metadata
+            .to_chunks_before_palette()
+            .into_iter()
+            .chain(metadata.to_chunks_after_palette().unwrap())
+        {
+            decoded.read(&chunk, ColorType::Truecolor).unwrap();
+        }
+        assert_eq!(decoded, metadata);
+
+        let (dpi_x, dpi_y) = decoded.physical_dimensions.unwrap().dpi().unwrap();
+        assert!((dpi_x - 300.0).abs() < 0.01);
+        assert!((dpi_y - 300.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_exif_orientation() {
+        // little endian TIFF header with one IFD0 entry: Orientation = 6
+        let exif = Exif {
+            data: vec![
+                b'I', b'I', 42, 0, 8, 0, 0, 0, // header
+                1, 0, // entry count
+                0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, // orientation
+                0, 0, 0, 0, // next IFD
+            ],
+        };
+        assert_eq!(exif.orientation().unwrap(), Some(6));
+
+        let exif = Exif {
+            data: vec![b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 0],
+        };
+        assert_eq!(exif.orientation().unwrap(), None);
+    }
+}
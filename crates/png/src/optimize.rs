@@ -0,0 +1,463 @@
+use crate::color::ColorInfo;
+use crate::metadata::Metadata;
+use crate::png::{ColorType, EncodeOptions, FilterStrategy, Png};
+use crate::transparency::{Background, Transparency};
+use std::collections::BTreeMap;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, InvalidInput};
+
+/// Which ancillary chunks are written to the optimized file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataPolicy {
+    /// Keep every chunk that the decoder understands.
+    Keep,
+    /// Keep only gAMA, cHRM, sRGB, iCCP, cICP, mDCV and cLLI.
+    KeepColor,
+    /// Drop all ancillary chunks except tRNS.
+    Strip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptimizeOptions {
+    pub filters: Vec<FilterStrategy>,
+    pub levels: Vec<u8>,
+    pub metadata: MetadataPolicy,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> OptimizeOptions {
+        OptimizeOptions {
+            filters: FilterStrategy::ALL.to_vec(),
+            levels: vec![6, 9],
+            metadata: MetadataPolicy::Keep,
+        }
+    }
+}
+
+/// Re-encode the image losslessly and return the smallest PNG file found.
+///
+/// Every reduced color type and bit depth that can represent the pixels exactly is tried
+/// with each filter strategy and compression level.
+/// The result is decoded again and its pixels are compared with the original.
+pub fn optimize(png: &Png, options: &OptimizeOptions) -> Result<Vec<u8>, Error> {
+    if options.filters.is_empty() || options.levels.is_empty() {
+        return Err(Error::new(InvalidInput, "No filter or level to try"));
+    }
+    if options.levels.iter().any(|&level| level > 9) {
+        return Err(Error::new(InvalidInput, "Invalid compression level"));
+    }
+
+    let rgba = png.to_rgba16();
+    let mut candidates = reduce(png, &rgba);
+    candidates.push(png.clone());
+
+    // iCCP and cICP of color images describe RGB, which must not be attached to grayscale images
+    let keeps_rgb_profile = options.metadata != MetadataPolicy::Strip
+        && !is_gray(png.color_type)
+        && (png.color.icc_profile.is_some() || png.color.cicp.is_some());
+
+    let mut best: Option<Vec<u8>> = None;
+    for mut candidate in candidates {
+        if keeps_rgb_profile && is_gray(candidate.color_type) {
+            continue;
+        }
+        apply_metadata_policy(png, &mut candidate, options.metadata);
+        for &filter in &options.filters {
+            for &level in &options.levels {
+                let mut out = Vec::new();
+                candidate.write_with_options(&mut out, &EncodeOptions { filter, level })?;
+                if best.as_ref().is_none_or(|best| out.len() < best.len()) {
+                    best = Some(out);
+                }
+            }
+        }
+    }
+    let best = best.unwrap_or_default();
+
+    let decoded = Png::from_bytes(&best)?;
+    if decoded.width != png.width || decoded.height != png.height || decoded.to_rgba16() != rgba {
+        return Err(Error::new(InvalidData, "Optimized image does not match"));
+    }
+
+    Ok(best)
+}
+
+fn apply_metadata_policy(original: &Png, candidate: &mut Png, policy: MetadataPolicy) {
+    let changed = candidate.color_type != original.color_type
+        || candidate.bit_depth != original.bit_depth
+        || candidate.palette != original.palette;
+    candidate.color = match policy {
+        MetadataPolicy::Strip => ColorInfo::default(),
+        _ => original.color.clone(),
+    };
+    match policy {
+        MetadataPolicy::Keep => {
+            candidate.text = original.text.clone();
+            candidate.metadata = original.metadata.clone();
+            if changed {
+                // sBIT and hIST depend on the color type and the palette.
+                candidate.metadata.significant_bits = None;
+                candidate.metadata.histogram = None;
+            }
+        }
+        _ => {
+            candidate.text = Vec::new();
+            candidate.metadata = Metadata::default();
+            candidate.background = None;
+        }
+    }
+}
+
+fn is_gray(color_type: ColorType) -> bool {
+    matches!(color_type, ColorType::Grayscale | ColorType::GrayscaleAlpha)
+}
+
+/// Statistics of the pixels used to choose reduced representations.
+struct Analysis {
+    is_gray: bool,
+    is_opaque: bool,
+    /// Every sample is exactly representable with 8 bits.
+    is_8bit: bool,
+    /// The color of all transparent pixels when they can be expressed with a tRNS color key.
+    color_key: Option<[u16; 3]>,
+    /// Distinct colors, or None when there are more than 256.
+    colors: Option<BTreeMap<[u16; 4], u8>>,
+}
+
+fn analyze(rgba: &[u16]) -> Analysis {
+    let mut analysis = Analysis {
+        is_gray: true,
+        is_opaque: true,
+        is_8bit: true,
+        color_key: None,
+        colors: Some(BTreeMap::new()),
+    };
+    let mut is_binary_alpha = true;
+    let mut transparent_color = None;
+    for pixel in rgba.chunks_exact(4) {
+        let pixel: [u16; 4] = pixel.try_into().unwrap();
+        analysis.is_gray &= pixel[0] == pixel[1] && pixel[1] == pixel[2];
+        analysis.is_opaque &= pixel[3] == 65535;
+        analysis.is_8bit &= pixel.iter().all(|v| v % 257 == 0);
+        match pixel[3] {
+            0 => match transparent_color {
+                None => transparent_color = Some([pixel[0], pixel[1], pixel[2]]),
+                Some(color) if color != [pixel[0], pixel[1], pixel[2]] => is_binary_alpha = false,
+                _ => {}
+            },
+            65535 => {}
+            _ => is_binary_alpha = false,
+        }
+        if let Some(colors) = &mut analysis.colors {
+            if !colors.contains_key(&pixel) {
+                if colors.len() == 256 {
+                    analysis.colors = None;
+                } else {
+                    colors.insert(pixel, 0);
+                }
+            }
+        }
+    }
+
+    if is_binary_alpha && !analysis.is_opaque {
+        let key = transparent_color.unwrap();
+        let is_key_unused = rgba
+            .chunks_exact(4)
+            .all(|pixel| pixel[3] == 0 || pixel[..3] != key);
+        if is_key_unused {
+            analysis.color_key = Some(key);
+        }
+    }
+    analysis
+}
+
+/// Build candidate images with smaller color types or bit depths.
+fn reduce(png: &Png, rgba: &[u16]) -> Vec<Png> {
+    let analysis = analyze(rgba);
+    let background = png.background_rgb16();
+    let template = Png {
+        width: png.width,
+        height: png.height,
+        ..Png::default()
+    };
+    let mut candidates = Vec::new();
+
+    if analysis.is_gray {
+        let grays = rgba.chunks_exact(4).map(|pixel| pixel[0]);
+        if analysis.is_opaque || analysis.color_key.is_some() {
+            let bit_depth = [1, 2, 4, 8, 16]
+                .into_iter()
+                .find(|&bit_depth| {
+                    let mut samples = grays.clone().chain(analysis.color_key.map(|key| key[0]));
+                    samples.all(|v| is_representable(v, bit_depth))
+                })
+                .unwrap();
+            let samples = grays.map(|v| downscale(v, bit_depth)).collect::<Vec<_>>();
+            candidates.push(Png {
+                bit_depth,
+                color_type: ColorType::Grayscale,
+                data: pack_samples(&samples, png.width, 1, bit_depth),
+                transparency: analysis
+                    .color_key
+                    .map(|key| Transparency::Gray(downscale(key[0], bit_depth))),
+                background: background
+                    .filter(|&[r, g, b]| r == g && g == b && is_representable(r, bit_depth))
+                    .map(|[r, _, _]| Background::Gray(downscale(r, bit_depth))),
+                ..template.clone()
+            });
+        } else {
+            let bit_depth = if analysis.is_8bit { 8 } else { 16 };
+            let samples = rgba
+                .chunks_exact(4)
+                .flat_map(|pixel| [pixel[0], pixel[3]])
+                .map(|v| downscale(v, bit_depth))
+                .collect::<Vec<_>>();
+            candidates.push(Png {
+                bit_depth,
+                color_type: ColorType::GrayscaleAlpha,
+                data: pack_samples(&samples, png.width, 2, bit_depth),
+                background: background
+                    .filter(|&[r, g, b]| r == g && g == b && is_representable(r, bit_depth))
+                    .map(|[r, _, _]| Background::Gray(downscale(r, bit_depth))),
+                ..template.clone()
+            });
+        }
+    }
+
+    let bit_depth = if analysis.is_8bit { 8 } else { 16 };
+    let rgb_background = background
+        .filter(|rgb| rgb.iter().all(|&v| is_representable(v, bit_depth)))
+        .map(|[r, g, b]| {
+            Background::Rgb(
+                downscale(r, bit_depth),
+                downscale(g, bit_depth),
+                downscale(b, bit_depth),
+            )
+        });
+    if analysis.is_opaque || analysis.color_key.is_some() {
+        let samples = rgba
+            .chunks_exact(4)
+            .flat_map(|pixel| &pixel[..3])
+            .map(|&v| downscale(v, bit_depth))
+            .collect::<Vec<_>>();
+        candidates.push(Png {
+            bit_depth,
+            color_type: ColorType::Truecolor,
+            data: pack_samples(&samples, png.width, 3, bit_depth),
+            transparency: analysis.color_key.map(|[r, g, b]| {
+                Transparency::Rgb(
+                    downscale(r, bit_depth),
+                    downscale(g, bit_depth),
+                    downscale(b, bit_depth),
+                )
+            }),
+            background: rgb_background,
+            ..template.clone()
+        });
+    } else {
+        let samples = rgba
+            .iter()
+            .map(|&v| downscale(v, bit_depth))
+            .collect::<Vec<_>>();
+        candidates.push(Png {
+            bit_depth,
+            color_type: ColorType::TruecolorAlpha,
+            data: pack_samples(&samples, png.width, 4, bit_depth),
+            background: rgb_background,
+            ..template.clone()
+        });
+    }
+
+    if let Some(mut colors) = analysis.colors.filter(|_| analysis.is_8bit) {
+        // Put translucent entries first so that the tRNS chunk stays short.
+        let mut entries = colors.keys().copied().collect::<Vec<_>>();
+        entries.sort_by_key(|pixel| pixel[3] == 65535);
+        for (index, pixel) in entries.iter().enumerate() {
+            colors.insert(*pixel, index as u8);
+        }
+        let bit_depth = [1, 2, 4, 8]
+            .into_iter()
+            .find(|&bit_depth| entries.len() <= 1 << bit_depth)
+            .unwrap();
+        let samples = rgba
+            .chunks_exact(4)
+            .map(|pixel| colors[pixel] as u16)
+            .collect::<Vec<_>>();
+        let alpha = entries
+            .iter()
+            .take_while(|pixel| pixel[3] != 65535)
+            .map(|pixel| (pixel[3] / 257) as u8)
+            .collect::<Vec<_>>();
+        candidates.push(Png {
+            bit_depth,
+            color_type: ColorType::Indexed,
+            data: pack_samples(&samples, png.width, 1, bit_depth),
+            palette: entries
+                .iter()
+                .map(|pixel| [pixel[0], pixel[1], pixel[2]].map(|v| (v / 257) as u8))
+                .collect(),
+            transparency: (!alpha.is_empty()).then_some(Transparency::Palette(alpha)),
+            background: background.and_then(|rgb| {
+                entries
+                    .iter()
+                    .position(|pixel| pixel[..3] == rgb)
+                    .map(|index| Background::Palette(index as u8))
+            }),
+            ..template
+        });
+    }
+
+    candidates
+}
+
+fn is_representable(value: u16, bit_depth: u8) -> bool {
+    (value as u32).is_multiple_of(65535 / ((1 << bit_depth) - 1))
+}
+
+fn downscale(value: u16, bit_depth: u8) -> u16 {
+    (value as u32 / (65535 / ((1 << bit_depth) - 1))) as u16
+}
+
+/// Pack samples into scanlines of the given bit depth.
+pub(crate) fn pack_samples(
+    samples: &[u16],
+    width: usize,
+    channels: usize,
+    bit_depth: u8,
+) -> Box<[u8]> {
+    let byte_per_line = (width * channels * bit_depth as usize).div_ceil(8);
+    let mut data = Vec::with_capacity(samples.len() * bit_depth as usize / 8);
+    for line in samples.chunks(width * channels) {
+        match bit_depth {
+            16 => line
+                .iter()
+                .for_each(|v| data.extend_from_slice(&v.to_be_bytes())),
+            8 => data.extend(line.iter().map(|&v| v as u8)),
+            _ => {
+                let start = data.len();
+                data.resize(start + byte_per_line, 0);
+                for (i, &v) in line.iter().enumerate() {
+                    let bit_offset = i * bit_depth as usize;
+                    let shift = 8 - bit_depth as usize - (bit_offset & 0b111);
+                    data[start + (bit_offset >> 3)] |= (v as u8) << shift;
+                }
+            }
+        }
+    }
+    data.into_boxed_slice()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimize_and_decode(png: &Png) -> Result<Png, Error> {
+        let optimized = optimize(png, &OptimizeOptions::default())?;
+        Png::from_bytes(&optimized)
+    }
+
+    #[test]
+    fn test_reduce_to_grayscale() -> Result<(), Error> {
+        let png = Png {
+            width: 4,
+            height: 1,
+            color_type: ColorType::TruecolorAlpha,
+            data: Box::new([
+                0, 0, 0, 255, 255, 255, 255, 255, 0, 0, 0, 255, 255, 255, 255, 255,
+            ]),
+            ..Png::default()
+        };
+
+        let decoded = optimize_and_decode(&png)?;
+        assert_eq!(decoded.color_type, ColorType::Grayscale);
+        assert_eq!(decoded.bit_depth, 1);
+        assert_eq!(decoded.to_rgba8(), png.to_rgba8());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_rgb_profile() -> Result<(), Error> {
+        let png = Png {
+            width: 2,
+            height: 1,
+            color_type: ColorType::Truecolor,
+            data: Box::new([0, 0, 0, 255, 255, 255]),
+            color: ColorInfo {
+                icc_profile: Some(crate::color::IccProfile {
+                    name: "RGB".to_string(),
+                    profile: vec![1, 2, 3, 4],
+                }),
+                ..ColorInfo::default()
+            },
+            ..Png::default()
+        };
+
+        let decoded = optimize_and_decode(&png)?;
+        assert!(!is_gray(decoded.color_type));
+        assert_eq!(decoded.color.icc_profile, png.color.icc_profile);
+        assert_eq!(decoded.to_rgba8(), png.to_rgba8());
+
+        let options = OptimizeOptions {
+            metadata: MetadataPolicy::Strip,
+            ..OptimizeOptions::default()
+        };
+        let decoded = Png::from_bytes(&optimize(&png, &options)?)?;
+        assert_eq!(decoded.color_type, ColorType::Grayscale);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reduce_to_palette() -> Result<(), Error> {
+        let mut data = Vec::new();
+        let colors = [
+            [255, 0, 0, 255],
+            [0, 255, 0, 255],
+            [0, 0, 0, 255],
+            [0, 0, 255, 128],
+        ];
+        let mut seed = 1u32;
+        for _ in 0..32 * 32 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            data.extend_from_slice(&colors[(seed >> 16) as usize % colors.len()]);
+        }
+        let png = Png {
+            width: 32,
+            height: 32,
+            color_type: ColorType::TruecolorAlpha,
+            data: data.into_boxed_slice(),
+            background: Some(Background::Rgb(0, 0, 0)),
+            ..Png::default()
+        };
+
+        let decoded = optimize_and_decode(&png)?;
+        assert_eq!(decoded.color_type, ColorType::Indexed);
+        assert_eq!(decoded.to_rgba8(), png.to_rgba8());
+        assert_eq!(decoded.background_rgb8(), Some([0, 0, 0]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_metadata() -> Result<(), Error> {
+        let mut png = Png::open("../../resources/sample_800x600.png")?;
+        png.color.gamma = Some(45455);
+        png.text.push(crate::text::Text::Latin1 {
+            keyword: "Comment".to_string(),
+            text: "test".to_string(),
+        });
+
+        let options = OptimizeOptions {
+            filters: vec![FilterStrategy::Adaptive],
+            levels: vec![6],
+            metadata: MetadataPolicy::KeepColor,
+        };
+        let decoded = Png::from_bytes(&optimize(&png, &options)?)?;
+        assert!(decoded.text.is_empty());
+        assert_eq!(decoded.color.gamma, Some(45455));
+        assert_eq!(decoded.to_rgba16(), png.to_rgba16());
+
+        Ok(())
+    }
+}
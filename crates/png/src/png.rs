@@ -1,154 +1,1001 @@
+use crate::color::{gamma_table, ColorInfo, TransferFunction};
+use crate::crc::update_crc32;
+use crate::metadata::Metadata;
+use crate::text::Text;
+use crate::transparency::{Background, Transparency};
+use binary::bytes::{ByteReader, ByteWriter, FromBytes, ToBytes};
 use std::fs::File;
 use std::io::Error;
-use std::io::ErrorKind::InvalidData;
-use std::mem::{size_of, transmute};
-use std::os::unix::fs::FileExt;
+use std::io::ErrorKind::{InvalidData, InvalidInput, UnexpectedEof, Unsupported};
+use std::io::{BufReader, Read, Write};
 
-#[derive(Default)]
+pub(crate) const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+#[derive(Clone)]
 pub struct Png {
     pub width: usize,
     pub height: usize,
+    pub bit_depth: u8,
+    pub color_type: ColorType,
+    /// Unfiltered scanlines without the filter type bytes.
+    /// Samples narrower than a byte are packed and 16-bit samples are big-endian.
     pub data: Box<[u8]>,
+    pub palette: Vec<[u8; 3]>,
+    pub transparency: Option<Transparency>,
+    pub background: Option<Background>,
+    pub text: Vec<Text>,
+    pub color: ColorInfo,
+    pub metadata: Metadata,
+    /// Store the image data with Adam7 interlacing. `data` itself is never interlaced.
+    pub interlaced: bool,
+}
+
+impl Default for Png {
+    fn default() -> Png {
+        Png {
+            width: 0,
+            height: 0,
+            bit_depth: 8,
+            color_type: ColorType::Truecolor,
+            data: Box::new([]),
+            palette: Vec::new(),
+            transparency: None,
+            background: None,
+            text: Vec::new(),
+            color: ColorInfo::default(),
+            metadata: Metadata::default(),
+            interlaced: false,
+        }
+    }
+}
+
+/// https://www.w3.org/TR/png/#6Colour-values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorType {
+    Grayscale = 0,
+    Truecolor = 2,
+    Indexed = 3,
+    GrayscaleAlpha = 4,
+    TruecolorAlpha = 6,
+}
+
+impl ColorType {
+    pub fn from_u8(value: u8) -> Result<ColorType, Error> {
+        match value {
+            0 => Ok(ColorType::Grayscale),
+            2 => Ok(ColorType::Truecolor),
+            3 => Ok(ColorType::Indexed),
+            4 => Ok(ColorType::GrayscaleAlpha),
+            6 => Ok(ColorType::TruecolorAlpha),
+            _ => Err(Error::new(InvalidData, "Unknown color type")),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match self {
+            ColorType::Grayscale | ColorType::Indexed => 1,
+            ColorType::GrayscaleAlpha => 2,
+            ColorType::Truecolor => 3,
+            ColorType::TruecolorAlpha => 4,
+        }
+    }
+
+    /// https://www.w3.org/TR/png/#table111
+    pub fn is_valid_bit_depth(&self, bit_depth: u8) -> bool {
+        match self {
+            ColorType::Grayscale => matches!(bit_depth, 1 | 2 | 4 | 8 | 16),
+            ColorType::Indexed => matches!(bit_depth, 1 | 2 | 4 | 8),
+            _ => matches!(bit_depth, 8 | 16),
+        }
+    }
 }
 
 /// https://www.w3.org/TR/png/#5Chunk-layout
 pub struct Chunk {
-    length: u32,
-    chunk_type: [u8; 4],
-    data: Vec<u8>,
+    pub length: u32,
+    pub chunk_type: [u8; 4],
+    pub data: Vec<u8>,
+    /// CRC stored in the file. Chunks are always written with a recomputed CRC.
+    pub crc: u32,
 }
 
 impl Chunk {
-    fn read(file: &File, offset: u64) -> Result<Chunk, Error> {
-        let mut buf4 = [0u8; 4];
+    pub fn new(chunk_type: [u8; 4], data: Vec<u8>) -> Chunk {
+        let mut chunk = Chunk {
+            length: data.len() as u32,
+            chunk_type,
+            data,
+            crc: 0,
+        };
+        chunk.crc = chunk.compute_crc();
+        chunk
+    }
 
-        file.read_at(&mut buf4, offset)?;
-        let length = u32::from_be_bytes(buf4);
+    /// Read a chunk from the current position of `reader`.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Chunk, Error> {
+        let (length, chunk_type) = read_chunk_header(reader)?;
 
-        let mut chunk_type = [0u8; 4];
-        file.read_at(&mut chunk_type, offset + 4)?;
+        let mut data = Vec::new();
+        reader.take(length as u64).read_to_end(&mut data)?;
+        if data.len() != length as usize {
+            return Err(Error::new(UnexpectedEof, "Unexpected end of file"));
+        }
 
-        let mut data = vec![0u8; length as usize];
-        file.read_at(&mut data, offset + 8)?;
+        let mut crc = [0u8; 4];
+        reader.read_exact(&mut crc)?;
 
         Ok(Chunk {
             length,
             chunk_type,
             data,
+            crc: u32::from_be_bytes(crc),
         })
     }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.length.to_be_bytes());
+        out.extend_from_slice(&self.chunk_type);
+        out.extend_from_slice(&self.data);
+        out.extend_from_slice(&self.compute_crc().to_be_bytes());
+    }
+
+    /// CRC of the chunk type and data.
+    pub fn compute_crc(&self) -> u32 {
+        update_crc32(update_crc32(0xffffffff, &self.chunk_type), &self.data) ^ 0xffffffff
+    }
+
+    pub fn is_crc_valid(&self) -> bool {
+        self.crc == self.compute_crc()
+    }
+
+    /// https://www.w3.org/TR/png/#5Chunk-naming-conventions
+    pub fn is_critical(&self) -> bool {
+        self.chunk_type[0] & 0x20 == 0
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.chunk_type[1] & 0x20 == 0
+    }
+
+    pub fn is_safe_to_copy(&self) -> bool {
+        self.chunk_type[3] & 0x20 != 0
+    }
 }
 
 /// https://www.w3.org/TR/png/#11IHDR
-#[repr(C, packed)]
 pub struct IHDRChunk {
-    width: u32,
-    height: u32,
-    bit_depth: u8,
-    color_type: u8,
-    compression_method: u8,
-    filter_method: u8,
-    interlace_method: u8,
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub color_type: u8,
+    pub compression_method: u8,
+    pub filter_method: u8,
+    pub interlace_method: u8,
+}
+
+impl IHDRChunk {
+    pub const SIZE: usize = 13;
+
+    pub fn from_chunk(chunk: &Chunk) -> Result<IHDRChunk, Error> {
+        if chunk.data.len() != IHDRChunk::SIZE {
+            return Err(Error::new(InvalidData, "Invalid IHDR chunk"));
+        }
+        IHDRChunk::from_bytes(&chunk.data)
+    }
+}
+
+impl FromBytes for IHDRChunk {
+    fn read_from(reader: &mut ByteReader) -> Result<IHDRChunk, Error> {
+        Ok(IHDRChunk {
+            width: reader.u32_be()?,
+            height: reader.u32_be()?,
+            bit_depth: reader.u8()?,
+            color_type: reader.u8()?,
+            compression_method: reader.u8()?,
+            filter_method: reader.u8()?,
+            interlace_method: reader.u8()?,
+        })
+    }
+}
+
+impl ToBytes for IHDRChunk {
+    fn write_to(&self, writer: &mut ByteWriter) {
+        writer
+            .u32_be(self.width)
+            .u32_be(self.height)
+            .u8(self.bit_depth)
+            .u8(self.color_type)
+            .u8(self.compression_method)
+            .u8(self.filter_method)
+            .u8(self.interlace_method);
+    }
 }
 
 impl Png {
     pub fn open(path: impl AsRef<std::path::Path>) -> Result<Png, Error> {
-        Png::read(&File::open(path).unwrap())
+        Png::read(BufReader::new(File::open(path)?))
     }
 
-    pub fn read(file: &File) -> Result<Png, Error> {
-        let mut offset = 8;
+    pub fn read<R: Read>(reader: R) -> Result<Png, Error> {
+        Png::from_chunks(&read_chunks(reader)?)
+    }
 
-        // parse IHDR chunk
-        let image_header_chunk = Chunk::read(file, offset)?;
-        offset += image_header_chunk.length as u64 + 12;
+    pub fn from_bytes(bytes: &[u8]) -> Result<Png, Error> {
+        Png::read(bytes)
+    }
 
-        let mut buf = [0u8; size_of::<IHDRChunk>()];
-        buf.copy_from_slice(&image_header_chunk.data);
-        let image_header: IHDRChunk = unsafe { transmute(buf) };
-        let width = image_header.width.to_be() as usize;
-        let height = image_header.height.to_be() as usize;
-        let bit_depth = image_header.bit_depth as usize;
+    pub fn from_chunks(chunks: &[Chunk]) -> Result<Png, Error> {
+        let mut png = Png::from_header_chunks(chunks)?;
 
         // collect all IDAT chunks
-        let mut data_chunks = Vec::new();
-        loop {
-            let chunk = Chunk::read(file, offset)?;
-            offset += chunk.length as u64 + 12;
+        let mut zlib_compressed = Vec::new();
+        for chunk in chunks {
+            if chunk.chunk_type == [b'I', b'D', b'A', b'T'] {
+                zlib_compressed.extend_from_slice(&chunk.data);
+            }
+        }
+
+        png.data = decode_image_data(&zlib_compressed, &png)?;
+
+        Ok(png)
+    }
+
+    /// Parse the image header and the ancillary chunks, leaving `data` empty.
+    pub(crate) fn from_header_chunks(chunks: &[Chunk]) -> Result<Png, Error> {
+        // parse IHDR chunk
+        let (image_header_chunk, chunks) = match chunks.split_first() {
+            Some((chunk, rest)) if chunk.chunk_type == [b'I', b'H', b'D', b'R'] => (chunk, rest),
+            _ => return Err(Error::new(InvalidData, "Missing IHDR chunk")),
+        };
+
+        if !image_header_chunk.is_crc_valid() {
+            return Err(Error::new(InvalidData, "CRC mismatch in IHDR chunk"));
+        }
+        let image_header = IHDRChunk::from_chunk(image_header_chunk)?;
+        let width = image_header.width as usize;
+        let height = image_header.height as usize;
+        let bit_depth = image_header.bit_depth;
+        let color_type = ColorType::from_u8(image_header.color_type)?;
+        if !color_type.is_valid_bit_depth(bit_depth) {
+            return Err(Error::new(InvalidData, "Invalid bit depth"));
+        }
+        if image_header.compression_method != 0 || image_header.filter_method != 0 {
+            return Err(Error::new(
+                InvalidData,
+                "Unknown compression or filter method",
+            ));
+        }
+        if image_header.interlace_method > 1 {
+            return Err(Error::new(InvalidData, "Unknown interlace method"));
+        }
+
+        let mut palette = Vec::new();
+        let mut transparency = None;
+        let mut background = None;
+        let mut text = Vec::new();
+        let mut color = ColorInfo::default();
+        let mut metadata = Metadata::default();
+        for chunk in chunks {
+            if !chunk.is_crc_valid() {
+                if chunk.is_critical() {
+                    return Err(Error::new(
+                        InvalidData,
+                        format!(
+                            "CRC mismatch in {} chunk",
+                            String::from_utf8_lossy(&chunk.chunk_type)
+                        ),
+                    ));
+                }
+                // corrupted ancillary chunks are ignored
+                continue;
+            }
             match chunk.chunk_type {
                 [b'I', b'E', b'N', b'D'] => break,
-                [b'I', b'D', b'A', b'T'] => {
-                    data_chunks.push(chunk.data);
+                [b'I', b'D', b'A', b'T'] => (),
+                [b'I', b'H', b'D', b'R'] => {
+                    return Err(Error::new(InvalidData, "Multiple IHDR chunks"));
+                }
+                [b'P', b'L', b'T', b'E'] => {
+                    if chunk.data.len() % 3 != 0 || chunk.data.len() > 256 * 3 {
+                        return Err(Error::new(InvalidData, "Invalid PLTE chunk"));
+                    }
+                    palette = chunk.data.chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+                }
+                [b't', b'R', b'N', b'S'] => {
+                    transparency = Some(Transparency::read(chunk, color_type, palette.len())?);
+                }
+                [b'b', b'K', b'G', b'D'] => {
+                    // malformed ancillary chunks are ignored like corrupted ones
+                    if let Ok(chunk) = Background::read(chunk, color_type) {
+                        background = Some(chunk);
+                    }
+                }
+                [b't', b'E', b'X', b't'] | [b'z', b'T', b'X', b't'] | [b'i', b'T', b'X', b't'] => {
+                    // malformed ancillary chunks are ignored like corrupted ones
+                    if let Ok(chunk) = Text::read(chunk) {
+                        text.push(chunk);
+                    }
+                }
+                [b'g', b'A', b'M', b'A']
+                | [b'c', b'H', b'R', b'M']
+                | [b's', b'R', b'G', b'B']
+                | [b'i', b'C', b'C', b'P']
+                | [b'c', b'I', b'C', b'P']
+                | [b'm', b'D', b'C', b'v']
+                | [b'c', b'L', b'L', b'i'] => {
+                    // malformed ancillary chunks are ignored like corrupted ones
+                    let _ = color.read(chunk);
+                }
+                [b'p', b'H', b'Y', b's']
+                | [b't', b'I', b'M', b'E']
+                | [b's', b'B', b'I', b'T']
+                | [b'h', b'I', b'S', b'T']
+                | [b's', b'P', b'L', b'T']
+                | [b'o', b'F', b'F', b's']
+                | [b'e', b'X', b'I', b'f'] => {
+                    // malformed ancillary chunks are ignored like corrupted ones
+                    let _ = metadata.read(chunk, color_type);
+                }
+                _ if chunk.is_critical() => {
+                    return Err(Error::new(
+                        Unsupported,
+                        format!(
+                            "Unknown critical chunk {}",
+                            String::from_utf8_lossy(&chunk.chunk_type)
+                        ),
+                    ));
                 }
                 _ => (),
             };
         }
-
-        // decode image data
-        let mut zlib_compressed = Vec::new();
-        for chunk in data_chunks {
-            zlib_compressed.extend(chunk);
+        if color_type == ColorType::Indexed && palette.is_empty() {
+            return Err(Error::new(InvalidData, "Missing PLTE chunk"));
         }
-        let data = decode_image_data(zlib_compressed, width, height, bit_depth)?;
 
         Ok(Png {
             width,
             height,
-            data,
+            bit_depth,
+            color_type,
+            data: Box::new([]),
+            palette,
+            transparency,
+            background,
+            text,
+            color,
+            metadata,
+            interlaced: image_header.interlace_method == 1,
         })
     }
+
+    pub fn bits_per_pixel(&self) -> usize {
+        self.color_type.channels() * self.bit_depth as usize
+    }
+
+    /// Bytes per complete pixel used by the filters, rounding up to one byte.
+    pub fn byte_per_pixel(&self) -> usize {
+        self.bits_per_pixel().div_ceil(8)
+    }
+
+    pub fn byte_per_line(&self) -> usize {
+        (self.width * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// Bytes of each scanline in the image data, excluding the filter type byte.
+    ///
+    /// Interlaced images list the scanlines of the Adam7 passes in order, skipping empty passes.
+    pub fn scanline_lengths(&self) -> Vec<usize> {
+        if !self.interlaced {
+            return vec![self.byte_per_line(); self.height];
+        }
+        let mut lengths = Vec::new();
+        for pass in ADAM7 {
+            let (width, height) = pass.size(self.width, self.height);
+            let pass_byte_per_line = (width * self.bits_per_pixel()).div_ceil(8);
+            lengths.extend(std::iter::repeat_n(pass_byte_per_line, height));
+        }
+        lengths
+    }
+
+    /// Read the raw value of a sample without scaling.
+    pub fn sample(&self, x: usize, y: usize, channel: usize) -> u16 {
+        let line = &self.data[y * self.byte_per_line()..];
+        let index = x * self.color_type.channels() + channel;
+        match self.bit_depth {
+            16 => u16::from_be_bytes([line[index * 2], line[index * 2 + 1]]),
+            8 => line[index] as u16,
+            bit_depth => {
+                let bit_offset = index * bit_depth as usize;
+                let shift = 8 - bit_depth as usize - (bit_offset & 0b111);
+                ((line[bit_offset >> 3] >> shift) & ((1 << bit_depth) - 1)) as u16
+            }
+        }
+    }
+
+    /// Scale a raw sample value to 16 bits.
+    fn scale_to_u16(&self, value: u16) -> u16 {
+        match self.bit_depth {
+            16 => value,
+            bit_depth => (value as u32 * 65535 / ((1 << bit_depth) - 1)) as u16,
+        }
+    }
+
+    /// Convert the image into 16-bit RGBA, applying the tRNS chunk.
+    pub fn to_rgba16(&self) -> Box<[u16]> {
+        let mut rgba = Vec::with_capacity(self.width * self.height * 4);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let pixel = match self.color_type {
+                    ColorType::Grayscale => {
+                        let gray = self.sample(x, y, 0);
+                        let alpha = match self.transparency {
+                            Some(Transparency::Gray(key)) if key == gray => 0,
+                            _ => 65535,
+                        };
+                        let gray = self.scale_to_u16(gray);
+                        [gray, gray, gray, alpha]
+                    }
+                    ColorType::Truecolor => {
+                        let (r, g, b) = (
+                            self.sample(x, y, 0),
+                            self.sample(x, y, 1),
+                            self.sample(x, y, 2),
+                        );
+                        let alpha = match self.transparency {
+                            Some(Transparency::Rgb(kr, kg, kb)) if (kr, kg, kb) == (r, g, b) => 0,
+                            _ => 65535,
+                        };
+                        [
+                            self.scale_to_u16(r),
+                            self.scale_to_u16(g),
+                            self.scale_to_u16(b),
+                            alpha,
+                        ]
+                    }
+                    ColorType::Indexed => {
+                        let index = self.sample(x, y, 0) as usize;
+                        let [r, g, b] = self.palette.get(index).copied().unwrap_or([0, 0, 0]);
+                        let alpha = match &self.transparency {
+                            Some(Transparency::Palette(alpha)) => {
+                                alpha.get(index).copied().unwrap_or(255)
+                            }
+                            _ => 255,
+                        };
+                        [r, g, b, alpha].map(|v| v as u16 * 257)
+                    }
+                    ColorType::GrayscaleAlpha => {
+                        let gray = self.scale_to_u16(self.sample(x, y, 0));
+                        [gray, gray, gray, self.scale_to_u16(self.sample(x, y, 1))]
+                    }
+                    ColorType::TruecolorAlpha => [
+                        self.scale_to_u16(self.sample(x, y, 0)),
+                        self.scale_to_u16(self.sample(x, y, 1)),
+                        self.scale_to_u16(self.sample(x, y, 2)),
+                        self.scale_to_u16(self.sample(x, y, 3)),
+                    ],
+                };
+                rgba.extend_from_slice(&pixel);
+            }
+        }
+        rgba.into_boxed_slice()
+    }
+
+    /// Convert the image into 8-bit RGBA, applying the tRNS chunk.
+    pub fn to_rgba8(&self) -> Box<[u8]> {
+        self.to_rgba16().iter().map(|&v| (v >> 8) as u8).collect()
+    }
+
+    /// The bKGD color scaled to 16-bit RGB.
+    pub fn background_rgb16(&self) -> Option<[u16; 3]> {
+        match self.background? {
+            Background::Palette(index) => self
+                .palette
+                .get(index as usize)
+                .map(|rgb| rgb.map(|v| v as u16 * 257)),
+            Background::Gray(gray) => {
+                let gray = self.scale_to_u16(gray);
+                Some([gray, gray, gray])
+            }
+            Background::Rgb(r, g, b) => Some([
+                self.scale_to_u16(r),
+                self.scale_to_u16(g),
+                self.scale_to_u16(b),
+            ]),
+        }
+    }
+
+    /// The bKGD color scaled to 8-bit RGB.
+    pub fn background_rgb8(&self) -> Option<[u8; 3]> {
+        self.background_rgb16()
+            .map(|rgb| rgb.map(|v| (v >> 8) as u8))
+    }
+
+    /// Composite the image onto `background`, or the bKGD color if not given, into 8-bit RGB.
+    ///
+    /// Blending is done in linear light using the transfer function of the image.
+    pub fn composite(&self, background: Option<[u8; 3]>) -> Result<Box<[u8]>, Error> {
+        let background = background
+            .or_else(|| self.background_rgb8())
+            .ok_or_else(|| Error::new(InvalidInput, "No background color"))?;
+
+        let transfer_function = self.color.transfer_function()?;
+        let mut to_linear = [0f64; 256];
+        for (i, value) in to_linear.iter_mut().enumerate() {
+            *value = transfer_function.to_linear(i as f64 / 255.0);
+        }
+
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+        for pixel in self.to_rgba8().chunks(4) {
+            let alpha = pixel[3] as f64 / 255.0;
+            for i in 0..3 {
+                let linear = to_linear[pixel[i] as usize] * alpha
+                    + to_linear[background[i] as usize] * (1.0 - alpha);
+                let value = transfer_function.from_linear(linear).clamp(0.0, 1.0);
+                rgb.push((value * 255.0).round() as u8);
+            }
+        }
+        Ok(rgb.into_boxed_slice())
+    }
+
+    /// Convert the image into 8-bit RGBA and its color samples from the transfer function
+    /// described by the color chunks to `target`. Alpha is left as is.
+    pub fn gamma_correct(&self, target: TransferFunction) -> Result<Box<[u8]>, Error> {
+        let table = gamma_table(self.color.transfer_function()?, target);
+        let mut rgba = self.to_rgba8();
+        for pixel in rgba.chunks_mut(4) {
+            for value in &mut pixel[..3] {
+                *value = table[*value as usize];
+            }
+        }
+        Ok(rgba)
+    }
+
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+        let mut out = Vec::new();
+        self.write(&mut out)?;
+        File::create(path)?.write_all(&out)
+    }
+
+    /// Encode the image with the default options.
+    pub fn write(&self, out: &mut Vec<u8>) -> Result<(), Error> {
+        self.write_with_options(out, &EncodeOptions::default())
+    }
+
+    pub fn write_with_options(
+        &self,
+        out: &mut Vec<u8>,
+        options: &EncodeOptions,
+    ) -> Result<(), Error> {
+        if self.data.len() != self.height * self.byte_per_line() {
+            return Err(Error::new(InvalidInput, "Data size does not match"));
+        }
+
+        self.write_header_chunks(out)?;
+
+        Chunk::new([b'I', b'D', b'A', b'T'], self.encode_image_data(options)).write(out);
+
+        Chunk::new([b'I', b'E', b'N', b'D'], Vec::new()).write(out);
+
+        Ok(())
+    }
+
+    /// Write the signature and all chunks before IDAT.
+    pub(crate) fn write_header_chunks(&self, out: &mut Vec<u8>) -> Result<(), Error> {
+        if !self.color_type.is_valid_bit_depth(self.bit_depth) {
+            return Err(Error::new(InvalidInput, "Invalid bit depth"));
+        }
+
+        out.extend_from_slice(&SIGNATURE);
+        self.image_header_chunk().write(out);
+
+        for chunk in self.color.to_chunks()? {
+            chunk.write(out);
+        }
+        for chunk in self.metadata.to_chunks_before_palette() {
+            chunk.write(out);
+        }
+
+        if !self.palette.is_empty() {
+            Chunk::new([b'P', b'L', b'T', b'E'], self.palette.concat()).write(out);
+        }
+        if let Some(transparency) = &self.transparency {
+            transparency.to_chunk().write(out);
+        }
+        if let Some(background) = &self.background {
+            background.to_chunk().write(out);
+        }
+        for chunk in self.metadata.to_chunks_after_palette()? {
+            chunk.write(out);
+        }
+
+        for text in &self.text {
+            text.to_chunk()?.write(out);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn image_header_chunk(&self) -> Chunk {
+        let image_header = IHDRChunk {
+            width: self.width as u32,
+            height: self.height as u32,
+            bit_depth: self.bit_depth,
+            color_type: self.color_type as u8,
+            compression_method: 0,
+            filter_method: 0,
+            interlace_method: self.interlaced as u8,
+        };
+        Chunk::new([b'I', b'H', b'D', b'R'], image_header.to_bytes())
+    }
+
+    /// Filter and compress the image data for IDAT chunks.
+    pub(crate) fn encode_image_data(&self, options: &EncodeOptions) -> Vec<u8> {
+        let byte_per_line = self.byte_per_line();
+        let byte_per_pixel = self.byte_per_pixel();
+        let mut filtered = Vec::with_capacity(self.height * (1 + byte_per_line));
+        if !self.interlaced {
+            filter_image(
+                &self.data,
+                self.height,
+                byte_per_line,
+                byte_per_pixel,
+                options.filter,
+                &mut filtered,
+            );
+        } else {
+            let bits_per_pixel = self.bits_per_pixel();
+            for pass in ADAM7 {
+                let (width, height) = pass.size(self.width, self.height);
+                let pass_byte_per_line = (width * bits_per_pixel).div_ceil(8);
+                let mut data = vec![0u8; height * pass_byte_per_line];
+                for y in 0..height {
+                    let source = &self.data[pass.y(y) * byte_per_line..];
+                    let target = &mut data[y * pass_byte_per_line..];
+                    for x in 0..width {
+                        copy_pixel(source, pass.x(x), target, x, bits_per_pixel);
+                    }
+                }
+                filter_image(
+                    &data,
+                    height,
+                    pass_byte_per_line,
+                    byte_per_pixel,
+                    options.filter,
+                    &mut filtered,
+                );
+            }
+        }
+        zlib::zlib::deflate_with_level(&filtered, options.level)
+    }
+}
+
+/// How scanlines are filtered before compression.
+///
+/// https://www.w3.org/TR/png/#12Filter-selection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterStrategy {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    /// Choose the filter with the minimum sum of absolute differences for each line.
+    Adaptive,
+}
+
+impl FilterStrategy {
+    pub const ALL: [FilterStrategy; 6] = [
+        FilterStrategy::None,
+        FilterStrategy::Sub,
+        FilterStrategy::Up,
+        FilterStrategy::Average,
+        FilterStrategy::Paeth,
+        FilterStrategy::Adaptive,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeOptions {
+    pub filter: FilterStrategy,
+    /// zlib compression level from 0 to 9.
+    pub level: u8,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> EncodeOptions {
+        EncodeOptions {
+            filter: FilterStrategy::Adaptive,
+            level: 6,
+        }
+    }
+}
+
+/// Append a filter type byte and the filtered scanline to `out`.
+pub(crate) fn filter_line(
+    strategy: FilterStrategy,
+    line: &[u8],
+    previous: Option<&[u8]>,
+    byte_per_pixel: usize,
+    out: &mut Vec<u8>,
+) {
+    let filter_type = match strategy {
+        FilterStrategy::None => 0,
+        FilterStrategy::Sub => 1,
+        FilterStrategy::Up => 2,
+        FilterStrategy::Average => 3,
+        FilterStrategy::Paeth => 4,
+        FilterStrategy::Adaptive => {
+            let mut best = (u64::MAX, 0);
+            let mut candidate = Vec::with_capacity(line.len());
+            for filter_type in 0..5 {
+                candidate.clear();
+                apply_filter(filter_type, line, previous, byte_per_pixel, &mut candidate);
+                let sum = candidate
+                    .iter()
+                    .map(|&v| (v as i8).unsigned_abs() as u64)
+                    .sum::<u64>();
+                if sum < best.0 {
+                    best = (sum, filter_type);
+                }
+            }
+            best.1
+        }
+    };
+    out.push(filter_type);
+    apply_filter(filter_type, line, previous, byte_per_pixel, out);
+}
+
+fn apply_filter(
+    filter_type: u8,
+    line: &[u8],
+    previous: Option<&[u8]>,
+    byte_per_pixel: usize,
+    out: &mut Vec<u8>,
+) {
+    for i in 0..line.len() {
+        let a = if i < byte_per_pixel {
+            0
+        } else {
+            line[i - byte_per_pixel]
+        };
+        let b = previous.map_or(0, |previous| previous[i]);
+        let c = if i < byte_per_pixel {
+            0
+        } else {
+            previous.map_or(0, |previous| previous[i - byte_per_pixel])
+        };
+        let predictor = match filter_type {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth_predictor(a as u16, b as u16, c as u16) as u8,
+        };
+        out.push(line[i].wrapping_sub(predictor));
+    }
+}
+
+/// Read all chunks from the signature to IEND.
+pub fn read_chunks<R: Read>(mut reader: R) -> Result<Vec<Chunk>, Error> {
+    let mut signature = [0u8; 8];
+    reader.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Err(Error::new(InvalidData, "Not a PNG file"));
+    }
+
+    let mut chunks = Vec::new();
+    loop {
+        let chunk = Chunk::read(&mut reader)?;
+        let is_end = chunk.chunk_type == [b'I', b'E', b'N', b'D'];
+        chunks.push(chunk);
+        if is_end {
+            break;
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Read the length and the type of a chunk.
+pub(crate) fn read_chunk_header<R: Read>(reader: &mut R) -> Result<(u32, [u8; 4]), Error> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    Ok((
+        u32::from_be_bytes([header[0], header[1], header[2], header[3]]),
+        [header[4], header[5], header[6], header[7]],
+    ))
+}
+
+/// Inflate, unfilter and deinterlace the image data of `image`.
+pub(crate) fn decode_image_data(zlib_compressed: &[u8], image: &Png) -> Result<Box<[u8]>, Error> {
+    let inflated = zlib::zlib::inflate(zlib_compressed)?;
+    let byte_per_line = image.byte_per_line();
+    let byte_per_pixel = image.byte_per_pixel();
+    if !image.interlaced {
+        return unfilter_image(&inflated, image.height, byte_per_line, byte_per_pixel);
+    }
+
+    let bits_per_pixel = image.bits_per_pixel();
+    let mut data = vec![0u8; image.height * byte_per_line];
+    let mut offset = 0;
+    for pass in ADAM7 {
+        let (width, height) = pass.size(image.width, image.height);
+        let pass_byte_per_line = (width * bits_per_pixel).div_ceil(8);
+        let pass_data = unfilter_image(
+            &inflated[offset.min(inflated.len())..],
+            height,
+            pass_byte_per_line,
+            byte_per_pixel,
+        )?;
+        offset += height * (1 + pass_byte_per_line);
+        for y in 0..height {
+            let source = &pass_data[y * pass_byte_per_line..];
+            let target = &mut data[pass.y(y) * byte_per_line..];
+            for x in 0..width {
+                copy_pixel(source, x, target, pass.x(x), bits_per_pixel);
+            }
+        }
+    }
+
+    Ok(data.into_boxed_slice())
 }
 
-fn decode_image_data(
-    zlib_compressed: Vec<u8>,
-    width: usize,
+/// Unfilter `height` consecutive scanlines.
+fn unfilter_image(
+    inflated: &[u8],
     height: usize,
-    bit_depth: usize,
+    byte_per_line: usize,
+    byte_per_pixel: usize,
 ) -> Result<Box<[u8]>, Error> {
-    let inflated = zlib::zlib::inflate(&zlib_compressed[..])?;
-    let byte_per_pixel = bit_depth / 8 * 3; // TODO;
-    let byte_per_line = width * byte_per_pixel;
+    if inflated.len() < height * (1 + byte_per_line) {
+        return Err(Error::new(InvalidData, "Image data is too short"));
+    }
 
     let mut data = vec![0u8; height * byte_per_line];
     for y in 0..height {
-        let filter_type = inflated[y * (1 + byte_per_line)];
+        let filtered = &inflated[y * (1 + byte_per_line)..(y + 1) * (1 + byte_per_line)];
+        let (previous, current) = data.split_at_mut(y * byte_per_line);
+        let previous = if y == 0 {
+            None
+        } else {
+            Some(&previous[(y - 1) * byte_per_line..])
+        };
+        unfilter_line(
+            filtered[0],
+            &filtered[1..],
+            previous,
+            &mut current[..byte_per_line],
+            byte_per_pixel,
+        )?;
+    }
 
-        for x in 0..width {
-            for i in 0..3 {
-                let filt_x = inflated[y * (1 + byte_per_line) + 1 + x * byte_per_pixel + i];
-                let recon_a = if x == 0 {
-                    0u8
-                } else {
-                    data[y * byte_per_line + (x - 1) * byte_per_pixel + i]
-                };
-                let recon_b = if y == 0 {
-                    0u8
-                } else {
-                    data[(y - 1) * byte_per_line + x * byte_per_pixel + i]
-                };
-                let recon_c = if y == 0 || x == 0 {
-                    0u8
-                } else {
-                    data[(y - 1) * byte_per_line + (x - 1) * byte_per_pixel + i]
-                };
+    Ok(data.into_boxed_slice())
+}
 
-                data[y * byte_per_line + x * byte_per_pixel + i] = match filter_type {
-                    0 => filt_x,
-                    1 => ((filt_x as u16 + recon_a as u16) & 0xff) as u8,
-                    2 => ((filt_x as u16 + recon_b as u16) & 0xff) as u8,
-                    3 => ((filt_x as u16 + (recon_a as u16 + recon_b as u16) / 2) & 0xff) as u8,
-                    4 => {
-                        ((filt_x as u16
-                            + paeth_predictor(recon_a as u16, recon_b as u16, recon_c as u16))
-                            & 0xff) as u8
-                    }
-                    _ => return Err(Error::new(InvalidData, "Unknown filter type")),
-                };
-            }
+/// Filter `height` consecutive scanlines, each prefixed with its filter type.
+fn filter_image(
+    data: &[u8],
+    height: usize,
+    byte_per_line: usize,
+    byte_per_pixel: usize,
+    strategy: FilterStrategy,
+    out: &mut Vec<u8>,
+) {
+    if byte_per_line == 0 {
+        return;
+    }
+    let mut previous = None;
+    for line in data.chunks(byte_per_line).take(height) {
+        filter_line(strategy, line, previous, byte_per_pixel, out);
+        previous = Some(line);
+    }
+}
+
+/// A pass of Adam7 interlacing, selecting the pixels at `(x0 + i * dx, y0 + j * dy)`.
+///
+/// https://www.w3.org/TR/png/#8Interlace
+#[derive(Clone, Copy)]
+struct Pass {
+    x0: usize,
+    y0: usize,
+    dx: usize,
+    dy: usize,
+}
+
+const ADAM7: [Pass; 7] = [
+    Pass::new(0, 0, 8, 8),
+    Pass::new(4, 0, 8, 8),
+    Pass::new(0, 4, 4, 8),
+    Pass::new(2, 0, 4, 4),
+    Pass::new(0, 2, 2, 4),
+    Pass::new(1, 0, 2, 2),
+    Pass::new(0, 1, 1, 2),
+];
+
+impl Pass {
+    const fn new(x0: usize, y0: usize, dx: usize, dy: usize) -> Pass {
+        Pass { x0, y0, dx, dy }
+    }
+
+    /// Width and height of the reduced image. Either may be zero.
+    fn size(&self, width: usize, height: usize) -> (usize, usize) {
+        let width = width.saturating_sub(self.x0).div_ceil(self.dx);
+        let height = height.saturating_sub(self.y0).div_ceil(self.dy);
+        if width == 0 || height == 0 {
+            (0, 0)
+        } else {
+            (width, height)
         }
     }
 
-    Ok(data.into_boxed_slice())
+    fn x(&self, x: usize) -> usize {
+        self.x0 + x * self.dx
+    }
+
+    fn y(&self, y: usize) -> usize {
+        self.y0 + y * self.dy
+    }
+}
+
+/// Copy a pixel between scanlines. Pixels narrower than a byte are ORed into `target`.
+fn copy_pixel(
+    source: &[u8],
+    source_x: usize,
+    target: &mut [u8],
+    target_x: usize,
+    bits_per_pixel: usize,
+) {
+    if bits_per_pixel >= 8 {
+        let n = bits_per_pixel / 8;
+        target[target_x * n..(target_x + 1) * n]
+            .copy_from_slice(&source[source_x * n..(source_x + 1) * n]);
+    } else {
+        let source_bit = source_x * bits_per_pixel;
+        let target_bit = target_x * bits_per_pixel;
+        let mask = (1u8 << bits_per_pixel) - 1;
+        let value = (source[source_bit >> 3] >> (8 - bits_per_pixel - (source_bit & 7))) & mask;
+        target[target_bit >> 3] |= value << (8 - bits_per_pixel - (target_bit & 7));
+    }
+}
+
+/// Reconstruct a scanline from its filtered bytes.
+///
+/// https://www.w3.org/TR/png/#9Filter-types
+pub(crate) fn unfilter_line(
+    filter_type: u8,
+    filtered: &[u8],
+    previous: Option<&[u8]>,
+    line: &mut [u8],
+    byte_per_pixel: usize,
+) -> Result<(), Error> {
+    for i in 0..line.len() {
+        let filt_x = filtered[i];
+        let recon_a = if i < byte_per_pixel {
+            0u8
+        } else {
+            line[i - byte_per_pixel]
+        };
+        let recon_b = previous.map_or(0u8, |previous| previous[i]);
+        let recon_c = if i < byte_per_pixel {
+            0u8
+        } else {
+            previous.map_or(0u8, |previous| previous[i - byte_per_pixel])
+        };
+
+        line[i] = match filter_type {
+            0 => filt_x,
+            1 => ((filt_x as u16 + recon_a as u16) & 0xff) as u8,
+            2 => ((filt_x as u16 + recon_b as u16) & 0xff) as u8,
+            3 => ((filt_x as u16 + (recon_a as u16 + recon_b as u16) / 2) & 0xff) as u8,
+            4 => {
+                ((filt_x as u16 + paeth_predictor(recon_a as u16, recon_b as u16, recon_c as u16))
+                    & 0xff) as u8
+            }
+            _ => return Err(Error::new(InvalidData, "Unknown filter type")),
+        };
+    }
+
+    Ok(())
 }
 
 fn paeth_predictor(a: u16, b: u16, c: u16) -> u16 {
@@ -165,3 +1012,139 @@ fn paeth_predictor(a: u16, b: u16, c: u16) -> u16 {
         c
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(png: &Png, name: &str) -> Result<Png, Error> {
+        let path = std::env::temp_dir().join(name);
+        png.save(&path)?;
+        Png::open(&path)
+    }
+
+    #[test]
+    fn test_write_round_trip() -> Result<(), Error> {
+        let mut png = Png::open("../../resources/sample_800x600.png")?;
+        png.text.push(Text::Compressed {
+            keyword: "Source".to_string(),
+            text: "https://samplelib.com/sample-png.html".to_string(),
+        });
+        png.color.gamma = Some(45455);
+
+        let decoded = round_trip(&png, "png_test_write_round_trip.png")?;
+
+        assert_eq!(decoded.width, png.width);
+        assert_eq!(decoded.height, png.height);
+        assert_eq!(decoded.data, png.data);
+        assert_eq!(decoded.text, png.text);
+        assert_eq!(decoded.color, png.color);
+
+        Ok(())
+    }
+
+    /// Encode `png` with `chunk` inserted right after IHDR.
+    fn with_chunk(png: &Png, chunk: Chunk) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        png.write(&mut out)?;
+        let mut inserted = Vec::new();
+        chunk.write(&mut inserted);
+        out.splice(33..33, inserted);
+        Ok(out)
+    }
+
+    #[test]
+    fn test_malformed_ancillary_chunks() -> Result<(), Error> {
+        let png = Png {
+            width: 1,
+            height: 1,
+            color_type: ColorType::Grayscale,
+            data: Box::new([128]),
+            ..Png::default()
+        };
+        let chunks = [
+            // broken zlib stream
+            Chunk::new([b'z', b'T', b'X', b't'], b"Comment\0\0broken".to_vec()),
+            // keyword without a null separator
+            Chunk::new([b't', b'E', b'X', b't'], vec![b'a'; 100]),
+            // broken profile
+            Chunk::new([b'i', b'C', b'C', b'P'], b"sRGB\0\0broken".to_vec()),
+            Chunk::new([b'g', b'A', b'M', b'A'], vec![0; 3]),
+            // too long chunks are not partially read
+            Chunk::new([b'g', b'A', b'M', b'A'], vec![0; 5]),
+            Chunk::new([b'b', b'K', b'G', b'D'], vec![0; 6]),
+            Chunk::new([b'p', b'H', b'Y', b's'], vec![0; 8]),
+            Chunk::new([b't', b'I', b'M', b'E'], vec![0; 6]),
+            Chunk::new([b'o', b'F', b'F', b's'], vec![0; 10]),
+        ];
+        for chunk in chunks {
+            let decoded = Png::from_bytes(&with_chunk(&png, chunk)?)?;
+            assert_eq!(decoded.data, png.data);
+            assert!(decoded.text.is_empty());
+            assert_eq!(decoded.color, ColorInfo::default());
+            assert_eq!(decoded.background, None);
+            assert_eq!(decoded.metadata, Metadata::default());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_grayscale_transparency() -> Result<(), Error> {
+        let png = Png {
+            width: 3,
+            height: 1,
+            bit_depth: 2,
+            color_type: ColorType::Grayscale,
+            data: Box::new([0b00_01_11_00]),
+            transparency: Some(Transparency::Gray(1)),
+            background: Some(Background::Gray(3)),
+            ..Png::default()
+        };
+
+        let decoded = round_trip(&png, "png_test_grayscale_transparency.png")?;
+        assert_eq!(
+            &decoded.to_rgba8()[..],
+            &[0, 0, 0, 255, 85, 85, 85, 0, 255, 255, 255, 255]
+        );
+        assert_eq!(
+            &decoded.composite(None)?[..],
+            &[0, 0, 0, 255, 255, 255, 255, 255, 255]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_indexed_transparency() -> Result<(), Error> {
+        let png = Png {
+            width: 2,
+            height: 2,
+            bit_depth: 4,
+            color_type: ColorType::Indexed,
+            data: Box::new([0x01, 0x10]),
+            palette: vec![[255, 0, 0], [0, 0, 255]],
+            transparency: Some(Transparency::Palette(vec![0])),
+            ..Png::default()
+        };
+
+        let decoded = round_trip(&png, "png_test_indexed_transparency.png")?;
+        assert_eq!(
+            &decoded.to_rgba8()[..],
+            &[255, 0, 0, 0, 0, 0, 255, 255, 0, 0, 255, 255, 255, 0, 0, 0]
+        );
+        assert_eq!(
+            &decoded.composite(Some([0, 255, 0]))?[..],
+            &[0, 255, 0, 0, 0, 255, 0, 0, 255, 0, 255, 0]
+        );
+        assert!(decoded.composite(None).is_err());
+
+        let png = Png {
+            transparency: Some(Transparency::Palette(vec![0, 0, 0])),
+            ..png
+        };
+        assert!(round_trip(&png, "png_test_indexed_transparency_too_long.png").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+use crate::optimize::pack_samples;
+use crate::png::{ColorType, Png};
+use crate::transparency::Transparency;
+use std::collections::HashMap;
+use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
+
+/// How the palette colors are chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantizeMethod {
+    /// Split the color space at the median of the widest channel.
+    MedianCut,
+    /// Refine the median cut palette with k-means clustering.
+    KMeans,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantizeOptions {
+    /// Maximum number of palette entries, from 1 to 256.
+    pub colors: usize,
+    pub method: QuantizeMethod,
+    /// Diffuse the quantization error with Floyd–Steinberg dithering.
+    pub dither: bool,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> QuantizeOptions {
+        QuantizeOptions {
+            colors: 256,
+            method: QuantizeMethod::MedianCut,
+            dither: false,
+        }
+    }
+}
+
+/// Palette image produced by `quantize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quantized {
+    pub width: usize,
+    pub height: usize,
+    /// RGBA palette entries.
+    pub palette: Vec<[u8; 4]>,
+    /// One palette index per pixel, from top to bottom.
+    pub indices: Box<[u8]>,
+}
+
+/// Reduce 8-bit RGBA pixels to a palette of at most `options.colors` entries.
+///
+/// All fully transparent pixels are treated as the same color.
+/// When the image already has few enough colors, the palette is exact and no dithering is applied.
+pub fn quantize(
+    rgba: &[u8],
+    width: usize,
+    height: usize,
+    options: &QuantizeOptions,
+) -> Result<Quantized, Error> {
+    if !(1..=256).contains(&options.colors) {
+        return Err(Error::new(InvalidInput, "Invalid number of colors"));
+    }
+    if width.checked_mul(height).and_then(|n| n.checked_mul(4)) != Some(rgba.len()) {
+        return Err(Error::new(InvalidInput, "Image size does not match"));
+    }
+
+    let pixels = rgba
+        .chunks_exact(4)
+        .map(|pixel| normalize([pixel[0], pixel[1], pixel[2], pixel[3]]))
+        .collect::<Vec<_>>();
+    let mut histogram = HashMap::new();
+    for &pixel in &pixels {
+        *histogram.entry(pixel).or_insert(0u32) += 1;
+    }
+    let mut colors = histogram.into_iter().collect::<Vec<_>>();
+    // sort for a deterministic palette
+    colors.sort();
+
+    if colors.len() <= options.colors {
+        let palette = colors.iter().map(|&(color, _)| color).collect::<Vec<_>>();
+        let index = palette
+            .iter()
+            .enumerate()
+            .map(|(i, &color)| (color, i as u8))
+            .collect::<HashMap<_, _>>();
+        return Ok(Quantized {
+            width,
+            height,
+            indices: pixels.iter().map(|pixel| index[pixel]).collect(),
+            palette,
+        });
+    }
+
+    let mut palette = median_cut(&mut colors, options.colors);
+    if options.method == QuantizeMethod::KMeans {
+        palette = k_means(&colors, palette);
+    }
+    let indices = if options.dither {
+        dither(&pixels, width, &palette)
+    } else {
+        let mut cache = HashMap::new();
+        pixels
+            .iter()
+            .map(|&pixel| {
+                *cache
+                    .entry(pixel)
+                    .or_insert_with(|| nearest(&palette, pixel))
+            })
+            .collect()
+    };
+
+    Ok(Quantized {
+        width,
+        height,
+        palette,
+        indices,
+    })
+}
+
+impl Quantized {
+    /// Indexed-color PNG with the smallest bit depth for the palette.
+    ///
+    /// Translucent entries are moved to the front so that the tRNS chunk stays short.
+    pub fn to_png(&self) -> Png {
+        let mut order = (0..self.palette.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| self.palette[i][3] == 255);
+        let mut remap = vec![0u16; self.palette.len()];
+        for (new, &old) in order.iter().enumerate() {
+            remap[old] = new as u16;
+        }
+
+        let bit_depth = [1, 2, 4, 8]
+            .into_iter()
+            .find(|&bit_depth| self.palette.len() <= 1 << bit_depth)
+            .unwrap_or(8);
+        let samples = self
+            .indices
+            .iter()
+            .map(|&index| remap[index as usize])
+            .collect::<Vec<_>>();
+        let alpha = order
+            .iter()
+            .map(|&i| self.palette[i][3])
+            .take_while(|&alpha| alpha != 255)
+            .collect::<Vec<_>>();
+        Png {
+            width: self.width,
+            height: self.height,
+            bit_depth,
+            color_type: ColorType::Indexed,
+            data: pack_samples(&samples, self.width, 1, bit_depth),
+            palette: order
+                .iter()
+                .map(|&i| {
+                    let [r, g, b, _] = self.palette[i];
+                    [r, g, b]
+                })
+                .collect(),
+            transparency: (!alpha.is_empty()).then_some(Transparency::Palette(alpha)),
+            ..Png::default()
+        }
+    }
+}
+
+fn normalize(pixel: [u8; 4]) -> [u8; 4] {
+    if pixel[3] == 0 {
+        [0, 0, 0, 0]
+    } else {
+        pixel
+    }
+}
+
+fn distance(a: [u8; 4], b: [i32; 4]) -> i32 {
+    (0..4).map(|c| (a[c] as i32 - b[c]).pow(2)).sum()
+}
+
+fn nearest(palette: &[[u8; 4]], pixel: [u8; 4]) -> u8 {
+    nearest_i32(palette, pixel.map(|v| v as i32))
+}
+
+fn nearest_i32(palette: &[[u8; 4]], pixel: [i32; 4]) -> u8 {
+    (0..palette.len())
+        .min_by_key(|&i| distance(palette[i], pixel))
+        .unwrap_or(0) as u8
+}
+
+/// Weighted mean of the colors.
+fn mean(colors: &[([u8; 4], u32)]) -> [u8; 4] {
+    let mut sum = [0u64; 4];
+    let mut count = 0u64;
+    for &(color, n) in colors {
+        for c in 0..4 {
+            sum[c] += color[c] as u64 * n as u64;
+        }
+        count += n as u64;
+    }
+    let count = count.max(1);
+    normalize(sum.map(|v| ((v + count / 2) / count) as u8))
+}
+
+/// The channel with the widest range and its width.
+fn widest_channel(colors: &[([u8; 4], u32)]) -> (usize, u8) {
+    (0..4)
+        .map(|c| {
+            let min = colors.iter().map(|(color, _)| color[c]).min().unwrap_or(0);
+            let max = colors.iter().map(|(color, _)| color[c]).max().unwrap_or(0);
+            (c, max - min)
+        })
+        .max_by_key(|&(_, range)| range)
+        .unwrap_or((0, 0))
+}
+
+/// https://en.wikipedia.org/wiki/Median_cut
+fn median_cut(colors: &mut [([u8; 4], u32)], count: usize) -> Vec<[u8; 4]> {
+    let mut boxes = vec![(0, colors.len())];
+    while boxes.len() < count {
+        // split the box with the widest range, weighted by the number of pixels
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, &(start, end))| end - start > 1)
+            .map(|(i, &(start, end))| {
+                let colors = &colors[start..end];
+                let (channel, width) = widest_channel(colors);
+                let pixels = colors.iter().map(|&(_, n)| n as u64).sum::<u64>();
+                (i, channel, width as u64 * pixels)
+            })
+            .max_by_key(|&(_, _, score)| score)
+            .map(|(i, channel, _)| (i, channel))
+        else {
+            break;
+        };
+
+        let (start, end) = boxes[index];
+        let part = &mut colors[start..end];
+        part.sort_by_key(|&(color, _)| color[channel]);
+        let total = part.iter().map(|&(_, n)| n as u64).sum::<u64>();
+        let mut accumulated = 0;
+        let mut median = 1;
+        for (i, &(_, n)) in part.iter().enumerate() {
+            accumulated += n as u64;
+            if accumulated * 2 >= total {
+                median = (i + 1).clamp(1, part.len() - 1);
+                break;
+            }
+        }
+        boxes[index] = (start, start + median);
+        boxes.push((start + median, end));
+    }
+
+    boxes
+        .into_iter()
+        .map(|(start, end)| mean(&colors[start..end]))
+        .collect()
+}
+
+/// https://en.wikipedia.org/wiki/K-means_clustering
+fn k_means(colors: &[([u8; 4], u32)], mut palette: Vec<[u8; 4]>) -> Vec<[u8; 4]> {
+    const MAX_ITERATIONS: usize = 16;
+    for _ in 0..MAX_ITERATIONS {
+        let mut clusters = vec![Vec::new(); palette.len()];
+        for &(color, n) in colors {
+            clusters[nearest(&palette, color) as usize].push((color, n));
+        }
+        let next = clusters
+            .iter()
+            .zip(&palette)
+            .map(|(cluster, &center)| {
+                if cluster.is_empty() {
+                    center
+                } else {
+                    mean(cluster)
+                }
+            })
+            .collect::<Vec<_>>();
+        if next == palette {
+            break;
+        }
+        palette = next;
+    }
+    palette
+}
+
+/// https://en.wikipedia.org/wiki/Floyd%E2%80%93Steinberg_dithering
+fn dither(pixels: &[[u8; 4]], width: usize, palette: &[[u8; 4]]) -> Box<[u8]> {
+    // errors are scaled by 16
+    let mut current = vec![[0i32; 4]; width + 2];
+    let mut next = vec![[0i32; 4]; width + 2];
+    let mut indices = Vec::with_capacity(pixels.len());
+    for line in pixels.chunks(width) {
+        for (x, &pixel) in line.iter().enumerate() {
+            let value = std::array::from_fn(|c| {
+                (pixel[c] as i32 + (current[x + 1][c] + 8).div_euclid(16)).clamp(0, 255)
+            });
+            let index = nearest_i32(palette, value);
+            indices.push(index);
+            let chosen = palette[index as usize];
+            for c in 0..4 {
+                let error = value[c] - chosen[c] as i32;
+                current[x + 2][c] += error * 7;
+                next[x][c] += error * 3;
+                next[x + 1][c] += error * 5;
+                next[x + 2][c] += error;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.fill([0; 4]);
+    }
+    indices.into_boxed_slice()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: usize, height: usize) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| {
+                (0..width).flat_map(move |x| {
+                    [
+                        (x * 255 / (width - 1)) as u8,
+                        (y * 255 / (height - 1)) as u8,
+                        128,
+                        if x < 4 { 0 } else { 255 },
+                    ]
+                })
+            })
+            .collect()
+    }
+
+    fn mean_error(rgba: &[u8], quantized: &Quantized) -> f64 {
+        let decoded = quantized.to_png().to_rgba8();
+        let sum = rgba
+            .chunks_exact(4)
+            .zip(decoded.chunks_exact(4))
+            .map(|(a, b)| {
+                let a = normalize([a[0], a[1], a[2], a[3]]);
+                (0..4).map(|c| a[c].abs_diff(b[c]) as f64).sum::<f64>()
+            })
+            .sum::<f64>();
+        sum / rgba.len() as f64
+    }
+
+    #[test]
+    fn test_exact_palette() -> Result<(), Error> {
+        let rgba = [255, 0, 0, 255, 0, 0, 255, 128, 255, 0, 0, 255, 9, 9, 9, 0];
+        let quantized = quantize(&rgba, 2, 2, &QuantizeOptions::default())?;
+        assert_eq!(quantized.palette.len(), 3);
+
+        let png = quantized.to_png();
+        assert_eq!(png.bit_depth, 2);
+        assert_eq!(png.transparency, Some(Transparency::Palette(vec![0, 128])));
+        let mut encoded = Vec::new();
+        png.write(&mut encoded)?;
+        assert_eq!(
+            &Png::from_bytes(&encoded)?.to_rgba8()[..],
+            [255, 0, 0, 255, 0, 0, 255, 128, 255, 0, 0, 255, 0, 0, 0, 0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reduce_colors() -> Result<(), Error> {
+        let rgba = gradient(64, 64);
+        for method in [QuantizeMethod::MedianCut, QuantizeMethod::KMeans] {
+            for dither in [false, true] {
+                let options = QuantizeOptions {
+                    colors: 16,
+                    method,
+                    dither,
+                };
+                let quantized = quantize(&rgba, 64, 64, &options)?;
+                assert!(quantized.palette.len() <= 16);
+                assert!(quantized.palette.contains(&[0, 0, 0, 0]));
+                assert_eq!(quantized.to_png().bit_depth, 4);
+                assert!(mean_error(&rgba, &quantized) < 12.0, "{:?}", options);
+                if dither {
+                    // dithering keeps the average color of the opaque area
+                    let decoded = quantized.to_png().to_rgba8();
+                    let average = |data: &[u8], c: usize| {
+                        data.chunks_exact(4)
+                            .filter(|pixel| pixel[3] == 255)
+                            .map(|pixel| pixel[c] as f64)
+                            .sum::<f64>()
+                            / (60.0 * 64.0)
+                    };
+                    for c in 0..3 {
+                        assert!((average(&rgba, c) - average(&decoded, c)).abs() < 2.0);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let options = QuantizeOptions {
+            colors: 0,
+            ..QuantizeOptions::default()
+        };
+        assert!(quantize(&[0; 4], 1, 1, &options).is_err());
+        assert!(quantize(&[0; 4], 2, 1, &QuantizeOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,438 @@
+use crate::crc::update_crc32;
+use crate::png::{
+    filter_line, read_chunk_header, unfilter_line, Chunk, EncodeOptions, FilterStrategy, Png,
+    SIGNATURE,
+};
+use std::fs::File;
+use std::io::ErrorKind::{InvalidData, InvalidInput, UnexpectedEof, Unsupported};
+use std::io::{sink, BufReader, BufWriter, Error, Read, Write};
+use std::path::Path;
+use zlib::zlib::{ZlibReader, ZlibWriter};
+
+/// Decoder which yields one unfiltered scanline at a time.
+///
+/// IDAT chunks are read and inflated only as rows are requested,
+/// so memory usage does not depend on the image height.
+/// Ancillary chunks after the first IDAT chunk are not read.
+///
+/// CRCs are checked like `Png::read`: a mismatch in a critical chunk is an error,
+/// and corrupted ancillary chunks are ignored.
+pub struct PngRowReader<R: Read> {
+    info: Png,
+    inflater: ZlibReader<IdatReader<R>>,
+    filtered: Vec<u8>,
+    previous: Vec<u8>,
+    row: Vec<u8>,
+    y: usize,
+    is_finished: bool,
+}
+
+impl PngRowReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<PngRowReader<BufReader<File>>, Error> {
+        PngRowReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PngRowReader<R> {
+    /// Read the chunks up to the first IDAT chunk.
+    pub fn new(mut reader: R) -> Result<PngRowReader<R>, Error> {
+        let mut signature = [0u8; 8];
+        reader.read_exact(&mut signature)?;
+        if signature != SIGNATURE {
+            return Err(Error::new(InvalidData, "Not a PNG file"));
+        }
+
+        let mut chunks = Vec::new();
+        let remaining = loop {
+            let (length, chunk_type) = read_chunk_header(&mut reader)?;
+            match chunk_type {
+                [b'I', b'D', b'A', b'T'] => break length,
+                [b'I', b'E', b'N', b'D'] => {
+                    return Err(Error::new(InvalidData, "Missing IDAT chunk"))
+                }
+                _ => {}
+            }
+            let mut data = vec![0u8; length as usize];
+            reader.read_exact(&mut data)?;
+            let mut crc = [0u8; 4];
+            reader.read_exact(&mut crc)?;
+            chunks.push(Chunk {
+                length,
+                chunk_type,
+                data,
+                crc: u32::from_be_bytes(crc),
+            });
+        };
+
+        let info = Png::from_header_chunks(&chunks)?;
+        if info.interlaced {
+            return Err(Error::new(
+                Unsupported,
+                "Interlaced images cannot be read row by row",
+            ));
+        }
+        let mut idat = IdatReader {
+            reader,
+            remaining: 0,
+            crc: 0,
+            is_end: false,
+        };
+        idat.start_chunk(remaining)?;
+        let byte_per_line = info.byte_per_line();
+        Ok(PngRowReader {
+            info,
+            inflater: ZlibReader::new(idat)?,
+            filtered: vec![0; 1 + byte_per_line],
+            previous: vec![0; byte_per_line],
+            row: vec![0; byte_per_line],
+            y: 0,
+            is_finished: false,
+        })
+    }
+
+    /// The image header and the ancillary chunks before IDAT. `data` is always empty.
+    pub fn info(&self) -> &Png {
+        &self.info
+    }
+
+    /// Decode the next scanline in the same layout as a line of `Png::data`.
+    ///
+    /// After the last row, the rest of the image data is read to verify its checksums.
+    pub fn next_row(&mut self) -> Result<Option<&[u8]>, Error> {
+        if self.y == self.info.height {
+            if !self.is_finished {
+                self.is_finished = true;
+                std::io::copy(&mut self.inflater, &mut sink())?;
+            }
+            return Ok(None);
+        }
+
+        self.inflater
+            .read_exact(&mut self.filtered)
+            .map_err(|error| match error.kind() {
+                UnexpectedEof => Error::new(InvalidData, "Image data is too short"),
+                _ => error,
+            })?;
+        std::mem::swap(&mut self.previous, &mut self.row);
+        unfilter_line(
+            self.filtered[0],
+            &self.filtered[1..],
+            (self.y > 0).then_some(&self.previous[..]),
+            &mut self.row,
+            self.info.byte_per_pixel(),
+        )?;
+        self.y += 1;
+
+        Ok(Some(&self.row))
+    }
+}
+
+/// Concatenated data of consecutive IDAT chunks.
+struct IdatReader<R: Read> {
+    reader: R,
+    /// Bytes left in the current chunk.
+    remaining: usize,
+    /// Running CRC of the current chunk.
+    crc: u32,
+    is_end: bool,
+}
+
+impl<R: Read> IdatReader<R> {
+    /// Begin an IDAT chunk whose header has been read.
+    fn start_chunk(&mut self, length: u32) -> Result<(), Error> {
+        self.remaining = length as usize;
+        self.crc = update_crc32(0xffffffff, b"IDAT");
+        if self.remaining == 0 {
+            self.check_crc()?;
+        }
+        Ok(())
+    }
+
+    /// Read the CRC following the data of the current chunk and compare it.
+    fn check_crc(&mut self) -> Result<(), Error> {
+        let mut crc = [0u8; 4];
+        self.reader.read_exact(&mut crc)?;
+        if u32::from_be_bytes(crc) != self.crc ^ 0xffffffff {
+            return Err(Error::new(InvalidData, "CRC mismatch in IDAT chunk"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for IdatReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.remaining == 0 {
+            if self.is_end {
+                return Ok(0);
+            }
+            let (length, chunk_type) = read_chunk_header(&mut self.reader)?;
+            if chunk_type != [b'I', b'D', b'A', b'T'] {
+                self.is_end = true;
+                return Ok(0);
+            }
+            self.start_chunk(length)?;
+        }
+
+        let length = buf.len().min(self.remaining);
+        let length = self.reader.read(&mut buf[..length])?;
+        if length == 0 {
+            return Err(Error::new(UnexpectedEof, "Unexpected end of file"));
+        }
+        self.crc = update_crc32(self.crc, &buf[..length]);
+        self.remaining -= length;
+        // check the CRC as soon as the chunk ends, since the last chunk may not be read further
+        if self.remaining == 0 {
+            self.check_crc()?;
+        }
+        Ok(length)
+    }
+}
+
+/// Encoder which accepts one scanline at a time.
+///
+/// Each row is filtered against the previous one and compressed incrementally,
+/// and IDAT chunks are written as soon as they are filled.
+pub struct PngRowWriter<W: Write> {
+    compressor: ZlibWriter<IdatWriter<W>>,
+    filter: FilterStrategy,
+    byte_per_line: usize,
+    byte_per_pixel: usize,
+    height: usize,
+    filtered: Vec<u8>,
+    previous: Vec<u8>,
+    y: usize,
+}
+
+impl PngRowWriter<BufWriter<File>> {
+    pub fn create(
+        path: impl AsRef<Path>,
+        info: &Png,
+        options: &EncodeOptions,
+    ) -> Result<PngRowWriter<BufWriter<File>>, Error> {
+        PngRowWriter::new(BufWriter::new(File::create(path)?), info, options)
+    }
+}
+
+impl<W: Write> PngRowWriter<W> {
+    /// Write the chunks before IDAT using everything in `info` except `data`.
+    pub fn new(
+        mut writer: W,
+        info: &Png,
+        options: &EncodeOptions,
+    ) -> Result<PngRowWriter<W>, Error> {
+        if info.interlaced {
+            return Err(Error::new(
+                Unsupported,
+                "Interlaced images cannot be written row by row",
+            ));
+        }
+        let mut header = Vec::new();
+        info.write_header_chunks(&mut header)?;
+        writer.write_all(&header)?;
+
+        let idat = IdatWriter {
+            writer,
+            buffer: Vec::with_capacity(IDAT_SIZE),
+        };
+        Ok(PngRowWriter {
+            compressor: ZlibWriter::new(idat, options.level)?,
+            filter: options.filter,
+            byte_per_line: info.byte_per_line(),
+            byte_per_pixel: info.byte_per_pixel(),
+            height: info.height,
+            filtered: Vec::with_capacity(1 + info.byte_per_line()),
+            previous: Vec::with_capacity(info.byte_per_line()),
+            y: 0,
+        })
+    }
+
+    /// Write the next scanline in the same layout as a line of `Png::data`.
+    pub fn write_row(&mut self, row: &[u8]) -> Result<(), Error> {
+        if self.y == self.height {
+            return Err(Error::new(InvalidInput, "Too many rows"));
+        }
+        if row.len() != self.byte_per_line {
+            return Err(Error::new(InvalidInput, "Row size does not match"));
+        }
+
+        self.filtered.clear();
+        filter_line(
+            self.filter,
+            row,
+            (self.y > 0).then_some(&self.previous[..]),
+            self.byte_per_pixel,
+            &mut self.filtered,
+        );
+        self.compressor.write_all(&self.filtered)?;
+        self.previous.clear();
+        self.previous.extend_from_slice(row);
+        self.y += 1;
+
+        Ok(())
+    }
+
+    /// Write the remaining IDAT data and IEND after all rows, and return the inner writer.
+    pub fn finish(self) -> Result<W, Error> {
+        if self.y != self.height {
+            return Err(Error::new(InvalidInput, "Missing rows"));
+        }
+        self.compressor.finish()?.finish()
+    }
+}
+
+/// Maximum data length of IDAT chunks written by `PngRowWriter`.
+const IDAT_SIZE: usize = 1 << 16;
+
+struct IdatWriter<W: Write> {
+    writer: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> IdatWriter<W> {
+    fn write_chunk(&mut self, chunk_type: [u8; 4], length: usize) -> Result<(), Error> {
+        let mut out = Vec::with_capacity(length + 12);
+        Chunk::new(chunk_type, self.buffer.drain(..length).collect()).write(&mut out);
+        self.writer.write_all(&out)
+    }
+
+    fn finish(mut self) -> Result<W, Error> {
+        if !self.buffer.is_empty() {
+            self.write_chunk([b'I', b'D', b'A', b'T'], self.buffer.len())?;
+        }
+        self.write_chunk([b'I', b'E', b'N', b'D'], 0)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for IdatWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= IDAT_SIZE {
+            self.write_chunk([b'I', b'D', b'A', b'T'], IDAT_SIZE)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::png::{read_chunks, ColorType};
+
+    fn sample_png() -> Png {
+        Png {
+            width: 37,
+            height: 50,
+            color_type: ColorType::TruecolorAlpha,
+            data: (0..37 * 50 * 4).map(|i| (i * 7 / 5) as u8).collect(),
+            ..Png::default()
+        }
+    }
+
+    #[test]
+    fn test_read_rows() -> Result<(), Error> {
+        let png = sample_png();
+        let mut encoded = Vec::new();
+        png.write(&mut encoded)?;
+
+        // split the image data into small IDAT chunks
+        let mut split = SIGNATURE.to_vec();
+        for chunk in read_chunks(&encoded[..])? {
+            if chunk.chunk_type == [b'I', b'D', b'A', b'T'] {
+                for data in chunk.data.chunks(100) {
+                    Chunk::new(chunk.chunk_type, data.to_vec()).write(&mut split);
+                }
+            } else {
+                chunk.write(&mut split);
+            }
+        }
+
+        let mut reader = PngRowReader::new(&split[..])?;
+        assert_eq!(reader.info().width, 37);
+        let mut data = Vec::new();
+        while let Some(row) = reader.next_row()? {
+            data.extend_from_slice(row);
+        }
+        assert_eq!(&data[..], &png.data[..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_image_data() -> Result<(), Error> {
+        let png = sample_png();
+        let mut encoded = Vec::new();
+        png.write(&mut encoded)?;
+        encoded.truncate(encoded.len() / 2);
+
+        let mut reader = PngRowReader::new(&encoded[..])?;
+        let result = (0..png.height).try_for_each(|_| reader.next_row().map(|_| ()));
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    fn read_rows(path: &str) -> Result<(), Error> {
+        let mut reader = PngRowReader::open(path)?;
+        while reader.next_row()?.is_some() {}
+        Ok(())
+    }
+
+    #[test]
+    fn test_crc_mismatch() {
+        for path in ["tests/suite/x_idat_crc.png", "tests/suite/x_ihdr_crc.png"] {
+            assert_eq!(read_rows(path).unwrap_err().kind(), InvalidData);
+        }
+        // corrupted ancillary chunks are ignored
+        assert!(read_rows("tests/suite/bad_ancillary_crc.png").is_ok());
+    }
+
+    #[test]
+    fn test_write_rows() -> Result<(), Error> {
+        let info = Png {
+            width: 300,
+            height: 300,
+            bit_depth: 16,
+            color_type: ColorType::Truecolor,
+            ..Png::default()
+        };
+        let mut seed = 1u32;
+        let rows = (0..info.height)
+            .map(|_| {
+                (0..info.byte_per_line())
+                    .map(|_| {
+                        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                        (seed >> 16) as u8
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let options = EncodeOptions {
+            filter: FilterStrategy::Paeth,
+            level: 1,
+        };
+        let mut writer = PngRowWriter::new(Vec::new(), &info, &options)?;
+        for row in &rows {
+            writer.write_row(row)?;
+        }
+        let encoded = writer.finish()?;
+
+        let chunks = read_chunks(&encoded[..])?;
+        let idat_count = chunks
+            .iter()
+            .filter(|chunk| chunk.chunk_type == [b'I', b'D', b'A', b'T'])
+            .count();
+        assert!(idat_count > 1);
+        let decoded = Png::from_chunks(&chunks)?;
+        assert_eq!(decoded.width, 300);
+        assert_eq!(&decoded.data[..], &rows.concat()[..]);
+
+        Ok(())
+    }
+}
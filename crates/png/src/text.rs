@@ -0,0 +1,184 @@
+use crate::png::Chunk;
+use crate::util::{decode_latin1, encode_keyword, encode_latin1, invalid_data, split_null};
+use std::io::Error;
+
+/// Textual information stored in tEXt, zTXt and iTXt chunks.
+///
+/// https://www.w3.org/TR/png/#11textinfo
+#[derive(Debug, Clone, PartialEq)]
+pub enum Text {
+    /// tEXt: Latin-1 text
+    Latin1 { keyword: String, text: String },
+    /// zTXt: zlib-compressed Latin-1 text
+    Compressed { keyword: String, text: String },
+    /// iTXt: UTF-8 text, optionally zlib-compressed
+    International {
+        keyword: String,
+        compressed: bool,
+        language_tag: String,
+        translated_keyword: String,
+        text: String,
+    },
+}
+
+impl Text {
+    pub fn keyword(&self) -> &str {
+        match self {
+            Text::Latin1 { keyword, .. }
+            | Text::Compressed { keyword, .. }
+            | Text::International { keyword, .. } => keyword,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        match self {
+            Text::Latin1 { text, .. }
+            | Text::Compressed { text, .. }
+            | Text::International { text, .. } => text,
+        }
+    }
+
+    pub fn read(chunk: &Chunk) -> Result<Text, Error> {
+        let data = &chunk.data[..];
+        let (keyword, rest) = split_null(data)?;
+        let keyword = decode_latin1(keyword);
+
+        match chunk.chunk_type {
+            [b't', b'E', b'X', b't'] => Ok(Text::Latin1 {
+                keyword,
+                text: decode_latin1(rest),
+            }),
+            [b'z', b'T', b'X', b't'] => {
+                let (&compression_method, compressed) = rest
+                    .split_first()
+                    .ok_or_else(|| invalid_data("zTXt chunk is too short"))?;
+                if compression_method != 0 {
+                    return Err(invalid_data("Unknown compression method"));
+                }
+                Ok(Text::Compressed {
+                    keyword,
+                    text: decode_latin1(&zlib::zlib::inflate(compressed)?),
+                })
+            }
+            [b'i', b'T', b'X', b't'] => {
+                if rest.len() < 2 {
+                    return Err(invalid_data("iTXt chunk is too short"));
+                }
+                let compressed = match rest[0] {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(invalid_data("Invalid compression flag")),
+                };
+                if compressed && rest[1] != 0 {
+                    return Err(invalid_data("Unknown compression method"));
+                }
+                let (language_tag, rest) = split_null(&rest[2..])?;
+                let (translated_keyword, text) = split_null(rest)?;
+                let text = if compressed {
+                    zlib::zlib::inflate(text)?
+                } else {
+                    text.to_vec()
+                };
+                Ok(Text::International {
+                    keyword,
+                    compressed,
+                    language_tag: decode_utf8(language_tag)?,
+                    translated_keyword: decode_utf8(translated_keyword)?,
+                    text: decode_utf8(&text)?,
+                })
+            }
+            _ => Err(invalid_data("Not a text chunk")),
+        }
+    }
+
+    pub fn to_chunk(&self) -> Result<Chunk, Error> {
+        let keyword = encode_keyword(self.keyword())?;
+
+        let mut data = keyword;
+        data.push(0);
+
+        let chunk_type = match self {
+            Text::Latin1 { text, .. } => {
+                data.extend(encode_latin1(text)?);
+                [b't', b'E', b'X', b't']
+            }
+            Text::Compressed { text, .. } => {
+                data.push(0); // compression method
+                data.extend(zlib::zlib::deflate(&encode_latin1(text)?));
+                [b'z', b'T', b'X', b't']
+            }
+            Text::International {
+                compressed,
+                language_tag,
+                translated_keyword,
+                text,
+                ..
+            } => {
+                data.push(*compressed as u8);
+                data.push(0); // compression method
+                data.extend(language_tag.as_bytes());
+                data.push(0);
+                data.extend(translated_keyword.as_bytes());
+                data.push(0);
+                if *compressed {
+                    data.extend(zlib::zlib::deflate(text.as_bytes()));
+                } else {
+                    data.extend(text.as_bytes());
+                }
+                [b'i', b'T', b'X', b't']
+            }
+        };
+
+        Ok(Chunk::new(chunk_type, data))
+    }
+}
+
+fn decode_utf8(bytes: &[u8]) -> Result<String, Error> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("Invalid UTF-8 text"))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::png::Chunk;
+    use crate::text::Text;
+
+    #[test]
+    fn test_round_trip() {
+        let texts = [
+            Text::Latin1 {
+                keyword: "Author".to_string(),
+                text: "Jürgen".to_string(),
+            },
+            Text::Compressed {
+                keyword: "Comment".to_string(),
+                text: "provenance ".repeat(10),
+            },
+            Text::International {
+                keyword: "Title".to_string(),
+                compressed: true,
+                language_tag: "ja".to_string(),
+                translated_keyword: "タイトル".to_string(),
+                text: "サンプル画像".to_string(),
+            },
+        ];
+
+        for text in texts {
+            assert_eq!(Text::read(&text.to_chunk().unwrap()).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_invalid_keyword() {
+        let text = Text::Latin1 {
+            keyword: "".to_string(),
+            text: "text".to_string(),
+        };
+        assert!(text.to_chunk().is_err());
+    }
+
+    #[test]
+    fn test_invalid_compression_flag() {
+        let chunk = Chunk::new([b'i', b'T', b'X', b't'], b"Title\0\x02\0\0\0text".to_vec());
+        assert!(Text::read(&chunk).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::png::{Chunk, ColorType};
+use crate::util::{invalid_data, read_exact};
+use std::io::Error;
+
+/// https://www.w3.org/TR/png/#11tRNS
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transparency {
+    /// Alpha values for the leading palette entries
+    Palette(Vec<u8>),
+    /// Gray sample value treated as fully transparent
+    Gray(u16),
+    /// RGB sample values treated as fully transparent
+    Rgb(u16, u16, u16),
+}
+
+/// https://www.w3.org/TR/png/#11bKGD
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    Palette(u8),
+    Gray(u16),
+    Rgb(u16, u16, u16),
+}
+
+impl Transparency {
+    /// `palette_len` is the number of PLTE entries, which limits the entries of indexed images.
+    pub fn read(
+        chunk: &Chunk,
+        color_type: ColorType,
+        palette_len: usize,
+    ) -> Result<Transparency, Error> {
+        let data = &chunk.data[..];
+        match color_type {
+            ColorType::Indexed => {
+                if data.len() > palette_len {
+                    return Err(invalid_data("tRNS chunk has more entries than PLTE"));
+                }
+                Ok(Transparency::Palette(data.to_vec()))
+            }
+            ColorType::Grayscale => {
+                read_exact(data, |reader| Ok(Transparency::Gray(reader.u16_be()?)))
+            }
+            ColorType::Truecolor => read_exact(data, |reader| {
+                Ok(Transparency::Rgb(
+                    reader.u16_be()?,
+                    reader.u16_be()?,
+                    reader.u16_be()?,
+                ))
+            }),
+            _ => Err(invalid_data(
+                "tRNS chunk is not allowed for images with alpha channel",
+            )),
+        }
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let data = match self {
+            Transparency::Palette(alpha) => alpha.clone(),
+            Transparency::Gray(gray) => gray.to_be_bytes().to_vec(),
+            Transparency::Rgb(r, g, b) => [r, g, b].iter().flat_map(|v| v.to_be_bytes()).collect(),
+        };
+        Chunk::new([b't', b'R', b'N', b'S'], data)
+    }
+}
+
+impl Background {
+    pub fn read(chunk: &Chunk, color_type: ColorType) -> Result<Background, Error> {
+        let data = &chunk.data[..];
+        read_exact(data, |reader| match color_type {
+            ColorType::Indexed => Ok(Background::Palette(reader.u8()?)),
+            ColorType::Grayscale | ColorType::GrayscaleAlpha => {
+                Ok(Background::Gray(reader.u16_be()?))
+            }
+            ColorType::Truecolor | ColorType::TruecolorAlpha => Ok(Background::Rgb(
+                reader.u16_be()?,
+                reader.u16_be()?,
+                reader.u16_be()?,
+            )),
+        })
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let data = match self {
+            Background::Palette(index) => vec![*index],
+            Background::Gray(gray) => gray.to_be_bytes().to_vec(),
+            Background::Rgb(r, g, b) => [r, g, b].iter().flat_map(|v| v.to_be_bytes()).collect(),
+        };
+        Chunk::new([b'b', b'K', b'G', b'D'], data)
+    }
+}
@@ -0,0 +1,54 @@
+use binary::bytes::ByteReader;
+use std::io::{Error, ErrorKind};
+
+/// Read chunk data of a fixed layout, failing if it is shorter or longer than the layout.
+pub fn read_exact<'a, T>(
+    data: &'a [u8],
+    read: impl FnOnce(&mut ByteReader<'a>) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut reader = ByteReader::new(data);
+    let value = read(&mut reader)?;
+    reader.finish()?;
+    Ok(value)
+}
+
+pub fn split_null(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let position = data
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| invalid_data("Missing null separator"))?;
+    Ok((&data[..position], &data[position + 1..]))
+}
+
+pub fn decode_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+pub fn encode_latin1(text: &str) -> Result<Vec<u8>, Error> {
+    text.chars()
+        .map(|c| {
+            u8::try_from(c).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "Text is not representable in Latin-1",
+                )
+            })
+        })
+        .collect()
+}
+
+/// Encode the keyword of tEXt, zTXt, iTXt or the profile name of iCCP, which is 1-79 bytes of Latin-1.
+pub fn encode_keyword(keyword: &str) -> Result<Vec<u8>, Error> {
+    let bytes = encode_latin1(keyword)?;
+    if bytes.is_empty() || bytes.len() > 79 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Keyword must be 1-79 bytes",
+        ));
+    }
+    Ok(bytes)
+}
+
+pub fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,78 @@
+//! Decode the generated images in `tests/suite` and compare them with the expected pixels.
+//!
+//! Run `python3 tests/suite/generate.py` to regenerate the suite.
+
+use png::png::Png;
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+fn suite() -> Result<Vec<PathBuf>, Error> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/suite");
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "png"));
+    paths.sort();
+    Ok(paths)
+}
+
+fn is_corrupt(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("x_"))
+}
+
+fn rgba16_bytes(png: &Png) -> Vec<u8> {
+    png.to_rgba16()
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+#[test]
+fn test_valid_images() -> Result<(), Error> {
+    let paths = suite()?
+        .into_iter()
+        .filter(|path| !is_corrupt(path))
+        .collect::<Vec<_>>();
+    assert!(paths.len() >= 50);
+
+    for path in paths {
+        let name = path.display();
+        let png = Png::open(&path).map_err(|e| Error::new(e.kind(), format!("{name}: {e}")))?;
+        let expected = fs::read(path.with_extension("rgba16"))?;
+        assert_eq!(
+            expected.len(),
+            png.width * png.height * 8,
+            "{name}: image size"
+        );
+        assert!(rgba16_bytes(&png) == expected, "{name}: pixels");
+
+        // the encoder writes the same pixels back, keeping the interlace method
+        let mut encoded = Vec::new();
+        png.write(&mut encoded)?;
+        let decoded = Png::from_bytes(&encoded)?;
+        assert_eq!(decoded.interlaced, png.interlaced, "{name}: interlace");
+        assert!(
+            rgba16_bytes(&decoded) == expected,
+            "{name}: re-encoded pixels"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_corrupt_images() -> Result<(), Error> {
+    let paths = suite()?
+        .into_iter()
+        .filter(|path| is_corrupt(path))
+        .collect::<Vec<_>>();
+    assert!(paths.len() >= 10);
+
+    for path in paths {
+        assert!(Png::open(&path).is_err(), "{}: decoded", path.display());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,298 @@
+#!/usr/bin/env python3
+"""Generate the PNG conformance suite.
+
+Each valid image `NAME.png` is written with `NAME.rgba16`, the expected pixels as
+16-bit big-endian RGBA in the same form as `Png::to_rgba16`.
+Files whose names start with `x_` are corrupt and must fail to decode.
+
+Only the standard library is used, so the files are reproducible with
+
+    python3 generate.py
+"""
+
+import os
+import struct
+import zlib
+
+OUT = os.path.dirname(os.path.abspath(__file__))
+
+GRAY, RGB, INDEXED, GRAY_ALPHA, RGBA = 0, 2, 3, 4, 6
+CHANNELS = {GRAY: 1, RGB: 3, INDEXED: 1, GRAY_ALPHA: 2, RGBA: 4}
+
+# (x0, y0, dx, dy) of the Adam7 passes
+ADAM7 = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+]
+
+
+def chunk(chunk_type, data):
+    body = chunk_type + data
+    return struct.pack(">I", len(data)) + body + struct.pack(">I", zlib.crc32(body))
+
+
+def pack_line(samples, bit_depth):
+    if bit_depth == 16:
+        return b"".join(struct.pack(">H", v) for v in samples)
+    if bit_depth == 8:
+        return bytes(samples)
+    out = bytearray((len(samples) * bit_depth + 7) // 8)
+    for i, v in enumerate(samples):
+        bit = i * bit_depth
+        out[bit >> 3] |= v << (8 - bit_depth - (bit & 7))
+    return bytes(out)
+
+
+def paeth(a, b, c):
+    p = a + b - c
+    pa, pb, pc = abs(p - a), abs(p - b), abs(p - c)
+    if pa <= pb and pa <= pc:
+        return a
+    if pb <= pc:
+        return b
+    return c
+
+
+def filter_line(filter_type, line, previous, bpp):
+    out = bytearray([filter_type])
+    for i, x in enumerate(line):
+        a = line[i - bpp] if i >= bpp else 0
+        b = previous[i] if previous is not None else 0
+        c = previous[i - bpp] if previous is not None and i >= bpp else 0
+        # unknown filter types are written like None for the corrupt files
+        predictor = [0, a, b, (a + b) // 2, paeth(a, b, c)][filter_type] if filter_type < 5 else 0
+        out.append((x - predictor) & 0xFF)
+    return bytes(out)
+
+
+def filter_image(rows, bit_depth, channels, filters):
+    """Filter rows of samples. `filters` maps the row number to a filter type."""
+    bpp = max(1, channels * bit_depth // 8)
+    out = b""
+    previous = None
+    for y, row in enumerate(rows):
+        line = pack_line([v for pixel in row for v in pixel], bit_depth)
+        out += filter_line(filters(y), line, previous, bpp)
+        previous = line
+    return out
+
+
+def image_data(pixels, bit_depth, channels, interlace, filters):
+    if not interlace:
+        return filter_image(pixels, bit_depth, channels, filters)
+    out = b""
+    height, width = len(pixels), len(pixels[0])
+    for x0, y0, dx, dy in ADAM7:
+        rows = [
+            [pixels[y][x] for x in range(x0, width, dx)]
+            for y in range(y0, height, dy)
+        ]
+        if rows and rows[0]:
+            out += filter_image(rows, bit_depth, channels, filters)
+    return out
+
+
+def scale(value, bit_depth):
+    return value * 65535 // ((1 << bit_depth) - 1)
+
+
+def to_rgba16(pixels, color_type, bit_depth, palette=None, trns=None):
+    out = bytearray()
+    for row in pixels:
+        for pixel in row:
+            if color_type == INDEXED:
+                r, g, b = palette[pixel[0]]
+                alpha = trns[pixel[0]] if trns and pixel[0] < len(trns) else 255
+                rgba = [v * 257 for v in (r, g, b, alpha)]
+            elif color_type == GRAY:
+                alpha = 0 if trns == pixel[0] else 65535
+                rgba = [scale(pixel[0], bit_depth)] * 3 + [alpha]
+            elif color_type == RGB:
+                alpha = 0 if trns == tuple(pixel) else 65535
+                rgba = [scale(v, bit_depth) for v in pixel] + [alpha]
+            elif color_type == GRAY_ALPHA:
+                rgba = [scale(pixel[0], bit_depth)] * 3 + [scale(pixel[1], bit_depth)]
+            else:
+                rgba = [scale(v, bit_depth) for v in pixel]
+            out += struct.pack(">4H", *rgba)
+    return bytes(out)
+
+
+def sample_pixels(width, height, color_type, bit_depth):
+    channels = CHANNELS[color_type]
+    limit = 1 << bit_depth
+    if color_type == INDEXED:
+        limit = min(limit, 256)
+    return [
+        [
+            tuple(
+                (x * 7 + y * 13 + c * 29 + x * y * 3) * (1 if bit_depth < 16 else 1021) % limit
+                for c in range(channels)
+            )
+            for x in range(width)
+        ]
+        for y in range(height)
+    ]
+
+
+def sample_palette(size):
+    return [((i * 37) % 256, (i * 91 + 17) % 256, (i * 53 + 101) % 256) for i in range(size)]
+
+
+def encode(
+    width,
+    height,
+    color_type,
+    bit_depth,
+    pixels,
+    interlace=False,
+    filters=lambda y: y % 5,
+    palette=None,
+    trns=None,
+    idat_size=None,
+):
+    ihdr = struct.pack(">IIBBBBB", width, height, bit_depth, color_type, 0, 0, int(interlace))
+    chunks = [(b"IHDR", ihdr)]
+    if palette is not None:
+        chunks.append((b"PLTE", b"".join(bytes(entry) for entry in palette)))
+    if trns is not None:
+        if color_type == INDEXED:
+            data = bytes(trns)
+        elif color_type == GRAY:
+            data = struct.pack(">H", trns)
+        else:
+            data = struct.pack(">3H", *trns)
+        chunks.append((b"tRNS", data))
+    data = zlib.compress(image_data(pixels, bit_depth, CHANNELS[color_type], interlace, filters), 9)
+    if idat_size is None:
+        chunks.append((b"IDAT", data))
+    else:
+        for i in range(0, len(data), idat_size):
+            chunks.append((b"IDAT", data[i : i + idat_size]))
+            # empty IDAT chunks are allowed
+            chunks.append((b"IDAT", b""))
+    chunks.append((b"IEND", b""))
+    return chunks
+
+
+def to_bytes(chunks):
+    return b"\x89PNG\r\n\x1a\n" + b"".join(chunk(t, d) for t, d in chunks)
+
+
+def flip(data, offset):
+    """Corrupt one byte."""
+    return data[:offset] + bytes([data[offset] ^ 0xFF]) + data[offset + 1 :]
+
+
+def write(name, data, expected=None):
+    with open(os.path.join(OUT, name + ".png"), "wb") as f:
+        f.write(data)
+    if expected is not None:
+        with open(os.path.join(OUT, name + ".rgba16"), "wb") as f:
+            f.write(expected)
+
+
+def valid(name, width, height, color_type, bit_depth, interlace=False, **options):
+    pixels = sample_pixels(width, height, color_type, bit_depth)
+    palette = None
+    if color_type == INDEXED:
+        palette = sample_palette(min(1 << bit_depth, 256))
+    palette = options.pop("palette", palette)
+    if palette is not None:
+        pixels = [[(pixel[0] % len(palette),) for pixel in row] for row in pixels]
+    chunks = encode(width, height, color_type, bit_depth, pixels, interlace, palette=palette, **options)
+    expected = to_rgba16(pixels, color_type, bit_depth, palette, options.get("trns"))
+    write(name, to_bytes(chunks), expected)
+    return chunks
+
+
+def main():
+    for name in os.listdir(OUT):
+        if name.endswith(".png") or name.endswith(".rgba16"):
+            os.remove(os.path.join(OUT, name))
+
+    # every color type and bit depth, with and without interlacing
+    formats = [
+        ("gray", GRAY, [1, 2, 4, 8, 16]),
+        ("rgb", RGB, [8, 16]),
+        ("indexed", INDEXED, [1, 2, 4, 8]),
+        ("gray_alpha", GRAY_ALPHA, [8, 16]),
+        ("rgba", RGBA, [8, 16]),
+    ]
+    for prefix, color_type, bit_depths in formats:
+        for bit_depth in bit_depths:
+            for interlace in [False, True]:
+                name = "%s%d_%s" % (prefix, bit_depth, "i" if interlace else "n")
+                valid(name, 16, 16, color_type, bit_depth, interlace)
+
+    # each filter type on all rows
+    for filter_type in range(5):
+        valid("filter%d" % filter_type, 16, 16, RGB, 8, filters=lambda y, f=filter_type: f)
+        valid("filter%d_gray2" % filter_type, 16, 16, GRAY, 2, filters=lambda y, f=filter_type: f)
+        valid("filter%d_rgba16" % filter_type, 16, 16, RGBA, 16, filters=lambda y, f=filter_type: f)
+
+    # odd sizes, including Adam7 passes without pixels
+    for width, height in [(1, 1), (1, 9), (9, 1), (2, 3), (5, 5), (7, 7), (9, 9), (33, 1)]:
+        for interlace in [False, True]:
+            suffix = "i" if interlace else "n"
+            valid("size%dx%d_%s" % (width, height, suffix), width, height, INDEXED, 2, interlace)
+    valid("size1x1_rgb16", 1, 1, RGB, 16)
+    valid("size1x17_rgba8_i", 1, 17, RGBA, 8, True)
+
+    # transparency
+    valid("trns_gray8", 16, 16, GRAY, 8, trns=sample_pixels(1, 1, GRAY, 8)[0][0][0])
+    valid("trns_gray1_i", 16, 16, GRAY, 1, True, trns=1)
+    valid("trns_rgb16", 16, 16, RGB, 16, trns=sample_pixels(3, 3, RGB, 16)[2][2])
+    valid("trns_indexed4", 16, 16, INDEXED, 4, trns=[0, 64, 128])
+
+    # a palette smaller than the bit depth allows
+    valid("palette_small", 16, 16, INDEXED, 8, palette=sample_palette(256)[:200], filters=lambda y: 0)
+
+    # image data split into many IDAT chunks
+    valid("split_idat", 16, 16, RGBA, 8, idat_size=7)
+
+    # a corrupted ancillary chunk is ignored
+    chunks = valid("bad_ancillary_crc", 8, 8, RGB, 8)
+    data = to_bytes(chunks[:1] + [(b"tEXt", b"Title\x00sample")] + chunks[1:])
+    index = data.index(b"tEXt")
+    data = flip(data, index + 16)
+    write("bad_ancillary_crc", data, to_rgba16(sample_pixels(8, 8, RGB, 8), RGB, 8))
+
+    # corrupt files
+    base = encode(8, 8, RGB, 8, sample_pixels(8, 8, RGB, 8))
+    ihdr = base[0][1]
+
+    def with_ihdr(offset, value):
+        return [(b"IHDR", ihdr[:offset] + bytes([value]) + ihdr[offset + 1 :])] + base[1:]
+
+    write("x_signature", b"\x89PNG\r\n\x1a\x00" + to_bytes(base)[8:])
+    write("x_missing_ihdr", to_bytes(base[1:]))
+    write("x_ihdr_crc", flip(to_bytes(base), 29))
+    write("x_color_type", to_bytes(with_ihdr(9, 1)))
+    write("x_bit_depth", to_bytes(with_ihdr(8, 4)))
+    write("x_compression_method", to_bytes(with_ihdr(10, 1)))
+    write("x_filter_method", to_bytes(with_ihdr(11, 1)))
+    write("x_interlace_method", to_bytes(with_ihdr(12, 2)))
+    write("x_missing_plte", to_bytes([c for c in valid("tmp", 8, 8, INDEXED, 4) if c[0] != b"PLTE"]))
+    write("x_plte_length", to_bytes([(t, d[:-1] if t == b"PLTE" else d) for t, d in valid("tmp", 8, 8, INDEXED, 4)]))
+    write("x_missing_idat", to_bytes([c for c in base if c[0] != b"IDAT"]))
+    write("x_idat_crc", flip(to_bytes(base), -16))
+    write("x_filter_type", to_bytes(encode(8, 8, RGB, 8, sample_pixels(8, 8, RGB, 8), filters=lambda y: 5)))
+    short = [(t, zlib.compress(filter_image(sample_pixels(8, 7, RGB, 8), 8, 3, lambda y: 0)) if t == b"IDAT" else d) for t, d in base]
+    write("x_short_data", to_bytes(short))
+    idat = base[1][1]
+    write("x_adler32", to_bytes([base[0], (b"IDAT", idat[:-1] + bytes([idat[-1] ^ 1])), base[2]]))
+    write("x_zlib_header", to_bytes([base[0], (b"IDAT", b"\x78\x00" + idat[2:]), base[2]]))
+    write("x_truncated", to_bytes(base)[:60])
+    write("x_unknown_critical", to_bytes(base[:1] + [(b"CRIT", b"\x00")] + base[1:]))
+    os.remove(os.path.join(OUT, "tmp.png"))
+    os.remove(os.path.join(OUT, "tmp.rgba16"))
+
+
+if __name__ == "__main__":
+    main()
@@ -1,6 +1,5 @@
 use std::fs::File;
-use std::io::Error;
-use std::os::unix::fs::FileExt;
+use std::io::{BufReader, Error, ErrorKind, Read};
 use std::path::Path;
 
 #[derive(Debug)]
@@ -24,7 +23,10 @@ impl Chunk {
     ) -> Chunk {
         Chunk {
             id: id.into(),
-            size: 4 + children.iter().map(|c| c.size + 8).sum::<usize>(),
+            size: 4 + children
+                .iter()
+                .map(|c| c.size + (c.size & 1) + 8)
+                .sum::<usize>(),
             form_type: form_type.into(),
             children,
             data: Vec::new(),
@@ -42,12 +44,16 @@ impl Chunk {
     }
 
     pub fn open(path: impl AsRef<Path>) -> Result<Chunk, Error> {
-        let mut file = File::open(path)?;
-        Ok(Chunk::read(&mut file))
+        Chunk::read(&mut BufReader::new(File::open(path)?))
     }
 
-    pub fn read(file: &mut File) -> Chunk {
-        parse_chunk(file, 0).unwrap()
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Chunk, Error> {
+        Chunk::read(&mut bytes)
+    }
+
+    /// Read a chunk and its children from the current position of `reader`.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Chunk, Error> {
+        parse_chunk(reader)
     }
 
     pub fn print(&self) {
@@ -60,6 +66,9 @@ impl Chunk {
 
         if self.children.is_empty() {
             out.extend_from_slice(&self.data);
+            if self.size & 1 == 1 {
+                out.push(0); // padding
+            }
         } else {
             out.extend_from_slice(self.form_type.as_bytes());
             for child in self.children.iter() {
@@ -108,29 +117,35 @@ impl Chunk {
     }
 }
 
-fn parse_chunk(file: &mut File, mut offset: usize) -> Result<Chunk, std::io::Error> {
+fn parse_chunk<R: Read>(reader: &mut R) -> Result<Chunk, Error> {
     let mut buf4 = [0u8; 4];
 
-    file.read_at(&mut buf4, offset as u64)?;
+    reader.read_exact(&mut buf4)?;
     let id = String::from_utf8_lossy(&buf4).to_string();
-    offset += 4;
 
-    file.read_at(&mut buf4, offset as u64)?;
+    reader.read_exact(&mut buf4)?;
     let size = u32::from_le_bytes(buf4) as usize;
-    offset += 4;
 
     let mut form_type = String::new();
 
     let (children, data) = match id.as_str() {
         "RIFF" | "LIST" => {
-            file.read_at(&mut buf4, offset as u64)?;
+            if size < 4 {
+                return Err(Error::new(ErrorKind::InvalidData, "Invalid list size"));
+            }
+            reader.read_exact(&mut buf4)?;
             form_type = String::from_utf8_lossy(&buf4).to_string();
-            offset += 4;
-            (parse_chunk_list(file, offset, size - 4)?, Vec::new())
+            (parse_chunk_list(reader, size - 4)?, Vec::new())
         }
         _ => {
-            let mut buf = vec![0u8; size];
-            file.read_at(&mut buf, offset as u64)?;
+            let mut buf = Vec::new();
+            reader.take(size as u64).read_to_end(&mut buf)?;
+            if buf.len() != size {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Unexpected end of file",
+                ));
+            }
             (Vec::new(), buf)
         }
     };
@@ -144,18 +159,16 @@ fn parse_chunk(file: &mut File, mut offset: usize) -> Result<Chunk, std::io::Err
     })
 }
 
-fn parse_chunk_list(
-    file: &mut File,
-    offset: usize,
-    size: usize,
-) -> Result<Vec<Chunk>, std::io::Error> {
+fn parse_chunk_list<R: Read>(reader: &mut R, size: usize) -> Result<Vec<Chunk>, Error> {
     let mut chunks = Vec::new();
-    let mut offset = offset;
-    let end = offset + size;
+    let mut offset = 0;
 
-    while offset < end {
-        let chunk = parse_chunk(file, offset)?;
+    while offset < size {
+        let chunk = parse_chunk(reader)?;
         // Odd size chunks are padded with a null byte
+        if chunk.size & 1 == 1 && offset + chunk.size + 8 < size {
+            reader.read_exact(&mut [0u8])?;
+        }
         offset += chunk.size + (chunk.size & 1) + 8; // 8 for id and size fields
         chunks.push(chunk);
     }
@@ -179,4 +192,50 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_from_bytes() -> Result<(), Error> {
+        let riff = Chunk::list_with_id(
+            "RIFF",
+            "TEST",
+            vec![
+                Chunk::new("odd ", vec![1, 2, 3]),
+                Chunk::list("list", vec![Chunk::new("even", vec![4, 5])]),
+            ],
+        );
+        let mut bytes = Vec::new();
+        riff.write(&mut bytes);
+
+        let parsed = Chunk::from_bytes(&bytes)?;
+        assert_eq!(parsed.form_type, "TEST");
+        assert_eq!(parsed.children[0].data, [1, 2, 3]);
+        assert_eq!(parsed.children[1].children[0].data, [4, 5]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_odd_size_padding() -> Result<(), Error> {
+        let riff = Chunk::list_with_id(
+            "RIFF",
+            "TEST",
+            vec![Chunk::new("odd ", vec![1]), Chunk::new("even", vec![2, 3])],
+        );
+        let mut bytes = Vec::new();
+        riff.write(&mut bytes);
+
+        // the list size counts the padding byte of the odd chunk
+        assert_eq!(
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            4 + 10 + 10
+        );
+        assert_eq!(&bytes[12..22], b"odd \x01\0\0\0\x01\0");
+        assert_eq!(bytes.len(), 8 + 24);
+
+        let parsed = Chunk::from_bytes(&bytes)?;
+        assert_eq!(parsed.children[0].data, [1]);
+        assert_eq!(parsed.children[1].data, [2, 3]);
+
+        Ok(())
+    }
 }
@@ -1,26 +1,181 @@
-pub fn read_one_bit(buffer: &[u8], bit_offset: usize) -> u8 {
-    let byte = bit_offset >> 3;
-    let bit = bit_offset & 0b111;
-    (buffer[byte] >> bit) & 1
+use std::io::{Error, ErrorKind, Read};
+
+const BUFFER_SIZE: usize = 8192;
+
+/// Bit stream reader which takes bits from the least significant bit of each byte.
+pub struct BitReader<R: Read> {
+    reader: R,
+    buffer: Box<[u8]>,
+    start: usize,
+    end: usize,
+    /// Bits already consumed from `buffer[start]`.
+    bit_offset: u8,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(reader: R) -> BitReader<R> {
+        BitReader {
+            reader,
+            buffer: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            bit_offset: 0,
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), Error> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+            loop {
+                match self.reader.read(&mut self.buffer) {
+                    Ok(0) => {
+                        return Err(Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "Unexpected end of stream",
+                        ))
+                    }
+                    Ok(length) => {
+                        self.end = length;
+                        break;
+                    }
+                    Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_one_bit(&mut self) -> Result<u8, Error> {
+        self.fill()?;
+        let bit = (self.buffer[self.start] >> self.bit_offset) & 1;
+        self.bit_offset += 1;
+        if self.bit_offset == 8 {
+            self.bit_offset = 0;
+            self.start += 1;
+        }
+        Ok(bit)
+    }
+
+    /// Read `bits` bits, least significant bit first.
+    pub fn read_bits(&mut self, bits: u8) -> Result<usize, Error> {
+        let mut value = 0usize;
+        for i in 0..bits {
+            value |= (self.read_one_bit()? as usize) << i;
+        }
+        Ok(value)
+    }
+
+    /// Skip the remaining bits to the next byte boundary.
+    pub fn align_to_byte(&mut self) {
+        if self.bit_offset != 0 {
+            self.bit_offset = 0;
+            self.start += 1;
+        }
+    }
+
+    /// Read bytes from the next byte boundary.
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        self.align_to_byte();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            self.fill()?;
+            let length = (self.end - self.start).min(bytes.len() - offset);
+            bytes[offset..offset + length]
+                .copy_from_slice(&self.buffer[self.start..self.start + length]);
+            self.start += length;
+            offset += length;
+        }
+        Ok(())
+    }
+}
+
+/// Bit stream writer which packs bits from the least significant bit of each byte.
+pub struct BitWriter {
+    buffer: Vec<u8>,
+    bit_offset: usize,
 }
 
-pub fn read_bits(buffer: &[u8], bit_offset: usize, bits: u8) -> usize {
-    let mut value = 0usize;
-    for i in 0..bits {
-        let bit = read_one_bit(buffer, bit_offset + i as usize) as usize;
-        value |= bit << i;
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {
+            buffer: Vec::new(),
+            bit_offset: 0,
+        }
+    }
+
+    /// Write `bits` bits of `value`, least significant bit first.
+    pub fn write_bits(&mut self, value: usize, bits: u8) {
+        for i in 0..bits {
+            if self.bit_offset & 0b111 == 0 {
+                self.buffer.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.buffer.last_mut().unwrap() |= bit << (self.bit_offset & 0b111);
+            self.bit_offset += 1;
+        }
+    }
+
+    /// Write a huffman code, most significant bit first.
+    pub fn write_code(&mut self, code: u16, length: u8) {
+        for i in (0..length).rev() {
+            self.write_bits(((code >> i) & 1) as usize, 1);
+        }
+    }
+
+    /// Skip the remaining bits to the next byte boundary.
+    pub fn align_to_byte(&mut self) {
+        self.bit_offset = (self.bit_offset + 7) & !0b111;
+    }
+
+    /// Write bytes from the next byte boundary.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.align_to_byte();
+        self.buffer.extend_from_slice(bytes);
+        self.bit_offset += bytes.len() << 3;
+    }
+
+    pub fn bit_len(&self) -> usize {
+        self.bit_offset
+    }
+
+    /// Remove the completed bytes from the buffer, keeping a partially written byte.
+    pub fn take_complete_bytes(&mut self) -> Vec<u8> {
+        let rest = self.buffer.split_off(self.bit_offset >> 3);
+        self.bit_offset &= 0b111;
+        std::mem::replace(&mut self.buffer, rest)
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buffer
     }
-    value
 }
 
 #[cfg(test)]
 mod test {
-    use crate::bit::read_bits;
+    use crate::bit::{BitReader, BitWriter};
 
     #[test]
     fn test_read_bit() {
-        assert_eq!(read_bits(&[0b00110000], 0, 1), 0b0);
-        assert_eq!(read_bits(&[0b00001100], 1, 2), 0b10);
-        assert_eq!(read_bits(&[0b01000000, 0b00000010], 6, 6), 0b001001);
+        let mut reader = BitReader::new(&[0b01001100, 0b00000010, 0xab][..]);
+        assert_eq!(reader.read_bits(1).unwrap(), 0b0);
+        assert_eq!(reader.read_bits(2).unwrap(), 0b10);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b001);
+        assert_eq!(reader.read_bits(6).unwrap(), 0b001001);
+        let mut byte = [0u8];
+        reader.read_bytes(&mut byte).unwrap();
+        assert_eq!(byte, [0xab]);
+        assert!(reader.read_one_bit().is_err());
+    }
+
+    #[test]
+    fn test_write_bits() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b1, 1);
+        writer.write_bits(0b10, 2);
+        writer.write_code(0b110, 3);
+        writer.write_bits(0b111, 3);
+        assert_eq!(writer.finish(), vec![0b11011101, 0b1]);
     }
 }
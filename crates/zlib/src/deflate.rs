@@ -1,215 +1,720 @@
-use crate::bit::{read_bits, read_one_bit};
-use crate::huffman_table::HuffmanTable;
-use std::collections::HashMap;
-use std::io::{Error, ErrorKind};
+use crate::bit::{BitReader, BitWriter};
+use crate::huffman_table::{lengths_to_codes, HuffmanTable};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{Error, ErrorKind, Read, Write};
 
 pub fn inflate(compressed: &[u8]) -> Result<Vec<u8>, Error> {
-    let mut bit_offset = 0usize;
-    let mut inflated = Vec::<u8>::new();
+    let mut inflated = Vec::new();
+    InflateReader::new(compressed).read_to_end(&mut inflated)?;
+    Ok(inflated)
+}
+
+/// Incremental decompressor which pulls compressed data from `R` only as output is requested.
+///
+/// Only the last 32KB of output are kept for back references.
+pub struct InflateReader<R: Read> {
+    reader: BitReader<R>,
+    state: BlockState,
+    is_final: bool,
+    /// Decompressed data; bytes before `position` have already been returned.
+    window: Vec<u8>,
+    position: usize,
+}
+
+enum BlockState {
+    Header,
+    Uncompressed {
+        remaining: usize,
+    },
+    Compressed {
+        literal: HuffmanTable,
+        distance: HuffmanTable,
+    },
+    End,
+}
 
-    while bit_offset >> 3 < compressed.len() {
-        let is_final = read_one_bit(compressed, bit_offset) == 1;
-        bit_offset += 1;
+/// Output produced per step before control returns to the caller.
+const STEP_SIZE: usize = 16384;
+
+impl<R: Read> InflateReader<R> {
+    pub fn new(reader: R) -> InflateReader<R> {
+        InflateReader::from_bit_reader(BitReader::new(reader))
+    }
+
+    pub(crate) fn from_bit_reader(reader: BitReader<R>) -> InflateReader<R> {
+        InflateReader {
+            reader,
+            state: BlockState::Header,
+            is_final: false,
+            window: Vec::new(),
+            position: 0,
+        }
+    }
 
-        let block_type = read_bits(compressed, bit_offset, 2);
-        bit_offset += 2;
+    /// Read bytes following the compressed data, such as the zlib checksum.
+    pub(crate) fn read_trailer(&mut self, bytes: &mut [u8]) -> Result<(), Error> {
+        self.reader.read_bytes(bytes)
+    }
 
-        match block_type {
-            0b00 => {
-                // uncompressed block
-                bit_offset = inflate_uncompressed_block(compressed, &mut inflated, bit_offset)?;
+    fn step(&mut self) -> Result<(), Error> {
+        match &mut self.state {
+            BlockState::Header => {
+                if self.is_final {
+                    self.state = BlockState::End;
+                    return Ok(());
+                }
+                self.is_final = match self.reader.read_one_bit() {
+                    Ok(bit) => bit == 1,
+                    // tolerate streams without a final block
+                    Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                        self.state = BlockState::End;
+                        return Ok(());
+                    }
+                    Err(error) => return Err(error),
+                };
+                self.state = match self.reader.read_bits(2)? {
+                    0b00 => {
+                        // uncompressed block
+                        let mut header = [0u8; 4];
+                        self.reader.read_bytes(&mut header)?;
+                        let length = u16::from_le_bytes([header[0], header[1]]);
+                        let length_complement = u16::from_le_bytes([header[2], header[3]]);
+                        if length != !length_complement {
+                            return Err(Error::new(ErrorKind::InvalidData, "Corrupted data"));
+                        }
+                        BlockState::Uncompressed {
+                            remaining: length as usize,
+                        }
+                    }
+                    // compressed with static huffman codes
+                    0b01 => BlockState::Compressed {
+                        literal: HuffmanTable::from_code_lengths(&static_literal_code_lengths()),
+                        distance: HuffmanTable::from_code_lengths(&static_distance_code_lengths()),
+                    },
+                    // compressed with dynamic huffman codes
+                    0b10 => {
+                        let (literal, distance) = read_dynamic_tables(&mut self.reader)?;
+                        BlockState::Compressed { literal, distance }
+                    }
+                    _ => return Err(Error::new(ErrorKind::InvalidData, "Corrupted data")),
+                };
             }
-            0b01 => {
-                // compressed with static huffman codes
-                bit_offset = inflate_static_block(compressed, &mut inflated, bit_offset)?;
+            BlockState::Uncompressed { remaining } => {
+                let length = (*remaining).min(STEP_SIZE);
+                let start = self.window.len();
+                self.window.resize(start + length, 0);
+                self.reader.read_bytes(&mut self.window[start..])?;
+                *remaining -= length;
+                if *remaining == 0 {
+                    self.state = BlockState::Header;
+                }
             }
-            0b10 => {
-                // compressed with dynamic huffman codes
-                bit_offset = inflate_dynamic_block(compressed, &mut inflated, bit_offset)?;
+            BlockState::Compressed { literal, distance } => {
+                let start = self.window.len();
+                while self.window.len() - start < STEP_SIZE {
+                    let value = literal.decode(&mut self.reader)?;
+                    if value <= 255 {
+                        self.window.push(value as u8);
+                    } else if value == 256 {
+                        self.state = BlockState::Header;
+                        break;
+                    } else {
+                        let index = value as usize - 257;
+                        if index >= LENGTH_BASE.len() {
+                            return Err(Error::new(ErrorKind::InvalidData, "Corrupted data"));
+                        }
+                        let length = self.reader.read_bits(LENGTH_EXTRA_BITS[index] as u8)?
+                            + LENGTH_BASE[index];
+
+                        let index = distance.decode(&mut self.reader)? as usize;
+                        if index >= DISTANCE_BASE.len() {
+                            return Err(Error::new(ErrorKind::InvalidData, "Corrupted data"));
+                        }
+                        let distance = self.reader.read_bits(DISTANCE_EXTRA_BITS[index] as u8)?
+                            + DISTANCE_BASE[index];
+                        if distance > self.window.len() {
+                            return Err(Error::new(ErrorKind::InvalidData, "Invalid distance"));
+                        }
+
+                        let start = self.window.len() - distance;
+                        for offset in 0..length {
+                            self.window.push(self.window[start + offset]);
+                        }
+                    }
+                }
             }
-            _ => return Err(Error::new(ErrorKind::InvalidData, "Corrupted data")),
+            BlockState::End => {}
         }
+        Ok(())
+    }
+}
 
-        if is_final {
-            break;
+impl<R: Read> Read for InflateReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.position == self.window.len() && !matches!(self.state, BlockState::End) {
+            self.step()?;
         }
-    }
 
-    Ok(inflated)
+        let length = buf.len().min(self.window.len() - self.position);
+        buf[..length].copy_from_slice(&self.window[self.position..self.position + length]);
+        self.position += length;
+
+        // drop output that can no longer be referenced
+        if self.position > WINDOW_SIZE * 2 {
+            let discard = self.position - WINDOW_SIZE;
+            self.window.drain(..discard);
+            self.position -= discard;
+        }
+
+        Ok(length)
+    }
 }
 
-fn inflate_uncompressed_block(
-    compressed: &[u8],
-    inflated: &mut Vec<u8>,
-    mut bit_offset: usize,
-) -> Result<usize, Error> {
-    bit_offset = (bit_offset + 7) & !0b111; // round up to byte boundary
-    let mut byte_offset = bit_offset >> 3;
+fn read_dynamic_tables<R: Read>(
+    reader: &mut BitReader<R>,
+) -> Result<(HuffmanTable, HuffmanTable), Error> {
+    let literal_codes_count = reader.read_bits(5)? + 257;
+    let distance_codes_count = reader.read_bits(5)? + 1;
+    let code_length_codes_count = reader.read_bits(4)? + 4;
 
-    let length = compressed[byte_offset] as usize + ((compressed[byte_offset + 1] as usize) << 8);
-    byte_offset += 2;
+    let mut code_length_code_lengths = HashMap::new();
+    for &code_length in &CODE_LENGTH_ORDER[..code_length_codes_count] {
+        code_length_code_lengths.insert(code_length, reader.read_bits(3)? as u8);
+    }
+    let code_length_huffman = HuffmanTable::from_code_lengths(&code_length_code_lengths);
 
-    byte_offset += 2; // NLEN
+    let mut code_lengths = Vec::new();
+    let mut last_length = 0;
+    while code_lengths.len() < literal_codes_count + distance_codes_count {
+        let value = code_length_huffman.decode(reader)?;
 
-    inflated.extend(&compressed[byte_offset..byte_offset + length]);
-    byte_offset += length;
+        if value <= 15 {
+            code_lengths.push(value as u8);
+            last_length = value as u8;
+        } else if value == 16 {
+            let repeat_count = reader.read_bits(2)? + 3;
+            code_lengths.extend(std::iter::repeat_n(last_length, repeat_count));
+        } else if value == 17 {
+            let repeat_count = reader.read_bits(3)? + 3;
+            code_lengths.extend(std::iter::repeat_n(0, repeat_count));
+        } else if value == 18 {
+            let repeat_count = reader.read_bits(7)? + 11;
+            code_lengths.extend(std::iter::repeat_n(0, repeat_count));
+        }
+    }
+    if code_lengths.len() != literal_codes_count + distance_codes_count {
+        return Err(Error::new(ErrorKind::InvalidData, "Corrupted data"));
+    }
 
-    bit_offset += byte_offset << 3;
+    let literal_code_lengths = to_length_map(&code_lengths[..literal_codes_count]);
+    let distance_code_lengths = to_length_map(&code_lengths[literal_codes_count..]);
+    Ok((
+        HuffmanTable::from_code_lengths(&literal_code_lengths),
+        HuffmanTable::from_code_lengths(&distance_code_lengths),
+    ))
+}
 
-    Ok(bit_offset)
+/// Compress data with the default compression level.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    deflate_with_level(data, DEFAULT_LEVEL)
 }
 
-fn inflate_static_block(
-    compressed: &[u8],
-    inflated: &mut Vec<u8>,
-    mut bit_offset: usize,
-) -> Result<usize, Error> {
-    let mut code_lengths = HashMap::new();
-    for i in 0..=287 {
-        code_lengths.insert(
-            i as u16,
-            if i <= 143 {
-                8
-            } else if i <= 255 {
-                9
-            } else if i <= 279 {
-                7
-            } else {
-                8
-            },
+pub const DEFAULT_LEVEL: u8 = 6;
+
+/// Compress data with `level` from 0 (no compression) to 9 (best compression).
+///
+/// Higher levels search longer hash chains for matches.
+pub fn deflate_with_level(data: &[u8], level: u8) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    write_best_block(&mut writer, data, 0, level, true);
+    writer.finish()
+}
+
+/// Incremental compressor which writes a block to `W` each time enough input is buffered.
+///
+/// Only the last 32KB of input are kept for back references.
+pub struct DeflateWriter<W: Write> {
+    writer: W,
+    bits: BitWriter,
+    /// Input already compressed followed by input not yet compressed from `pending`.
+    buffer: Vec<u8>,
+    pending: usize,
+    level: u8,
+}
+
+/// Input size for each block of `DeflateWriter`.
+const BLOCK_SIZE: usize = 1 << 17;
+
+impl<W: Write> DeflateWriter<W> {
+    pub fn new(writer: W, level: u8) -> DeflateWriter<W> {
+        DeflateWriter {
+            writer,
+            bits: BitWriter::new(),
+            buffer: Vec::new(),
+            pending: 0,
+            level,
+        }
+    }
+
+    fn write_block(&mut self, is_final: bool) -> Result<(), Error> {
+        write_best_block(
+            &mut self.bits,
+            &self.buffer,
+            self.pending,
+            self.level,
+            is_final,
         );
+        self.writer.write_all(&self.bits.take_complete_bytes())?;
+
+        let discard = self.buffer.len().saturating_sub(WINDOW_SIZE);
+        self.buffer.drain(..discard);
+        self.pending = self.buffer.len();
+        Ok(())
     }
-    let huffman = HuffmanTable::from_code_lengths(&code_lengths);
 
-    while bit_offset >> 3 < compressed.len() {
-        let (literal_or_length_code, next_offset) = huffman.decode(compressed, bit_offset)?;
-        bit_offset = next_offset;
+    /// Write the final block and return the inner writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.write_block(true)?;
+        let bits = std::mem::replace(&mut self.bits, BitWriter::new());
+        self.writer.write_all(&bits.finish())?;
+        Ok(self.writer)
+    }
+}
 
-        if literal_or_length_code <= 255 {
-            inflated.push(literal_or_length_code as u8);
-        } else if literal_or_length_code == 256 {
-            break;
-        } else {
-            let extra_bits = LENGTH_EXTRA_BITS[literal_or_length_code as usize - 257];
-            let length = read_bits(compressed, bit_offset, extra_bits as u8)
-                + LENGTH_BASE[literal_or_length_code as usize - 257];
-            bit_offset += extra_bits;
+impl<W: Write> Write for DeflateWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() - self.pending >= BLOCK_SIZE {
+            self.write_block(false)?;
+        }
+        Ok(buf.len())
+    }
 
-            let distance_code = read_bits(compressed, bit_offset, 5);
-            bit_offset += 5;
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
 
-            let extra_bits = DISTANCE_EXTRA_BITS[distance_code];
-            let distance =
-                read_bits(compressed, bit_offset, extra_bits as u8) + DISTANCE_BASE[distance_code];
-            bit_offset += extra_bits;
+/// Compress `data[start..]` into the smallest of static, dynamic and uncompressed blocks.
+/// `data[..start]` is used only as history for back references.
+fn write_best_block(writer: &mut BitWriter, data: &[u8], start: usize, level: u8, is_final: bool) {
+    let block = &data[start..];
+    if level == 0 {
+        write_uncompressed_blocks(writer, block, is_final);
+        return;
+    }
 
-            for offset in inflated.len() - distance..inflated.len() - distance + length {
-                inflated.push(inflated[offset]);
+    let (max_chain_length, lazy) = LEVELS[level.min(9) as usize];
+    let tokens = find_tokens(data, start, max_chain_length, lazy);
+
+    let mut static_writer = BitWriter::new();
+    write_static_block(&mut static_writer, &tokens, is_final);
+    let mut dynamic_writer = BitWriter::new();
+    write_dynamic_block(&mut dynamic_writer, &tokens, is_final);
+    let (static_length, dynamic_length) = (static_writer.bit_len(), dynamic_writer.bit_len());
+
+    // uncompressed blocks have 5 bytes of header for each 65535 bytes
+    let uncompressed_length = (block.len() + 5 * block.len().div_ceil(65535).max(1)) * 8;
+    if uncompressed_length < static_length.min(dynamic_length) {
+        write_uncompressed_blocks(writer, block, is_final);
+    } else if dynamic_length < static_length {
+        write_dynamic_block(writer, &tokens, is_final);
+    } else {
+        write_static_block(writer, &tokens, is_final);
+    }
+}
+
+/// (max chain length, lazy matching) for each compression level
+const LEVELS: [(usize, bool); 10] = [
+    (0, false),
+    (4, false),
+    (8, false),
+    (16, false),
+    (16, true),
+    (32, true),
+    (128, true),
+    (256, true),
+    (1024, true),
+    (4096, true),
+];
+
+/// LZ77 output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Token {
+    Literal(u8),
+    Match { length: usize, distance: usize },
+}
+
+impl Token {
+    /// Literal/length symbol and distance symbol
+    fn symbols(&self) -> (usize, Option<usize>) {
+        match *self {
+            Token::Literal(value) => (value as usize, None),
+            Token::Match { length, distance } => (
+                257 + find_code_index(&LENGTH_BASE, length),
+                Some(find_code_index(&DISTANCE_BASE, distance)),
+            ),
+        }
+    }
+}
+
+/// Find tokens for `data[start..]`, allowing matches into `data[..start]`.
+pub(crate) fn find_tokens(
+    data: &[u8],
+    start: usize,
+    max_chain_length: usize,
+    lazy: bool,
+) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut matcher = Matcher::new(data.len(), max_chain_length);
+    for offset in 0..start {
+        matcher.insert(data, offset);
+    }
+    let mut offset = start;
+    while offset < data.len() {
+        let found = matcher.find(data, offset);
+        matcher.insert(data, offset);
+
+        match found {
+            Some((length, _))
+                if lazy
+                    && matcher
+                        .find(data, offset + 1)
+                        .is_some_and(|(next_length, _)| next_length > length) =>
+            {
+                // a longer match starts at the next byte
+                tokens.push(Token::Literal(data[offset]));
+                offset += 1;
+            }
+            Some((length, distance)) => {
+                tokens.push(Token::Match { length, distance });
+                for i in offset + 1..offset + length {
+                    matcher.insert(data, i);
+                }
+                offset += length;
+            }
+            None => {
+                tokens.push(Token::Literal(data[offset]));
+                offset += 1;
             }
         }
     }
+    tokens
+}
 
-    Ok(bit_offset)
+pub(crate) fn write_uncompressed_blocks(writer: &mut BitWriter, data: &[u8], is_final: bool) {
+    let mut blocks = data.chunks(65535).peekable();
+    if blocks.peek().is_none() {
+        writer.write_bits(is_final as usize, 1);
+        writer.write_bits(0b00, 2);
+        writer.write_bytes(&[0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        writer.write_bits((is_final && blocks.peek().is_none()) as usize, 1);
+        writer.write_bits(0b00, 2); // uncompressed block
+        let length = block.len() as u16;
+        writer.write_bytes(&length.to_le_bytes());
+        writer.write_bytes(&(!length).to_le_bytes());
+        writer.write_bytes(block);
+    }
 }
 
-fn inflate_dynamic_block(
-    compressed: &[u8],
-    inflated: &mut Vec<u8>,
-    mut bit_offset: usize,
-) -> Result<usize, Error> {
-    let literal_codes_count = read_bits(compressed, bit_offset, 5) + 257;
-    bit_offset += 5;
+pub(crate) fn write_static_block(writer: &mut BitWriter, tokens: &[Token], is_final: bool) {
+    writer.write_bits(is_final as usize, 1);
+    writer.write_bits(0b01, 2); // compressed with static huffman codes
 
-    let distance_codes_count = read_bits(compressed, bit_offset, 5) + 1;
-    bit_offset += 5;
+    let literal_codes = code_table(&static_literal_code_lengths());
+    let distance_codes = code_table(&static_distance_code_lengths());
+    write_tokens(writer, tokens, &literal_codes, &distance_codes);
+}
 
-    let code_length_codes_count = read_bits(compressed, bit_offset, 4) + 4;
-    bit_offset += 4;
+pub(crate) fn write_dynamic_block(writer: &mut BitWriter, tokens: &[Token], is_final: bool) {
+    let mut literal_frequencies = [0u32; 286];
+    let mut distance_frequencies = [0u32; 30];
+    for token in tokens {
+        let (literal, distance) = token.symbols();
+        literal_frequencies[literal] += 1;
+        if let Some(distance) = distance {
+            distance_frequencies[distance] += 1;
+        }
+    }
+    literal_frequencies[256] = 1; // end of block
 
-    let mut code_length_code_lengths = HashMap::new();
+    let mut literal_lengths = build_code_lengths(&literal_frequencies, 15);
+    let mut distance_lengths = build_code_lengths(&distance_frequencies, 15);
+    if distance_lengths.iter().all(|&length| length == 0) {
+        // at least one distance code is needed even if no match exists
+        distance_lengths[0] = 1;
+    }
+    while literal_lengths.len() > 257 && literal_lengths.last() == Some(&0) {
+        literal_lengths.pop();
+    }
+    while distance_lengths.len() > 1 && distance_lengths.last() == Some(&0) {
+        distance_lengths.pop();
+    }
+
+    // run-length encode the code lengths with symbols 16, 17 and 18
+    let code_lengths = [&literal_lengths[..], &distance_lengths[..]].concat();
+    let mut runs: Vec<(u8, usize, u8)> = Vec::new(); // (symbol, extra value, extra bits)
+    let mut i = 0;
+    while i < code_lengths.len() {
+        let length = code_lengths[i];
+        let run = code_lengths[i..]
+            .iter()
+            .take_while(|&&l| l == length)
+            .count();
+        if length == 0 && run >= 11 {
+            let run = run.min(138);
+            runs.push((18, run - 11, 7));
+            i += run;
+        } else if length == 0 && run >= 3 {
+            runs.push((17, run - 3, 3));
+            i += run;
+        } else if length != 0 && run >= 4 {
+            let run = (run - 1).min(6);
+            runs.push((length, 0, 0));
+            runs.push((16, run - 3, 2));
+            i += 1 + run;
+        } else {
+            runs.push((length, 0, 0));
+            i += 1;
+        }
+    }
+
+    let mut code_length_frequencies = [0u32; 19];
+    for &(symbol, _, _) in &runs {
+        code_length_frequencies[symbol as usize] += 1;
+    }
+    let code_length_lengths = build_code_lengths(&code_length_frequencies, 7);
+    let mut code_length_codes_count = 19;
+    while code_length_codes_count > 4
+        && code_length_lengths[CODE_LENGTH_ORDER[code_length_codes_count - 1] as usize] == 0
+    {
+        code_length_codes_count -= 1;
+    }
+
+    writer.write_bits(is_final as usize, 1);
+    writer.write_bits(0b10, 2); // compressed with dynamic huffman codes
+    writer.write_bits(literal_lengths.len() - 257, 5);
+    writer.write_bits(distance_lengths.len() - 1, 5);
+    writer.write_bits(code_length_codes_count - 4, 4);
     for &code_length in &CODE_LENGTH_ORDER[..code_length_codes_count] {
-        let code_length_code_length = read_bits(compressed, bit_offset, 3) as u8;
-        bit_offset += 3;
-        code_length_code_lengths.insert(code_length, code_length_code_length);
+        writer.write_bits(code_length_lengths[code_length as usize] as usize, 3);
     }
-    let code_length_huffman = HuffmanTable::from_code_lengths(&code_length_code_lengths);
 
-    let mut code_lengths = Vec::new();
-    let mut last_length = 0;
-    loop {
-        let (value, next_offset) = code_length_huffman.decode(compressed, bit_offset)?;
-        bit_offset = next_offset;
+    let code_length_codes = code_table(&to_length_map(&code_length_lengths));
+    for (symbol, extra_value, extra_bits) in runs {
+        let (code, code_length) = code_length_codes[symbol as usize];
+        writer.write_code(code, code_length);
+        writer.write_bits(extra_value, extra_bits);
+    }
 
-        if value <= 15 {
-            code_lengths.push(value as u8);
-            last_length = value as u8;
-        } else if value == 16 {
-            let repeat_count = read_bits(compressed, bit_offset, 2) as u16 + 3;
-            bit_offset += 2;
-            for _ in 0..repeat_count {
-                code_lengths.push(last_length)
-            }
-        } else if value == 17 {
-            let repeat_count = read_bits(compressed, bit_offset, 3) as u16 + 3;
-            bit_offset += 3;
-            for _ in 0..repeat_count {
-                code_lengths.push(0)
+    let literal_codes = code_table(&to_length_map(&literal_lengths));
+    let distance_codes = code_table(&to_length_map(&distance_lengths));
+    write_tokens(writer, tokens, &literal_codes, &distance_codes);
+}
+
+fn write_tokens(
+    writer: &mut BitWriter,
+    tokens: &[Token],
+    literal_codes: &[(u16, u8)],
+    distance_codes: &[(u16, u8)],
+) {
+    for token in tokens {
+        match *token {
+            Token::Literal(value) => {
+                let (code, code_length) = literal_codes[value as usize];
+                writer.write_code(code, code_length);
             }
-        } else if value == 18 {
-            let repeat_count = read_bits(compressed, bit_offset, 7) as u16 + 11;
-            bit_offset += 7;
-            for _ in 0..repeat_count {
-                code_lengths.push(0)
+            Token::Match { length, distance } => {
+                let index = find_code_index(&LENGTH_BASE, length);
+                let (code, code_length) = literal_codes[257 + index];
+                writer.write_code(code, code_length);
+                writer.write_bits(length - LENGTH_BASE[index], LENGTH_EXTRA_BITS[index] as u8);
+
+                let index = find_code_index(&DISTANCE_BASE, distance);
+                let (code, code_length) = distance_codes[index];
+                writer.write_code(code, code_length);
+                writer.write_bits(
+                    distance - DISTANCE_BASE[index],
+                    DISTANCE_EXTRA_BITS[index] as u8,
+                );
             }
         }
+    }
+
+    let (code, code_length) = literal_codes[256];
+    writer.write_code(code, code_length);
+}
 
-        if code_lengths.len() == literal_codes_count + distance_codes_count {
-            break;
+/// Build huffman code lengths from frequencies, limiting the lengths to `max_length`.
+///
+/// Frequencies are halved until the code fits in `max_length` bits.
+fn build_code_lengths(frequencies: &[u32], max_length: u8) -> Vec<u8> {
+    let mut frequencies = frequencies.to_vec();
+    loop {
+        let lengths = huffman_code_lengths(&frequencies);
+        if lengths.iter().all(|&length| length <= max_length) {
+            return lengths;
+        }
+        for frequency in frequencies.iter_mut().filter(|f| **f > 0) {
+            *frequency = (*frequency >> 1) | 1;
         }
     }
+}
+
+fn huffman_code_lengths(frequencies: &[u32]) -> Vec<u8> {
+    let mut lengths = vec![0u8; frequencies.len()];
+    let symbols: Vec<usize> = (0..frequencies.len())
+        .filter(|&i| frequencies[i] > 0)
+        .collect();
+    if symbols.len() == 1 {
+        lengths[symbols[0]] = 1;
+        return lengths;
+    }
 
-    let mut literal_code_lengths = HashMap::new();
-    for (value, &code_length) in code_lengths[..literal_codes_count].iter().enumerate() {
-        literal_code_lengths.insert(value as u16, code_length);
+    // nodes[i] = (parent node index)
+    let mut parents = vec![usize::MAX; symbols.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = symbols
+        .iter()
+        .enumerate()
+        .map(|(node, &symbol)| Reverse((frequencies[symbol] as u64, node)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((frequency1, node1)) = heap.pop().unwrap();
+        let Reverse((frequency2, node2)) = heap.pop().unwrap();
+        let parent = parents.len();
+        parents.push(usize::MAX);
+        parents[node1] = parent;
+        parents[node2] = parent;
+        heap.push(Reverse((frequency1 + frequency2, parent)));
     }
-    let literal_codes_huffman = HuffmanTable::from_code_lengths(&literal_code_lengths);
 
-    let mut distance_code_lengths = HashMap::new();
-    for (value, &code_length) in code_lengths[literal_codes_count..].iter().enumerate() {
-        distance_code_lengths.insert(value as u16, code_length);
+    for (node, &symbol) in symbols.iter().enumerate() {
+        let mut depth = 0;
+        let mut current = node;
+        while parents[current] != usize::MAX {
+            current = parents[current];
+            depth += 1;
+        }
+        lengths[symbol] = depth.min(u8::MAX as usize) as u8;
     }
-    let distance_codes_huffman = HuffmanTable::from_code_lengths(&distance_code_lengths);
+    lengths
+}
 
-    while (bit_offset >> 3) < compressed.len() {
-        let (value, next_offset) = literal_codes_huffman.decode(compressed, bit_offset)?;
-        bit_offset = next_offset;
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH_LENGTH: usize = 3;
+const MAX_MATCH_LENGTH: usize = 258;
+const HASH_SIZE: usize = 1 << 15;
 
-        if value <= 255 {
-            inflated.push(value as u8);
-        } else if value == 256 {
-            break;
-        } else {
-            let extra_bits = LENGTH_EXTRA_BITS[value as usize - 257];
-            let length = read_bits(compressed, bit_offset, extra_bits as u8)
-                + LENGTH_BASE[value as usize - 257];
-            bit_offset += extra_bits;
-
-            let (value, next_offset) = distance_codes_huffman.decode(compressed, bit_offset)?;
-            bit_offset = next_offset;
-
-            let extra_bits = DISTANCE_EXTRA_BITS[value as usize];
-            let distance =
-                read_bits(compressed, bit_offset, extra_bits as u8) + DISTANCE_BASE[value as usize];
-            bit_offset += extra_bits;
-
-            let start = inflated.len() - distance;
-            for offset in 0..length {
-                inflated.push(inflated[start + offset]);
+/// LZ77 match finder using hash chains of 3-byte prefixes.
+struct Matcher {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    max_chain_length: usize,
+}
+
+impl Matcher {
+    fn new(data_length: usize, max_chain_length: usize) -> Matcher {
+        Matcher {
+            head: vec![usize::MAX; HASH_SIZE],
+            prev: vec![usize::MAX; data_length],
+            max_chain_length,
+        }
+    }
+
+    fn hash(data: &[u8], offset: usize) -> usize {
+        (((data[offset] as usize) << 10)
+            ^ ((data[offset + 1] as usize) << 5)
+            ^ data[offset + 2] as usize)
+            & (HASH_SIZE - 1)
+    }
+
+    fn insert(&mut self, data: &[u8], offset: usize) {
+        if offset + MIN_MATCH_LENGTH > data.len() {
+            return;
+        }
+        let hash = Self::hash(data, offset);
+        self.prev[offset] = self.head[hash];
+        self.head[hash] = offset;
+    }
+
+    /// Find the longest match for the data at `offset`, returning (length, distance).
+    fn find(&self, data: &[u8], offset: usize) -> Option<(usize, usize)> {
+        if offset + MIN_MATCH_LENGTH > data.len() {
+            return None;
+        }
+        let max_length = MAX_MATCH_LENGTH.min(data.len() - offset);
+
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[Self::hash(data, offset)];
+        for _ in 0..self.max_chain_length {
+            if candidate == usize::MAX || offset - candidate > WINDOW_SIZE {
+                break;
+            }
+
+            let length = (0..max_length)
+                .take_while(|&i| data[candidate + i] == data[offset + i])
+                .count();
+            if length > best.map_or(MIN_MATCH_LENGTH - 1, |(l, _)| l) {
+                best = Some((length, offset - candidate));
+                if length == max_length {
+                    break;
+                }
             }
+
+            candidate = self.prev[candidate];
         }
+
+        best
+    }
+}
+
+/// Find the index of the largest base value which is less than or equal to `value`.
+fn find_code_index(bases: &[usize], value: usize) -> usize {
+    bases.iter().rposition(|&base| base <= value).unwrap()
+}
+
+/// Convert code lengths into (code, length) pairs indexed by value.
+fn code_table(lengths: &HashMap<u16, u8>) -> Vec<(u16, u8)> {
+    let codes = lengths_to_codes(lengths);
+    let mut table = vec![(0, 0); lengths.len()];
+    for (&value, &code) in codes.iter() {
+        table[value as usize] = (code, lengths[&value]);
     }
+    table
+}
 
-    Ok(bit_offset)
+fn to_length_map(lengths: &[u8]) -> HashMap<u16, u8> {
+    lengths
+        .iter()
+        .enumerate()
+        .map(|(value, &length)| (value as u16, length))
+        .collect()
+}
+
+fn static_literal_code_lengths() -> HashMap<u16, u8> {
+    let mut code_lengths = HashMap::new();
+    for i in 0..=287 {
+        code_lengths.insert(
+            i as u16,
+            if i <= 143 {
+                8
+            } else if i <= 255 {
+                9
+            } else if i <= 279 {
+                7
+            } else {
+                8
+            },
+        );
+    }
+    code_lengths
+}
+
+fn static_distance_code_lengths() -> HashMap<u16, u8> {
+    (0..30).map(|i| (i as u16, 5)).collect()
 }
 
 const CODE_LENGTH_ORDER: [u16; 19] = [
@@ -235,3 +740,34 @@ const DISTANCE_BASE: [usize; 30] = [
     33, 49, 65, 97, 129, 193, 257, 385, 513, 769, // 10-19
     1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577, // 20-29
 ];
+
+#[cfg(test)]
+mod test {
+    use crate::deflate::{deflate, deflate_with_level, inflate};
+
+    #[test]
+    fn test_deflate_round_trip() {
+        let data = b"abcabcabcabcXYZabcabcabc, hello hello hello world".repeat(20);
+        let compressed = deflate(&data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(inflate(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_deflate_empty() {
+        assert_eq!(inflate(&deflate(&[])).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_deflate_levels() {
+        let mut data = Vec::new();
+        for i in 0..100000u64 {
+            data.push((i * i / 7 % 251) as u8);
+        }
+        for level in 0..=9 {
+            let compressed = deflate_with_level(&data, level);
+            assert_eq!(inflate(&compressed).unwrap(), data, "level {}", level);
+        }
+        assert!(deflate_with_level(&data, 9).len() <= deflate_with_level(&data, 1).len());
+    }
+}
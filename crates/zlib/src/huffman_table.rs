@@ -1,7 +1,7 @@
 use std::collections::HashMap;
-use std::io::{Error, ErrorKind};
+use std::io::{Error, ErrorKind, Read};
 
-use crate::bit::read_one_bit;
+use crate::bit::BitReader;
 
 #[derive(Debug)]
 pub struct HuffmanTable {
@@ -11,13 +11,13 @@ pub struct HuffmanTable {
 }
 
 impl HuffmanTable {
-    pub fn decode(&self, buffer: &[u8], bit_offset: usize) -> Result<(u16, usize), Error> {
+    pub fn decode<R: Read>(&self, reader: &mut BitReader<R>) -> Result<u16, Error> {
         let mut code: u16 = 0;
         for len in 1..=self.max_len {
-            code = (code << 1) + read_one_bit(buffer, bit_offset + len as usize - 1) as u16;
+            code = (code << 1) + reader.read_one_bit()? as u16;
             if len >= self.min_len {
                 if let Some(&value) = self.map.get(&len).and_then(|m| m.get(&code)) {
-                    return Ok((value, bit_offset + len as usize));
+                    return Ok(value);
                 }
             }
         }
@@ -64,7 +64,7 @@ impl HuffmanTable {
     }
 }
 
-fn lengths_to_codes(lengths: &HashMap<u16, u8>) -> HashMap<u16, u16> {
+pub fn lengths_to_codes(lengths: &HashMap<u16, u8>) -> HashMap<u16, u16> {
     let mut count_by_length = [0u16; 16];
     for &length in lengths.values() {
         count_by_length[length as usize] += 1;
@@ -95,6 +95,7 @@ fn lengths_to_codes(lengths: &HashMap<u16, u8>) -> HashMap<u16, u16> {
 
 #[cfg(test)]
 mod test {
+    use crate::bit::BitReader;
     use crate::huffman_table::{lengths_to_codes, HuffmanTable};
     use std::collections::HashMap;
 
@@ -149,11 +150,11 @@ mod test {
         // 6=0b110 -> 2
         // 7=0b111 -> 3
         let huffman = HuffmanTable::from_code_lengths(&lengths);
-        let codes = [0b11011010, 0b1];
+        let mut reader = BitReader::new(&[0b11011010, 0b1][..]);
 
-        assert_eq!(huffman.decode(&codes, 0).unwrap(), (0, 1));
-        assert_eq!(huffman.decode(&codes, 1).unwrap(), (1, 3));
-        assert_eq!(huffman.decode(&codes, 3).unwrap(), (2, 6));
-        assert_eq!(huffman.decode(&codes, 6).unwrap(), (3, 9));
+        assert_eq!(huffman.decode(&mut reader).unwrap(), 0);
+        assert_eq!(huffman.decode(&mut reader).unwrap(), 1);
+        assert_eq!(huffman.decode(&mut reader).unwrap(), 2);
+        assert_eq!(huffman.decode(&mut reader).unwrap(), 3);
     }
 }
@@ -1,17 +1,193 @@
-use crate::bit::read_bits;
-use crate::deflate;
-use std::io::{Error, ErrorKind};
+use crate::bit::BitReader;
+use crate::deflate::{self, DeflateWriter, InflateReader};
+use std::io::{Error, ErrorKind, Read, Write};
 
 pub fn inflate(compressed: &[u8]) -> Result<Vec<u8>, Error> {
-    let compression_method = read_bits(compressed, 0, 4);
-    if compression_method != 8 {
-        return Err(Error::new(
-            ErrorKind::InvalidData,
-            "unsupported compression method",
-        ));
+    let mut inflated = Vec::new();
+    ZlibReader::new(compressed)?.read_to_end(&mut inflated)?;
+    Ok(inflated)
+}
+
+/// Incremental zlib decompressor which verifies the Adler-32 checksum at the end of the stream.
+pub struct ZlibReader<R: Read> {
+    inflater: InflateReader<R>,
+    adler: u32,
+    is_checked: bool,
+}
+
+impl<R: Read> ZlibReader<R> {
+    /// Read the zlib header from `reader`.
+    pub fn new(reader: R) -> Result<ZlibReader<R>, Error> {
+        let mut reader = BitReader::new(reader);
+        let mut header = [0u8; 2];
+        reader.read_bytes(&mut header)?;
+        if header[0] & 0x0f != 8 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unsupported compression method",
+            ));
+        }
+        if u16::from_be_bytes(header) % 31 != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid header check"));
+        }
+        if header[1] & 0x20 != 0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "preset dictionary is not supported",
+            ));
+        }
+
+        Ok(ZlibReader {
+            inflater: InflateReader::from_bit_reader(reader),
+            adler: 1,
+            is_checked: false,
+        })
+    }
+}
+
+impl<R: Read> Read for ZlibReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let length = self.inflater.read(buf)?;
+        self.adler = update_adler32(self.adler, &buf[..length]);
+
+        if length == 0 && !buf.is_empty() && !self.is_checked {
+            self.is_checked = true;
+            let mut checksum = [0u8; 4];
+            match self.inflater.read_trailer(&mut checksum) {
+                Ok(()) if u32::from_be_bytes(checksum) != self.adler => {
+                    return Err(Error::new(ErrorKind::InvalidData, "Adler-32 mismatch"));
+                }
+                // tolerate a missing checksum
+                Err(error) if error.kind() != ErrorKind::UnexpectedEof => return Err(error),
+                _ => {}
+            }
+        }
+
+        Ok(length)
+    }
+}
+
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    deflate_with_level(data, deflate::DEFAULT_LEVEL)
+}
+
+/// Compress data with `level` from 0 (no compression) to 9 (best compression).
+pub fn deflate_with_level(data: &[u8], level: u8) -> Vec<u8> {
+    // CM=8 (deflate), CINFO=7 (32K window), FLEVEL=0, FCHECK makes the header a multiple of 31
+    let mut compressed = vec![0x78, 0x01];
+    compressed.extend(deflate::deflate_with_level(data, level));
+    compressed.extend(adler32(data).to_be_bytes());
+    compressed
+}
+
+/// Incremental zlib compressor.
+pub struct ZlibWriter<W: Write> {
+    deflater: DeflateWriter<W>,
+    adler: u32,
+}
+
+impl<W: Write> ZlibWriter<W> {
+    /// Write the zlib header to `writer`.
+    pub fn new(mut writer: W, level: u8) -> Result<ZlibWriter<W>, Error> {
+        writer.write_all(&[0x78, 0x01])?;
+        Ok(ZlibWriter {
+            deflater: DeflateWriter::new(writer, level),
+            adler: 1,
+        })
     }
 
-    let compressed_data_length = compressed.len() - 6;
+    /// Write the remaining data and the checksum, and return the inner writer.
+    pub fn finish(self) -> Result<W, Error> {
+        let mut writer = self.deflater.finish()?;
+        writer.write_all(&self.adler.to_be_bytes())?;
+        Ok(writer)
+    }
+}
 
-    deflate::inflate(&compressed[2..2 + compressed_data_length])
+impl<W: Write> Write for ZlibWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let length = self.deflater.write(buf)?;
+        self.adler = update_adler32(self.adler, &buf[..length]);
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.deflater.flush()
+    }
+}
+
+/// https://www.rfc-editor.org/rfc/rfc1950#section-8
+pub fn adler32(data: &[u8]) -> u32 {
+    update_adler32(1, data)
+}
+
+/// Continue an Adler-32 checksum with `data`.
+pub fn update_adler32(adler: u32, data: &[u8]) -> u32 {
+    let mut a = adler & 0xffff;
+    let mut b = adler >> 16;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use crate::zlib::{adler32, deflate, inflate, ZlibReader, ZlibWriter};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn test_deflate_round_trip() {
+        let data = b"zlib zlib zlib".repeat(10);
+        let compressed = deflate(&data);
+        assert_eq!((compressed[0] as u16 * 256 + compressed[1] as u16) % 31, 0);
+        assert_eq!(inflate(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_incremental_read() {
+        let data = (0..200000u64)
+            .map(|i| (i * i / 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let compressed = deflate(&data);
+
+        let mut reader = ZlibReader::new(&compressed[..]).unwrap();
+        let mut inflated = Vec::new();
+        let mut buf = [0u8; 1000];
+        loop {
+            let length = reader.read(&mut buf).unwrap();
+            if length == 0 {
+                break;
+            }
+            inflated.extend_from_slice(&buf[..length]);
+        }
+        assert_eq!(inflated, data);
+
+        let mut corrupted = compressed.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(inflate(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_incremental_write() {
+        let data = (0..400000u64)
+            .map(|i| (i * i / 7 % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let mut writer = ZlibWriter::new(Vec::new(), 6).unwrap();
+        for chunk in data.chunks(999) {
+            writer.write_all(chunk).unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+        assert_eq!(inflate(&compressed).unwrap(), data);
+
+        let empty = ZlibWriter::new(Vec::new(), 6).unwrap().finish().unwrap();
+        assert_eq!(inflate(&empty).unwrap(), Vec::<u8>::new());
+    }
 }